@@ -0,0 +1,147 @@
+use anyhow::{bail, Result};
+use std::ops::Range;
+use std::path::Path;
+
+/// Who owns the blocks a repo writes: the repo's directory name
+pub fn owner(repo_path: &Path) -> String {
+    repo_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+pub fn begin_marker(owner: &str) -> String {
+    format!("# BEGIN homie:{}", owner)
+}
+
+pub fn end_marker(owner: &str) -> String {
+    format!("# END homie:{}", owner)
+}
+
+/// Byte range of `owner`'s block in `content`, from the start of the BEGIN
+/// line to the end of the END line (including its newline)
+fn span(content: &str, owner: &str) -> Result<Option<Range<usize>>> {
+    let begin = begin_marker(owner);
+    let end = end_marker(owner);
+
+    let mut start = None;
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        let marker = line.trim_end_matches(['\n', '\r']);
+        match start {
+            None if marker == begin => start = Some(offset),
+            Some(start) if marker == end => return Ok(Some(start..offset + line.len())),
+            Some(_) if marker == begin => bail!("Nested `{}` line", begin),
+            _ => {}
+        }
+        offset += line.len();
+    }
+
+    if start.is_some() {
+        bail!("`{}` has no matching `{}`", begin, end);
+    }
+    Ok(None)
+}
+
+/// The lines between `owner`'s markers, if the block exists
+pub fn extract<'a>(content: &'a str, owner: &str) -> Result<Option<&'a str>> {
+    let Some(range) = span(content, owner)? else {
+        return Ok(None);
+    };
+
+    let block = &content[range];
+    let body_start = block.find('\n').map_or(block.len(), |i| i + 1);
+    let body_end = block.trim_end_matches(['\n', '\r']).rfind('\n').map_or(body_start, |i| i + 1);
+    Ok(Some(&block[body_start..body_end.max(body_start)]))
+}
+
+/// `content` with `owner`'s block set to `body`: replaced in place if it
+/// exists, otherwise appended. Everything outside the block is kept.
+pub fn upsert(content: &str, owner: &str, body: &str) -> Result<String> {
+    let mut block = begin_marker(owner);
+    block.push('\n');
+    block.push_str(body);
+    if !body.is_empty() && !body.ends_with('\n') {
+        block.push('\n');
+    }
+    block.push_str(&end_marker(owner));
+    block.push('\n');
+
+    if let Some(range) = span(content, owner)? {
+        let mut updated = content.to_string();
+        updated.replace_range(range, &block);
+        return Ok(updated);
+    }
+
+    let mut updated = content.to_string();
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    updated.push_str(&block);
+    Ok(updated)
+}
+
+/// `content` without `owner`'s block, or `None` if it has none
+pub fn remove(content: &str, owner: &str) -> Result<Option<String>> {
+    let Some(range) = span(content, owner)? else {
+        return Ok(None);
+    };
+
+    let mut updated = content.to_string();
+    updated.replace_range(range, "");
+    Ok(Some(updated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upsert_appends_then_replaces() {
+        let original = "export PATH=/usr/bin\n# added by some installer\n";
+
+        let added = upsert(original, "dotfiles", "alias ll='ls -l'").unwrap();
+        assert_eq!(
+            added,
+            "export PATH=/usr/bin\n# added by some installer\n\
+             # BEGIN homie:dotfiles\nalias ll='ls -l'\n# END homie:dotfiles\n"
+        );
+        assert_eq!(extract(&added, "dotfiles").unwrap(), Some("alias ll='ls -l'\n"));
+
+        let edited = format!("{}export EDITOR=vi\n", added);
+        let replaced = upsert(&edited, "dotfiles", "alias la='ls -a'\n").unwrap();
+        assert!(replaced.contains("# BEGIN homie:dotfiles\nalias la='ls -a'\n# END homie:dotfiles\nexport EDITOR=vi\n"));
+        assert!(!replaced.contains("ll="));
+        assert_eq!(upsert(&replaced, "dotfiles", "alias la='ls -a'\n").unwrap(), replaced);
+    }
+
+    #[test]
+    fn test_upsert_without_trailing_newline() {
+        assert_eq!(
+            upsert("a", "work", "b\n").unwrap(),
+            "a\n# BEGIN homie:work\nb\n# END homie:work\n"
+        );
+        assert_eq!(upsert("", "work", "").unwrap(), "# BEGIN homie:work\n# END homie:work\n");
+        assert_eq!(extract("# BEGIN homie:work\n# END homie:work\n", "work").unwrap(), Some(""));
+    }
+
+    #[test]
+    fn test_blocks_of_other_owners_are_untouched() {
+        let content = upsert("", "work", "a\n").unwrap();
+        let content = upsert(&content, "dotfiles", "b\n").unwrap();
+        assert_eq!(extract(&content, "work").unwrap(), Some("a\n"));
+        assert_eq!(extract(&content, "dotfiles").unwrap(), Some("b\n"));
+
+        let removed = remove(&content, "work").unwrap().unwrap();
+        assert_eq!(removed, "# BEGIN homie:dotfiles\nb\n# END homie:dotfiles\n");
+        assert_eq!(remove(&removed, "work").unwrap(), None);
+    }
+
+    #[test]
+    fn test_unterminated_block_is_an_error() {
+        let content = "x\n# BEGIN homie:dotfiles\ny\n";
+        assert!(extract(content, "dotfiles").is_err());
+        assert!(upsert(content, "dotfiles", "z\n").is_err());
+        assert_eq!(extract(content, "work").unwrap(), None);
+    }
+}
@@ -0,0 +1,303 @@
+use handlebars::template::{Parameter, TemplateElement};
+use handlebars::Template;
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::template::{default_syntax, optional_syntax};
+use crate::vars::lookup;
+
+/// A var read by a template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// Dotted path from the top level of the vars, e.g. `git.email`
+    pub path: String,
+    pub line: usize,
+    /// Whether rendering fails in strict mode without it. `{{var?}}`,
+    /// `{{var:default}}`, `{{#if var}}` and `default var` are optional.
+    pub required: bool,
+}
+
+/// Something wrong with a template, at a 1-based line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Every var `template` reads from the top level. Names inside `each` and
+/// `with` blocks refer to the current item and are not included, nor are
+/// calls to `helpers` without arguments.
+pub fn references(template: &str, helpers: &[&str]) -> Result<Vec<Reference>, Problem> {
+    let mut references = Vec::new();
+
+    // homie's own syntax is resolved before Handlebars sees the template.
+    // Blank it out, keeping newlines so line numbers still match.
+    let mut source = template.to_string();
+    for syntax in [default_syntax(), optional_syntax()] {
+        for caps in syntax.captures_iter(&source) {
+            let start = caps.get(0).map_or(0, |m| m.start());
+            references.push(Reference {
+                path: caps[1].to_string(),
+                line: line_at(&source, start),
+                required: false,
+            });
+        }
+        source = syntax
+            .replace_all(&source, |caps: &regex_lite::Captures| {
+                "\n".repeat(caps[0].matches('\n').count())
+            })
+            .to_string();
+    }
+
+    let compiled = Template::compile(&source).map_err(|e| Problem {
+        line: e.pos().map_or(1, |(line, _)| line),
+        message: e.reason().to_string(),
+    })?;
+    walk(&compiled, helpers, 0, 1, &mut references);
+
+    references.sort_by_key(|r| r.line);
+    Ok(references)
+}
+
+/// Required references that are not defined in `vars`
+pub fn undefined<'a>(references: &'a [Reference], vars: &Value) -> Vec<&'a Reference> {
+    references
+        .iter()
+        .filter(|r| r.required && lookup(vars, &r.path).is_none())
+        .collect()
+}
+
+fn line_at(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+/// Collect references from `template`, `depth` being how many `each`/`with`
+/// blocks it is nested in
+fn walk(
+    template: &Template,
+    helpers: &[&str],
+    depth: usize,
+    parent_line: usize,
+    references: &mut Vec<Reference>,
+) {
+    for (i, element) in template.elements.iter().enumerate() {
+        let line = template.mapping.get(i).map_or(parent_line, |m| m.0);
+
+        match element {
+            TemplateElement::Expression(h) | TemplateElement::HtmlExpression(h) => {
+                let name = h.name.as_name().unwrap_or_default();
+                if h.params.is_empty() && h.hash.is_empty() {
+                    if !helpers.contains(&name) {
+                        reference(&h.name, depth, line, true, references);
+                    }
+                } else {
+                    helper_call(name, &h.params, &h.hash, helpers, depth, line, references);
+                }
+            }
+            TemplateElement::HelperBlock(h) => {
+                // `if` and `unless` treat a missing value as false; `each`
+                // and `with` only fail on one without an `else`
+                let (required, inner) = match h.name.as_name().unwrap_or_default() {
+                    "if" | "unless" => (false, depth),
+                    "each" | "with" => (h.inverse.is_none(), depth + 1),
+                    _ => (true, depth),
+                };
+                for param in &h.params {
+                    parameter(param, helpers, depth, line, required, references);
+                }
+                for param in h.hash.values() {
+                    parameter(param, helpers, depth, line, true, references);
+                }
+                if let Some(template) = &h.template {
+                    walk(template, helpers, inner, line, references);
+                }
+                if let Some(inverse) = &h.inverse {
+                    walk(inverse, helpers, depth, line, references);
+                }
+            }
+            TemplateElement::PartialExpression(d)
+            | TemplateElement::PartialBlock(d)
+            | TemplateElement::DecoratorExpression(d)
+            | TemplateElement::DecoratorBlock(d) => {
+                for param in d.params.iter().chain(d.hash.values()) {
+                    parameter(param, helpers, depth, line, true, references);
+                }
+                if let Some(template) = &d.template {
+                    walk(template, helpers, depth, line, references);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn helper_call(
+    name: &str,
+    params: &[Parameter],
+    hash: &HashMap<String, Parameter>,
+    helpers: &[&str],
+    depth: usize,
+    line: usize,
+    references: &mut Vec<Reference>,
+) {
+    for (i, param) in params.iter().enumerate() {
+        // `default` exists to handle a missing first value
+        let required = !(name == "default" && i == 0);
+        parameter(param, helpers, depth, line, required, references);
+    }
+    for param in hash.values() {
+        parameter(param, helpers, depth, line, true, references);
+    }
+}
+
+fn parameter(
+    param: &Parameter,
+    helpers: &[&str],
+    depth: usize,
+    line: usize,
+    required: bool,
+    references: &mut Vec<Reference>,
+) {
+    match param {
+        Parameter::Path(_) => reference(param, depth, line, required, references),
+        Parameter::Subexpression(sub) => {
+            let params = sub.params().map(Vec::as_slice).unwrap_or_default();
+            let hash = sub.hash().cloned().unwrap_or_default();
+            if params.is_empty() && hash.is_empty() {
+                // `(name)` is a helper call without arguments, or a lookup
+                if let TemplateElement::Expression(h) = sub.as_element() {
+                    if !helpers.contains(&sub.name()) {
+                        reference(&h.name, depth, line, required, references);
+                    }
+                }
+            } else {
+                helper_call(sub.name(), params, &hash, helpers, depth, line, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn reference(
+    name: &Parameter,
+    depth: usize,
+    line: usize,
+    required: bool,
+    references: &mut Vec<Reference>,
+) {
+    if let Some(path) = name.as_name().and_then(|raw| root_path(raw, depth)) {
+        references.push(Reference { path, line, required });
+    }
+}
+
+/// `raw` as a dotted path from the top level of the vars, if it refers
+/// there: `@root.x`, and `x` or `../x` once it climbs out of every block
+fn root_path(raw: &str, depth: usize) -> Option<String> {
+    let path = if let Some(rest) = raw.strip_prefix("@root") {
+        rest.trim_start_matches(['.', '/'])
+    } else if raw.starts_with('@') {
+        // @index, @key and friends
+        return None;
+    } else {
+        let mut rest = raw;
+        let mut up = 0;
+        while let Some(parent) = rest.strip_prefix("../") {
+            rest = parent;
+            up += 1;
+        }
+        if up < depth {
+            return None;
+        }
+        ["this.", "this/", "./"]
+            .iter()
+            .find_map(|prefix| rest.strip_prefix(prefix))
+            .unwrap_or(rest)
+    };
+
+    if path.is_empty() || path == "this" || path == "." {
+        return None;
+    }
+    Some(path.replace('/', "."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::TemplateEngine;
+    use serde_json::json;
+
+    fn paths(template: &str) -> Vec<(String, usize, bool)> {
+        references(template, TemplateEngine::new().helpers())
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.path, r.line, r.required))
+            .collect()
+    }
+
+    #[test]
+    fn test_references() {
+        let template = "\
+name = {{git.name}}
+email = {{git.email:nobody}}{{signing?}}
+{{#if work}}{{upper (default editor \"vi\")}}{{/if}}
+{{#each hosts}}Host {{name}} {{@index}} {{@root.domain}} {{../user}}{{/each}}
+{{> aliases shell}}
+";
+        assert_eq!(
+            paths(template),
+            vec![
+                ("git.name".to_string(), 1, true),
+                ("git.email".to_string(), 2, false),
+                ("signing".to_string(), 2, false),
+                ("work".to_string(), 3, false),
+                ("editor".to_string(), 3, false),
+                ("hosts".to_string(), 4, true),
+                ("domain".to_string(), 4, true),
+                ("user".to_string(), 4, true),
+                ("shell".to_string(), 5, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_each_with_else_is_optional() {
+        let template = "{{#each hosts}}{{name}}{{else}}{{fallback}}{{/each}}{{#if (eq os \"macos\")}}m{{/if}}";
+        assert_eq!(
+            paths(template),
+            vec![
+                ("hosts".to_string(), 1, false),
+                ("fallback".to_string(), 1, true),
+                ("os".to_string(), 1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_helpers_without_arguments_are_not_vars() {
+        let template = "{{log}}{{#if (exists)}}{{name}}{{/if}}\n{{upper}}";
+        assert_eq!(paths(template), vec![("name".to_string(), 1, true)]);
+
+        let refs = references(template, &[]).unwrap();
+        assert_eq!(refs.len(), 4);
+    }
+
+    #[test]
+    fn test_undefined() {
+        let template = "{{emial}}\n{{email}} {{git.name}} {{theme?}}\n{{env.TOKEN}}";
+        let refs = references(template, &[]).unwrap();
+        let vars = json!({ "email": "me@example.com", "git": { "name": "me" }, "env": {} });
+
+        let undefined: Vec<(&str, usize)> = undefined(&refs, &vars)
+            .into_iter()
+            .map(|r| (r.path.as_str(), r.line))
+            .collect();
+        assert_eq!(undefined, vec![("emial", 1), ("env.TOKEN", 3)]);
+    }
+
+    #[test]
+    fn test_parse_error_has_a_line() {
+        let problem = references("ok\n{{#if x}}\nnever closed\n", &[]).unwrap_err();
+        assert!(problem.line >= 2);
+        assert!(!problem.message.is_empty());
+    }
+}
@@ -0,0 +1,373 @@
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::config::GlobalConfig;
+use crate::repo::{discover_repos, Repo, RepoItem};
+use crate::strategy::Strategy;
+
+/// An item that wants to place something at `target`
+#[derive(Debug, Clone)]
+struct Claim {
+    repo: String,
+    path: String,
+    target: PathBuf,
+    priority: i32,
+    /// Position in the global `layers` list, if the repo is layered
+    layer: Option<usize>,
+    /// A `block` item, which shares its file with other repos' blocks
+    block: bool,
+}
+
+impl Claim {
+    /// Priority decides first; between repos of equal priority, the higher
+    /// layer wins. `Equal` means neither repo can win.
+    fn rank(&self, other: &Claim) -> Ordering {
+        match self.priority.cmp(&other.priority) {
+            Ordering::Equal => match (self.layer, other.layer) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                _ => Ordering::Equal,
+            },
+            ordering => ordering,
+        }
+    }
+}
+
+/// Items from different repos whose targets are the same path, or where one
+/// item (a directory unit) covers the other's target
+#[derive(Debug, PartialEq, Eq)]
+pub struct Collision {
+    /// The outermost target involved
+    pub target: PathBuf,
+    /// `(repo, item path)` pairs
+    pub items: Vec<(String, String)>,
+}
+
+/// Which items lose a cross-repo collision, and which collisions could not be
+/// settled because the repos involved share a priority and layer
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// `(repo, item path)` -> winning repo
+    shadowed: HashMap<(String, String), String>,
+    /// `(repo, item path)` of a winning item -> repos it shadows
+    overrides: HashMap<(String, String), BTreeSet<String>>,
+    unresolved: Vec<Collision>,
+}
+
+/// Check the targets of `selected` for collisions. With `include_others`, the
+/// other active repos are taken into account too (when only one repo is linked).
+pub fn resolve(config: &GlobalConfig, selected: &[Repo], include_others: bool) -> Result<Resolution> {
+    let mut claims = Vec::new();
+    for repo in selected {
+        claims.extend(claims_for(config, repo)?);
+    }
+
+    if include_others {
+        for repo in discover_repos(config)? {
+            if !selected.iter().any(|r| r.name == repo.name) {
+                claims.extend(claims_for(config, &repo)?);
+            }
+        }
+    }
+
+    Ok(Resolution::from_claims(claims))
+}
+
+fn claims_for(config: &GlobalConfig, repo: &Repo) -> Result<Vec<Claim>> {
+    let priority = config.repo_priority(&repo.name, &repo.config);
+    let layer = config.layer_of(&repo.name);
+    Ok(repo
+        .items()?
+        .into_iter()
+        .map(|item| Claim {
+            repo: repo.name.clone(),
+            path: item.relative_path,
+            target: item.target,
+            priority,
+            layer,
+            block: item.strategy == Strategy::Block && !item.is_secret,
+        })
+        .collect())
+}
+
+impl Resolution {
+    fn from_claims(claims: Vec<Claim>) -> Self {
+        let mut by_target: HashMap<&Path, Vec<usize>> = HashMap::new();
+        for (i, claim) in claims.iter().enumerate() {
+            by_target.entry(&claim.target).or_default().push(i);
+        }
+
+        // (outer, inner): same target, or inner's target is below outer's
+        let mut pairs = Vec::new();
+        for (inner, claim) in claims.iter().enumerate() {
+            for ancestor in claim.target.ancestors() {
+                let Some(outers) = by_target.get(ancestor) else {
+                    continue;
+                };
+                for &outer in outers {
+                    let same_target = ancestor == claim.target;
+                    // Each repo's block has its own markers
+                    let shared_file = same_target && claims[outer].block && claim.block;
+                    if claims[outer].repo != claim.repo && (!same_target || outer < inner) && !shared_file {
+                        pairs.push((outer, inner));
+                    }
+                }
+            }
+        }
+
+        // Each loser maps to the highest-ranked item that beats it
+        let mut losers: BTreeMap<usize, usize> = BTreeMap::new();
+        let mut beat = |loser: usize, winner: usize| {
+            let current = losers.entry(loser).or_insert(winner);
+            if claims[winner].rank(&claims[*current]) == Ordering::Greater {
+                *current = winner;
+            }
+        };
+        let mut ties = Vec::new();
+        for (outer, inner) in pairs {
+            match claims[outer].rank(&claims[inner]) {
+                Ordering::Greater => beat(inner, outer),
+                Ordering::Less => beat(outer, inner),
+                Ordering::Equal => ties.push((outer, inner)),
+            }
+        }
+
+        // A tie only matters if neither side already lost to a third repo
+        let mut groups: BTreeMap<&Path, BTreeSet<usize>> = BTreeMap::new();
+        for (outer, inner) in ties {
+            if losers.contains_key(&outer) || losers.contains_key(&inner) {
+                continue;
+            }
+            groups
+                .entry(&claims[outer].target)
+                .or_default()
+                .extend([outer, inner]);
+        }
+
+        let unresolved = groups
+            .into_iter()
+            .map(|(target, members)| Collision {
+                target: target.to_path_buf(),
+                items: members
+                    .into_iter()
+                    .map(|i| (claims[i].repo.clone(), claims[i].path.clone()))
+                    .collect(),
+            })
+            .collect();
+
+        let mut shadowed = HashMap::new();
+        let mut overrides: HashMap<(String, String), BTreeSet<String>> = HashMap::new();
+        for (loser, winner) in losers {
+            let (loser, winner) = (&claims[loser], &claims[winner]);
+            shadowed.insert((loser.repo.clone(), loser.path.clone()), winner.repo.clone());
+            overrides
+                .entry((winner.repo.clone(), winner.path.clone()))
+                .or_default()
+                .insert(loser.repo.clone());
+        }
+
+        Self {
+            shadowed,
+            overrides,
+            unresolved,
+        }
+    }
+
+    /// The repo that takes over the target of `path` in `repo`, if any
+    pub fn shadowed_by(&self, repo: &str, path: &str) -> Option<&str> {
+        self.shadowed
+            .get(&(repo.to_string(), path.to_string()))
+            .map(String::as_str)
+    }
+
+    /// The repos whose items `path` in `repo` takes the target from
+    pub fn overrides(&self, repo: &str, path: &str) -> Vec<&str> {
+        self.overrides
+            .get(&(repo.to_string(), path.to_string()))
+            .map(|repos| repos.iter().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    /// Split `items` into those to link and those shadowed by another repo
+    pub fn partition(&self, repo: &str, items: Vec<RepoItem>) -> (Vec<RepoItem>, Vec<(RepoItem, String)>) {
+        let mut kept = Vec::new();
+        let mut shadowed = Vec::new();
+        for item in items {
+            match self.shadowed_by(repo, &item.relative_path) {
+                Some(winner) => {
+                    let winner = winner.to_string();
+                    shadowed.push((item, winner));
+                }
+                None => kept.push(item),
+            }
+        }
+        (kept, shadowed)
+    }
+
+    /// Fail with a report if any of `repos` is part of an unresolved collision
+    pub fn ensure_resolved(&self, repos: &[Repo]) -> Result<()> {
+        let relevant: Vec<&Collision> = self
+            .unresolved
+            .iter()
+            .filter(|c| c.items.iter().any(|(name, _)| repos.iter().any(|r| r.name == *name)))
+            .collect();
+
+        if relevant.is_empty() {
+            return Ok(());
+        }
+
+        let mut report = String::new();
+        for collision in relevant {
+            report.push_str(&format!("\n  {}", collision.target.display()));
+            for (repo, path) in &collision.items {
+                report.push_str(&format!("\n    {}: {}", repo, path));
+            }
+        }
+        bail!(
+            "Repos link to the same paths:{}\nSet a higher `priority` for the repo that should win, or order the repos in `layers`.",
+            report
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(repo: &str, path: &str, priority: i32) -> Claim {
+        Claim {
+            repo: repo.to_string(),
+            path: path.to_string(),
+            target: PathBuf::from("/home/user").join(path),
+            priority,
+            layer: None,
+            block: false,
+        }
+    }
+
+    fn layered(repo: &str, path: &str, layer: usize) -> Claim {
+        Claim {
+            layer: Some(layer),
+            ..claim(repo, path, 0)
+        }
+    }
+
+    fn pair(repo: &str, path: &str) -> (String, String) {
+        (repo.to_string(), path.to_string())
+    }
+
+    #[test]
+    fn test_same_target_is_unresolved_without_priority() {
+        let resolution = Resolution::from_claims(vec![
+            claim("dotfiles", ".gitconfig", 0),
+            claim("dotfiles", ".zshrc", 0),
+            claim("work", ".gitconfig", 0),
+        ]);
+
+        assert_eq!(
+            resolution.unresolved,
+            vec![Collision {
+                target: PathBuf::from("/home/user/.gitconfig"),
+                items: vec![pair("dotfiles", ".gitconfig"), pair("work", ".gitconfig")],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_priority_resolves_collision() {
+        let resolution = Resolution::from_claims(vec![
+            claim("dotfiles", ".gitconfig", 0),
+            claim("work", ".gitconfig", 10),
+        ]);
+
+        assert!(resolution.unresolved.is_empty());
+        assert_eq!(resolution.shadowed_by("dotfiles", ".gitconfig"), Some("work"));
+        assert_eq!(resolution.shadowed_by("work", ".gitconfig"), None);
+    }
+
+    #[test]
+    fn test_directory_covering_a_file() {
+        let resolution = Resolution::from_claims(vec![
+            claim("dotfiles", ".config/nvim", 0),
+            claim("work", ".config/nvim/init.lua", 0),
+            claim("work", ".config/nvim-extra", 0),
+        ]);
+
+        assert_eq!(
+            resolution.unresolved,
+            vec![Collision {
+                target: PathBuf::from("/home/user/.config/nvim"),
+                items: vec![pair("dotfiles", ".config/nvim"), pair("work", ".config/nvim/init.lua")],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_items_in_one_repo_never_collide() {
+        let resolution = Resolution::from_claims(vec![
+            claim("dotfiles", ".config/nvim", 0),
+            claim("dotfiles", ".config/nvim/init.lua", 0),
+        ]);
+        assert!(resolution.unresolved.is_empty());
+        assert!(resolution.shadowed.is_empty());
+    }
+
+    #[test]
+    fn test_tie_between_losers_is_ignored() {
+        let resolution = Resolution::from_claims(vec![
+            claim("a", ".gitconfig", 0),
+            claim("b", ".gitconfig", 0),
+            claim("c", ".gitconfig", 5),
+        ]);
+
+        assert!(resolution.unresolved.is_empty());
+        assert_eq!(resolution.shadowed_by("a", ".gitconfig"), Some("c"));
+        assert_eq!(resolution.shadowed_by("b", ".gitconfig"), Some("c"));
+    }
+
+    #[test]
+    fn test_higher_layer_overrides_lower() {
+        let resolution = Resolution::from_claims(vec![
+            layered("work", ".gitconfig", 1),
+            layered("base", ".gitconfig", 0),
+            layered("base", ".zshrc", 0),
+            layered("personal", ".gitconfig", 2),
+        ]);
+
+        assert!(resolution.unresolved.is_empty());
+        assert_eq!(resolution.shadowed_by("base", ".gitconfig"), Some("personal"));
+        assert_eq!(resolution.shadowed_by("work", ".gitconfig"), Some("personal"));
+        assert_eq!(resolution.overrides("personal", ".gitconfig"), vec!["base", "work"]);
+        assert!(resolution.overrides("base", ".zshrc").is_empty());
+    }
+
+    #[test]
+    fn test_priority_beats_layer() {
+        let resolution = Resolution::from_claims(vec![
+            claim("base", ".gitconfig", 10),
+            layered("work", ".gitconfig", 1),
+            layered("unlisted", ".zshrc", 0),
+            claim("scratch", ".zshrc", 0),
+        ]);
+
+        assert_eq!(resolution.shadowed_by("work", ".gitconfig"), Some("base"));
+        // A layered repo and an unlisted one with the same priority still tie
+        assert_eq!(resolution.unresolved.len(), 1);
+        assert_eq!(resolution.unresolved[0].target, PathBuf::from("/home/user/.zshrc"));
+    }
+
+    #[test]
+    fn test_blocks_share_a_file() {
+        let block = |repo| Claim {
+            block: true,
+            ..claim(repo, ".bashrc", 0)
+        };
+        let resolution = Resolution::from_claims(vec![block("dotfiles"), block("work")]);
+        assert!(resolution.unresolved.is_empty());
+        assert!(resolution.shadowed.is_empty());
+
+        let resolution = Resolution::from_claims(vec![block("dotfiles"), claim("work", ".bashrc", 0)]);
+        assert_eq!(resolution.unresolved.len(), 1);
+    }
+}
@@ -4,10 +4,11 @@ use std::fs;
 use std::os::unix::fs as unix_fs;
 use std::path::PathBuf;
 
+use crate::config::GlobalConfig;
 use crate::repo::find_repo;
 
-pub fn run(repo_name: &str, file_path: &str, dry_run: bool) -> Result<()> {
-    let repo = find_repo(repo_name)?
+pub fn run(config: &GlobalConfig, repo_name: &str, file_path: &str, dry_run: bool) -> Result<()> {
+    let repo = find_repo(config, repo_name)?
         .ok_or_else(|| anyhow::anyhow!("Unknown repo: {}", repo_name))?;
 
     // Expand and resolve the file path
@@ -1,63 +1,198 @@
 use anyhow::{bail, Result};
 use colored::Colorize;
-use std::fs;
+use serde::Serialize;
 
-use crate::repo::{discover_repos, find_repo, Repo};
+use crate::config::GlobalConfig;
+use crate::diff::{item_diffs, FileDiff, FileStat};
+use crate::output::{ItemRecord, Output};
+use crate::repo::{discover_repos, find_repo, Repo, RepoItem};
+use crate::strategy::Strategy;
+use crate::template::TemplateEngine;
+use crate::vars::VarResolver;
 
-pub fn run(repo_name: Option<&str>) -> Result<()> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffFormat {
+    /// Unified diffs
+    Patch,
+    /// Changed lines per file
+    Stat,
+    /// Changed file paths only
+    NameOnly,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum DiffKind {
+    /// Target content differs from what link would write
+    Modified,
+    /// Target is a regular file where link would place a symlink
+    Conflict,
+    /// Target does not exist yet
+    New,
+}
+
+#[derive(Debug, Serialize)]
+struct ItemDiff {
+    diff: DiffKind,
+    files: Vec<FileStat>,
+}
+
+/// Maximum width of the `+-` bar in `--stat` output
+const STAT_BAR_WIDTH: usize = 40;
+
+pub fn run(
+    config: &GlobalConfig,
+    repo_name: Option<&str>,
+    format: DiffFormat,
+    output: &Output,
+) -> Result<()> {
     let repos: Vec<Repo> = if let Some(name) = repo_name {
-        match find_repo(name)? {
+        match find_repo(config, name)? {
             Some(repo) => vec![repo],
             None => bail!("Unknown repo: {}", name),
         }
     } else {
-        discover_repos()?
+        discover_repos(config)?
     };
 
     if repos.is_empty() {
-        println!("No repos found.");
-        return Ok(());
+        if output.is_text() {
+            println!("No repos found.");
+        }
+        return output.finish();
     }
 
+    let engine = TemplateEngine::for_config(config);
     let mut found_diff = false;
 
     for repo in &repos {
-        let items = repo.items()?;
-        let mut repo_has_diff = false;
+        let var_resolver = VarResolver::new(config, Some(repo));
+        let mut repo_diffs: Vec<FileDiff> = Vec::new();
 
-        for item in &items {
-            // Skip templates, non-existent targets, and symlinks
-            if item.is_template || !item.target.exists() || item.target.is_symlink() {
+        for item in &repo.items()? {
+            let diffs = item_diffs(item, &repo.path, &engine, &var_resolver)?;
+            if diffs.is_empty() {
                 continue;
             }
 
-            let (symbol, message) = if item.source.is_file() && item.target.is_file() {
-                let source_content = fs::read(&item.source).unwrap_or_default();
-                let target_content = fs::read(&item.target).unwrap_or_default();
-                if source_content == target_content {
-                    continue;
-                }
-                ("M".yellow(), "modified in target")
-            } else {
-                ("!".red(), "target is regular file, repo has version")
+            let state = ItemDiff {
+                diff: diff_kind(item, &diffs),
+                files: diffs.iter().map(FileDiff::stat).collect(),
             };
+            output.emit(&ItemRecord::for_item(&repo.name, item, state))?;
+            repo_diffs.extend(diffs);
+        }
 
-            if !repo_has_diff {
-                println!("{}:", repo.name.bold());
-                repo_has_diff = true;
-                found_diff = true;
-            }
-            println!("  {} {} ({})", symbol, item.relative_path, message);
+        if repo_diffs.is_empty() {
+            continue;
         }
+        found_diff = true;
 
-        if repo_has_diff {
-            println!();
+        if output.is_text() {
+            print_diffs(&repo.name, &repo_diffs, format);
         }
     }
 
-    if !found_diff {
+    if !found_diff && output.is_text() && format != DiffFormat::NameOnly {
         println!("No differences found.");
     }
 
-    Ok(())
+    output.finish()
+}
+
+fn diff_kind(item: &RepoItem, diffs: &[FileDiff]) -> DiffKind {
+    if !item.is_template && !item.strategy.is_copy() && item.strategy != Strategy::Block {
+        DiffKind::Conflict
+    } else if diffs.iter().all(|d| d.current.is_none()) {
+        DiffKind::New
+    } else {
+        DiffKind::Modified
+    }
+}
+
+fn print_diffs(repo_name: &str, diffs: &[FileDiff], format: DiffFormat) {
+    match format {
+        DiffFormat::NameOnly => {
+            for diff in diffs {
+                println!("{}", diff.path);
+            }
+        }
+        DiffFormat::Stat => {
+            println!("{}:", repo_name.bold());
+            print_stat(diffs);
+            println!();
+        }
+        DiffFormat::Patch => {
+            println!("{}:", repo_name.bold());
+            for diff in diffs {
+                for line in diff.unified().lines() {
+                    println!("{}", color_line(line));
+                }
+            }
+            println!();
+        }
+    }
+}
+
+fn print_stat(diffs: &[FileDiff]) {
+    let stats: Vec<FileStat> = diffs.iter().map(FileDiff::stat).collect();
+    let width = stats.iter().map(|s| s.path.len()).max().unwrap_or(0);
+    let largest = stats
+        .iter()
+        .map(|s| s.insertions + s.deletions)
+        .max()
+        .unwrap_or(0);
+
+    for (stat, diff) in stats.iter().zip(diffs) {
+        if stat.binary {
+            let size = |content: &Option<Vec<u8>>| content.as_ref().map_or(0, Vec::len);
+            println!(
+                "  {:width$} | Bin {} -> {} bytes",
+                stat.path,
+                size(&diff.current),
+                size(&diff.expected)
+            );
+            continue;
+        }
+
+        let changed = stat.insertions + stat.deletions;
+        let (plus, minus) = if largest > STAT_BAR_WIDTH {
+            (
+                (stat.insertions * STAT_BAR_WIDTH).div_ceil(largest),
+                (stat.deletions * STAT_BAR_WIDTH).div_ceil(largest),
+            )
+        } else {
+            (stat.insertions, stat.deletions)
+        };
+        println!(
+            "  {:width$} | {} {}{}",
+            stat.path,
+            changed,
+            "+".repeat(plus).green(),
+            "-".repeat(minus).red()
+        );
+    }
+
+    let insertions: usize = stats.iter().map(|s| s.insertions).sum();
+    let deletions: usize = stats.iter().map(|s| s.deletions).sum();
+    println!(
+        "  {} file(s) changed, {} insertion(s)(+), {} deletion(s)(-)",
+        stats.len(),
+        insertions,
+        deletions
+    );
+}
+
+fn color_line(line: &str) -> String {
+    if line.starts_with("---") || line.starts_with("+++") {
+        line.bold().to_string()
+    } else if line.starts_with("@@") {
+        line.cyan().to_string()
+    } else if line.starts_with('+') {
+        line.green().to_string()
+    } else if line.starts_with('-') {
+        line.red().to_string()
+    } else {
+        line.to_string()
+    }
 }
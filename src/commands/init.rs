@@ -57,6 +57,14 @@ strategy = "file"  # file | directory | contents
 # Paths to ignore (in addition to defaults like .git, homie.toml)
 # [ignore]
 # paths = ["*.swp", "temp/"]
+
+# Only apply on matching machines (os, hostname, user, or any var via match)
+# [[when]]
+# os = "macos"
+# hostname = "work-*"
+# ignore = {{ paths = [".config/i3"] }}
+# strategies = {{ "Library/Preferences" = "copy" }}
+# vars = {{ editor = "nvim" }}
 "#,
         target_path
     );
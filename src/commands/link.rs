@@ -1,83 +1,520 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use colored::Colorize;
+use std::collections::HashSet;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::collision;
+use crate::commands::Outcome;
 use crate::config::GlobalConfig;
-use crate::linker::{print_result, LinkOptions, LinkResult, Linker};
-use crate::manifest::Manifest;
-use crate::repo::{discover_repos, find_repo, Repo};
+use crate::hooks::HookRunner;
+use crate::journal::{new_run_id, prune_generations, Journal};
+use crate::linker::{orphan_blocker, print_result, LinkOptions, LinkResult, Linker};
+use crate::manifest::{Manifest, ManifestRecord};
+use crate::output::{ErrorState, ItemRecord, Output};
+use crate::repo::{discover_repos, find_repo, Repo, RepoItem};
+use crate::scripts;
+use crate::strategy::SyncPolicy;
+use crate::sync::{self, PullOutcome};
 use crate::vars::VarResolver;
 
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// A repo's uncommitted changes for this run
+pub(crate) struct Transaction<'a> {
+    pub(crate) repo: &'a Repo,
+    pub(crate) journal: Journal,
+    pub(crate) manifest: Manifest,
+    /// The manifest from the last run, for detecting target-side edits
+    pub(crate) previous: Manifest,
+    pub(crate) skipped: usize,
+    /// Items created or backed up, for hooks
+    pub(crate) changed: Vec<String>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(repo: &'a Repo) -> Result<Self> {
+        Ok(Self {
+            repo,
+            journal: Journal::new(&repo.path),
+            manifest: Manifest::default(),
+            previous: Manifest::load(&repo.path)?,
+            skipped: 0,
+            changed: Vec::new(),
+        })
+    }
+}
+
 pub fn run(
     config: &GlobalConfig,
     repo_name: Option<&str>,
     options: LinkOptions,
-) -> Result<()> {
+    output: &Output,
+) -> Result<Outcome> {
     let linker = Linker::new(config.clone());
 
     let repos: Vec<Repo> = if let Some(name) = repo_name {
-        match find_repo(name)? {
+        match find_repo(config, name)? {
             Some(repo) => vec![repo],
             None => bail!("Unknown repo: {}. Run 'homie list' to see available repos.", name),
         }
     } else {
-        discover_repos()?
+        discover_repos(config)?
     };
 
     if repos.is_empty() {
-        println!("No repos found in ~/.homie/repos/");
-        println!("Run 'homie init <name>' to create a new repo.");
-        return Ok(());
+        if output.is_text() {
+            println!("No repos found in ~/.homie/repos/");
+            println!("Run 'homie init <name>' to create a new repo.");
+        }
+        output.finish()?;
+        return Ok(Outcome::Clean);
     }
 
+    // Every active repo's targets are checked, even when linking just one
+    let resolution = collision::resolve(config, &repos, repo_name.is_some())?;
+    resolution.ensure_resolved(&repos)?;
+
+    if !options.dry_run {
+        // Let the current item finish, then roll back instead of dying mid-run
+        let _ = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst));
+    }
+
+    // Every repo's changes stay uncommitted until all repos have linked
+    let mut pending: Vec<Transaction> = Vec::new();
+
     for repo in &repos {
-        println!("{}:", repo.name.bold());
+        if output.is_text() {
+            println!("{}:", repo.name.bold());
+        }
+
+        if !options.dry_run {
+            recover_interrupted(repo, output)?;
+        }
 
         if !options.no_fetch && !repo.imports.is_empty() {
-            repo.fetch_imports(options.dry_run)?;
+            repo.fetch_imports(options.dry_run, !output.is_text())?;
         }
 
         let var_resolver = VarResolver::new(config, Some(repo));
-        let items = repo.items()?;
+        let (items, shadowed) = resolution.partition(&repo.name, repo.items()?);
 
-        if items.is_empty() {
+        // Repos without items still run their hooks and scripts
+        if items.is_empty() && output.is_text() {
             println!("  (no items to link)");
-            continue;
         }
 
-        let mut manifest = Manifest::default();
+        let mut tx = Transaction::new(repo)?;
+        let outcome = link_items(&linker, &mut tx, &items, &shadowed, &var_resolver, options, output);
+        pending.push(tx);
 
-        for item in &items {
-            match linker.link_item(item, &var_resolver, &repo.path, options) {
-                Ok(result) => {
-                    print_result(&item.relative_path, &result, options.verbose);
+        if let Err(e) = outcome {
+            if output.is_text() {
+                println!();
+            }
+            rollback_all(pending, output);
+            output.finish()?;
+            return Err(e.context("Link failed, all changes were rolled back"));
+        }
 
-                    let entry = match &result {
-                        LinkResult::Created { entry } => Some(*entry),
-                        LinkResult::AlreadyCorrect { entry } => Some(*entry),
-                        LinkResult::BackedUp { entry, .. } => Some(*entry),
-                        LinkResult::Skipped { .. } => None,
-                        LinkResult::Unlinked => None,
-                    };
-                    if let Some(entry) = entry {
-                        manifest.insert(item.relative_path.clone(), entry);
-                    }
-                }
-                Err(e) => {
+        if output.is_text() {
+            println!();
+        }
+    }
+
+    let skipped: usize = pending.iter().map(|tx| tx.skipped).sum();
+    let run = new_run_id();
+    let mut linked = Vec::new();
+
+    for tx in pending {
+        linked.push(commit(tx, &run, options, config.settings.keep_generations)?);
+    }
+
+    let failed = run_scripts_and_hooks(&linker, config, linked, options, output);
+
+    if options.dry_run && output.is_text() {
+        println!("{}", "(dry run - no changes made)".dimmed());
+    }
+
+    output.finish()?;
+
+    if failed > 0 {
+        bail!("Scripts or post-link hooks failed in {} repo(s)", failed);
+    }
+
+    Ok(if skipped > 0 { Outcome::Skipped } else { Outcome::Clean })
+}
+
+/// Record a repo's part of `run` as a generation, keeping the newest `keep`,
+/// and save its manifest. A dry run leaves the journal directory alone: it
+/// may hold an interrupted run's journal, which was not recovered.
+pub(crate) fn commit<'a>(
+    tx: Transaction<'a>,
+    run: &str,
+    options: LinkOptions,
+    keep: usize,
+) -> Result<(&'a Repo, Vec<String>)> {
+    if options.dry_run {
+        return Ok((tx.repo, tx.changed));
+    }
+
+    let repo_path = &tx.repo.path;
+    tx.journal.commit(repo_path, &Manifest::path_for_repo(repo_path), run)?;
+    prune_generations(repo_path, keep)?;
+
+    // A manifest emptied by pruning is still saved
+    let emptied = tx.manifest.is_empty() && !tx.previous.is_empty();
+    if !tx.manifest.is_empty() || emptied {
+        sync::record_bases(tx.repo, &tx.manifest)?;
+        tx.manifest.save(repo_path)?;
+    }
+
+    Ok((tx.repo, tx.changed))
+}
+
+/// Run each committed repo's scripts and post-link hooks, returning how many
+/// repos failed. Changes are committed by now, so failures are only reported.
+pub(crate) fn run_scripts_and_hooks(
+    linker: &Linker,
+    config: &GlobalConfig,
+    linked: Vec<(&Repo, Vec<String>)>,
+    options: LinkOptions,
+    output: &Output,
+) -> usize {
+    let mut failed = 0;
+    for (repo, changed) in linked {
+        let runner = HookRunner::new(repo, options.dry_run, !output.is_text());
+        let var_resolver = VarResolver::new(config, Some(repo));
+        let result = scripts::run_scripts(
+            repo,
+            linker.template_engine(),
+            &var_resolver,
+            &runner,
+            &changed,
+            options.dry_run,
+        )
+        .and_then(|()| run_post_link_hooks(&runner, repo, &changed));
+
+        if let Err(e) = result {
+            eprintln!("{} {}: {:#}", "✗".red(), repo.name, e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+/// Run the repo's `pre_link` hook, before anything of it is linked
+pub(crate) fn run_pre_link_hook(repo: &Repo, options: LinkOptions, output: &Output) -> Result<()> {
+    if let Some(command) = &repo.config.hooks.pre_link {
+        HookRunner::new(repo, options.dry_run, !output.is_text()).run("pre_link", command, &[])?;
+    }
+    Ok(())
+}
+
+fn run_post_link_hooks(runner: &HookRunner, repo: &Repo, changed: &[String]) -> Result<()> {
+    let hooks = &repo.config.hooks;
+
+    for (pattern, command, matched) in hooks.path_hooks_for(changed) {
+        runner.run(pattern, command, &matched)?;
+    }
+
+    if let Some(command) = &hooks.post_link {
+        runner.run("post_link", command, changed)?;
+    }
+
+    Ok(())
+}
+
+fn link_items(
+    linker: &Linker,
+    tx: &mut Transaction,
+    items: &[RepoItem],
+    shadowed: &[(RepoItem, String)],
+    var_resolver: &VarResolver,
+    options: LinkOptions,
+    output: &Output,
+) -> Result<()> {
+    let repo = tx.repo;
+    run_pre_link_hook(repo, options, output)?;
+
+    // Targets taken over by a higher-priority repo are left to it
+    for (item, winner) in shadowed {
+        let result = LinkResult::Skipped {
+            reason: format!("shadowed by {}", winner),
+        };
+        if output.is_text() && options.verbose {
+            print_result(&item.relative_path, &result, options.verbose);
+        }
+        output.emit(&ItemRecord::for_item(&repo.name, item, &result))?;
+    }
+
+    for item in items {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            bail!("Interrupted");
+        }
+
+        if item.sync == SyncPolicy::Pull && !pull_target_edits(linker, tx, item, var_resolver, options, output)? {
+            continue;
+        }
+
+        let result = match linker.link_item(item, var_resolver, &repo.path, options, &mut tx.journal) {
+            Ok(result) => result,
+            Err(e) => {
+                if output.is_text() {
                     println!("  {} {} ({})", "✗".red(), item.relative_path, e);
                 }
+                output.emit(&ItemRecord::for_item(
+                    &repo.name,
+                    item,
+                    ErrorState::new(format!("{:#}", e)),
+                ))?;
+                return Err(e)
+                    .with_context(|| format!("{}: failed to link {}", repo.name, item.relative_path));
             }
+        };
+
+        if output.is_text() {
+            print_result(&item.relative_path, &result, options.verbose);
+        }
+        output.emit(&ItemRecord::for_item(&repo.name, item, &result))?;
+
+        let entry = match &result {
+            LinkResult::Created { entry } | LinkResult::BackedUp { entry, .. } => {
+                tx.changed.push(item.relative_path.clone());
+                Some(*entry)
+            }
+            LinkResult::AlreadyCorrect { entry } => Some(*entry),
+            LinkResult::Skipped { .. } => {
+                tx.skipped += 1;
+                None
+            }
+            LinkResult::Unlinked => None,
+        };
+        if let Some(entry) = entry {
+            tx.manifest
+                .insert(item.relative_path.clone(), repo.record_for(item, entry)?);
+        }
+    }
+
+    let current: HashSet<&str> = items
+        .iter()
+        .chain(shadowed.iter().map(|(item, _)| item))
+        .map(|item| item.relative_path.as_str())
+        .collect();
+    prune_orphans(linker, tx, &current, options, output)
+}
+
+/// Remove the targets of manifest entries whose items are gone from the repo.
+/// Entries left in place are carried over so `status` keeps reporting them.
+pub(crate) fn prune_orphans(
+    linker: &Linker,
+    tx: &mut Transaction,
+    current: &HashSet<&str>,
+    options: LinkOptions,
+    output: &Output,
+) -> Result<()> {
+    let repo = tx.repo;
+    let orphans: Vec<(String, ManifestRecord)> = tx
+        .previous
+        .iter()
+        .filter(|(path, _)| !current.contains(path.as_str()))
+        .map(|(path, record)| (path.clone(), record.clone()))
+        .collect();
+
+    for (path, record) in orphans {
+        if options.no_prune {
+            tx.manifest.insert(path, record);
+            continue;
         }
 
-        if !options.dry_run && !manifest.is_empty() {
-            manifest.save(&repo.path)?;
+        let target = repo.manifest_target(&path, &record);
+        if !target.exists() && !target.is_symlink() {
+            continue;
+        }
+
+        let result = match orphan_blocker(&target, &record, &repo.path) {
+            Some(reason) => LinkResult::Skipped { reason },
+            None => linker.unlink_from_manifest(
+                &target,
+                record.kind,
+                &repo.path,
+                options,
+                Some(&mut tx.journal),
+            )?,
+        };
+
+        if output.is_text() {
+            match &result {
+                LinkResult::Unlinked => println!("  {} {} (removed)", "-".red(), path),
+                result => print_result(&path, result, options.verbose),
+            }
         }
+        output.emit(&ItemRecord {
+            repo: &repo.name,
+            path: &path,
+            source: None,
+            target: &target,
+            strategy: None,
+            state: &result,
+        })?;
 
-        println!();
+        if matches!(result, LinkResult::Skipped { .. }) {
+            tx.skipped += 1;
+            tx.manifest.insert(path, record);
+        }
     }
 
-    if options.dry_run {
-        println!("{}", "(dry run - no changes made)".dimmed());
+    Ok(())
+}
+
+/// Bring target-side edits of a `sync = "pull"` item into the repo before
+/// it is linked. Returns false if the item should not be linked this run.
+fn pull_target_edits(
+    linker: &Linker,
+    tx: &mut Transaction,
+    item: &RepoItem,
+    var_resolver: &VarResolver,
+    options: LinkOptions,
+    output: &Output,
+) -> Result<bool> {
+    let repo = tx.repo;
+    let outcome = sync::plan_pull(
+        item,
+        &tx.previous,
+        &repo.path,
+        linker.template_engine(),
+        var_resolver,
+        false,
+    )?;
+
+    match outcome {
+        PullOutcome::Pull(content) => {
+            if output.is_text() {
+                println!("  {} {} (pulled from target)", "←".cyan(), item.relative_path);
+            }
+            if options.dry_run {
+                return Ok(false);
+            }
+
+            let permissions = fs::metadata(&item.source)
+                .with_context(|| format!("Failed to read: {}", item.source.display()))?
+                .permissions();
+            tx.journal.remove(&item.source)?;
+            fs::write(&item.source, content)
+                .with_context(|| format!("Failed to write: {}", item.source.display()))?;
+            fs::set_permissions(&item.source, permissions)
+                .with_context(|| format!("Failed to set permissions: {}", item.source.display()))?;
+            tx.journal.record_created(&item.source)?;
+            Ok(true)
+        }
+        PullOutcome::Conflict | PullOutcome::Merged { .. } => {
+            let result = LinkResult::Skipped {
+                reason: "changed in repo and target, run 'homie pull-back --merge'".to_string(),
+            };
+            if output.is_text() {
+                print_result(&item.relative_path, &result, options.verbose);
+            }
+            output.emit(&ItemRecord::for_item(&repo.name, item, &result))?;
+            tx.skipped += 1;
+
+            // Keep the previous record so the next run still sees the edit
+            if let Some(record) = tx.previous.get(&item.relative_path) {
+                tx.manifest.insert(item.relative_path.clone(), record.clone());
+            }
+            Ok(false)
+        }
+        PullOutcome::Untracked | PullOutcome::Unchanged => Ok(true),
+    }
+}
+
+pub(crate) fn rollback_all(pending: Vec<Transaction>, output: &Output) {
+    for tx in pending.into_iter().rev() {
+        if tx.journal.is_empty() {
+            continue;
+        }
+
+        let repo = tx.repo;
+        let result = tx.journal.rollback();
+        if !output.is_text() {
+            if let Err(e) = result {
+                eprintln!("{}: {:#}", repo.name, e);
+            }
+            continue;
+        }
+
+        match result {
+            Ok(undone) => println!(
+                "{} {} ({} changes undone)",
+                "↺".yellow(),
+                repo.name.bold(),
+                undone
+            ),
+            Err(e) => println!("{} {} ({:#})", "✗".red(), repo.name.bold(), e),
+        }
+    }
+}
+
+/// Roll back a journal left behind by a run that was killed mid-way
+pub(crate) fn recover_interrupted(repo: &Repo, output: &Output) -> Result<()> {
+    let Some(journal) = Journal::load_pending(&repo.path)? else {
+        return Ok(());
+    };
+
+    let undone = journal
+        .rollback()
+        .context("Failed to roll back interrupted link run")?;
+    if !output.is_text() {
+        return Ok(());
     }
+    println!(
+        "  {} rolled back {} changes from an interrupted run",
+        "↺".yellow(),
+        undone
+    );
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::OutputFormat;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_dry_run_keeps_interrupted_journal() {
+        let temp = TempDir::new().unwrap();
+        let home = temp.path().join("home");
+        let repo_path = temp.path().join("dotfiles");
+        fs::create_dir_all(&home).unwrap();
+        fs::create_dir_all(&repo_path).unwrap();
+        fs::write(
+            repo_path.join("homie.toml"),
+            format!("target = \"{}\"", home.display()),
+        )
+        .unwrap();
+        fs::write(repo_path.join(".zshrc"), "from repo").unwrap();
+        fs::write(home.join(".zshrc"), "mine").unwrap();
+
+        // A run killed right after stashing the user's file
+        let mut interrupted = Journal::new(&repo_path);
+        interrupted.remove(&home.join(".zshrc")).unwrap();
+        drop(interrupted);
+
+        let config = GlobalConfig::default();
+        let repo = Repo::from_path(&repo_path, &config).unwrap();
+        let linker = Linker::new(config.clone());
+        let options = LinkOptions { dry_run: true, ..Default::default() };
+        let output = Output::new(OutputFormat::Text).quiet();
+
+        let mut tx = Transaction::new(&repo).unwrap();
+        let items = repo.items().unwrap();
+        let var_resolver = VarResolver::new(&config, Some(&repo));
+        link_items(&linker, &mut tx, &items, &[], &var_resolver, options, &output).unwrap();
+        commit(tx, "run", options, 0).unwrap();
+
+        let pending = Journal::load_pending(&repo.path).unwrap().expect("journal was discarded");
+        pending.rollback().unwrap();
+        assert_eq!(fs::read_to_string(home.join(".zshrc")).unwrap(), "mine");
+    }
+}
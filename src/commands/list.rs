@@ -1,11 +1,42 @@
 use anyhow::Result;
 use colored::Colorize;
+use serde::Serialize;
+use std::path::Path;
 
-use crate::config::repos_dir;
+use crate::config::{repos_dir, GlobalConfig};
+use crate::output::Output;
 use crate::repo::discover_repos;
 
-pub fn run() -> Result<()> {
-    let repos = discover_repos()?;
+#[derive(Serialize)]
+struct RepoRecord<'a> {
+    repo: &'a str,
+    path: &'a Path,
+    target: &'a Path,
+    items: usize,
+    vars: Vec<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<&'a str>,
+}
+
+pub fn run(config: &GlobalConfig, output: &Output) -> Result<()> {
+    let repos = discover_repos(config)?;
+
+    if !output.is_text() {
+        for repo in &repos {
+            let mut vars: Vec<&String> = repo.config.vars.keys().collect();
+            vars.sort();
+
+            output.emit(&RepoRecord {
+                repo: &repo.name,
+                path: &repo.path,
+                target: &repo.target,
+                items: repo.items().map(|i| i.len()).unwrap_or(0),
+                vars,
+                profile: config.active_profile.as_deref(),
+            })?;
+        }
+        return output.finish();
+    }
 
     if repos.is_empty() {
         let repos_path = repos_dir()?;
@@ -21,6 +52,10 @@ pub fn run() -> Result<()> {
 
     println!("Repos in ~/.homie/repos/:\n");
 
+    if let Some(profile) = &config.active_profile {
+        println!("  {} {}\n", "profile:".dimmed(), profile.bold());
+    }
+
     for repo in &repos {
         let items_count = repo.items().map(|i| i.len()).unwrap_or(0);
         let status = format!("{} items", items_count).green();
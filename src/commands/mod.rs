@@ -4,5 +4,34 @@ pub mod diff;
 pub mod init;
 pub mod link;
 pub mod list;
+pub mod plan;
+pub mod pull_back;
+pub mod rollback;
+pub mod secret;
 pub mod status;
+pub mod template;
 pub mod unlink;
+pub mod watch;
+
+use std::process::ExitCode;
+
+/// How a command finished. Errors exit with 1 from `main`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Everything is in the desired state (exit 0)
+    Clean,
+    /// Missing, conflicting or orphaned items were found (exit 2)
+    Drift,
+    /// Some items were skipped and left as they were (exit 3)
+    Skipped,
+}
+
+impl Outcome {
+    pub fn exit_code(self) -> ExitCode {
+        match self {
+            Outcome::Clean => ExitCode::SUCCESS,
+            Outcome::Drift => ExitCode::from(2),
+            Outcome::Skipped => ExitCode::from(3),
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use anyhow::{anyhow, bail, Context, Result};
+use colored::Colorize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::collision;
+use crate::commands::link::{
+    commit, recover_interrupted, rollback_all, run_pre_link_hook, run_scripts_and_hooks, Transaction,
+};
+use crate::commands::Outcome;
+use crate::config::GlobalConfig;
+use crate::journal::new_run_id;
+use crate::linker::{print_result, LinkOptions, LinkResult, Linker};
+use crate::manifest::Manifest;
+use crate::output::{ErrorState, ItemRecord, Output};
+use crate::plan::{self, Action, Plan, PlannedOp, RepoPlan};
+use crate::repo::{discover_repos, find_repo, Repo, RepoItem};
+use crate::vars::VarResolver;
+
+#[derive(Debug, Serialize)]
+struct PlanState<'a> {
+    action: Action,
+    reason: &'a str,
+}
+
+/// Work out what `link` would do and optionally save it for `homie apply`
+pub fn run(
+    config: &GlobalConfig,
+    repo_name: Option<&str>,
+    force: bool,
+    prune: bool,
+    out: Option<&Path>,
+    output: &Output,
+) -> Result<Outcome> {
+    let linker = Linker::new(config.clone());
+
+    let repos: Vec<Repo> = if let Some(name) = repo_name {
+        match find_repo(config, name)? {
+            Some(repo) => vec![repo],
+            None => bail!("Unknown repo: {}. Run 'homie list' to see available repos.", name),
+        }
+    } else {
+        discover_repos(config)?
+    };
+
+    if repos.is_empty() {
+        if output.is_text() {
+            println!("No repos found in ~/.homie/repos/");
+        }
+        output.finish()?;
+        return Ok(Outcome::Clean);
+    }
+
+    let resolution = collision::resolve(config, &repos, repo_name.is_some())?;
+    resolution.ensure_resolved(&repos)?;
+
+    let mut plan = Plan::new(force);
+    for repo in &repos {
+        let var_resolver = VarResolver::new(config, Some(repo));
+        let repo_plan = plan::plan_repo(&linker, repo, &resolution, &var_resolver, force, prune)
+            .with_context(|| format!("{}: failed to plan", repo.name))?;
+
+        for op in &repo_plan.ops {
+            output.emit(&ItemRecord {
+                repo: &repo.name,
+                path: &op.path,
+                source: None,
+                target: &op.target,
+                strategy: None,
+                state: PlanState {
+                    action: op.action,
+                    reason: &op.reason,
+                },
+            })?;
+        }
+        if output.is_text() && !repo_plan.ops.is_empty() {
+            print_repo_plan(&repo_plan);
+        }
+
+        plan.repos.push(repo_plan);
+    }
+
+    let changes = plan.change_count();
+    if output.is_text() {
+        print_summary(&plan);
+    }
+
+    if let Some(out) = out {
+        plan.save(out)?;
+        if output.is_text() {
+            println!("Saved to {}. Run 'homie apply {}' to apply it.", out.display(), out.display());
+        }
+    }
+
+    output.finish()?;
+    Ok(if changes > 0 { Outcome::Drift } else { Outcome::Clean })
+}
+
+fn print_repo_plan(repo_plan: &RepoPlan) {
+    println!("{}:", repo_plan.name.bold());
+    for op in &repo_plan.ops {
+        let symbol = match op.action {
+            Action::CreateSymlink | Action::Copy | Action::Render | Action::Decrypt => "+".green(),
+            Action::ReplaceSymlink | Action::MergeBlock => "~".yellow(),
+            Action::BackupReplace => "⚠".yellow(),
+            Action::RemoveOrphan => "-".red(),
+            Action::Skip => "⊘".yellow(),
+        };
+        println!(
+            "  {} {} {} ({})",
+            symbol,
+            op.path,
+            action_label(op.action),
+            op.reason.dimmed()
+        );
+    }
+    println!();
+}
+
+fn action_label(action: Action) -> &'static str {
+    match action {
+        Action::CreateSymlink => "create symlink",
+        Action::ReplaceSymlink => "replace symlink",
+        Action::BackupReplace => "backup and replace",
+        Action::Copy => "copy",
+        Action::Render => "render",
+        Action::Decrypt => "decrypt",
+        Action::MergeBlock => "merge block",
+        Action::RemoveOrphan => "remove orphan",
+        Action::Skip => "skip",
+    }
+}
+
+fn print_summary(plan: &Plan) {
+    let ops = || plan.repos.iter().flat_map(|r| &r.ops);
+    let removals = ops().filter(|op| op.action == Action::RemoveOrphan).count();
+    let skips = ops().filter(|op| op.action == Action::Skip).count();
+    let changes = plan.change_count() - removals;
+
+    if changes + removals + skips == 0 {
+        println!("Nothing to do, everything is linked.");
+        return;
+    }
+
+    println!(
+        "Plan: {} to change, {} to remove, {} skipped",
+        changes, removals, skips
+    );
+}
+
+/// Execute a saved plan, refusing if anything it covers changed since
+pub fn apply(
+    config: &GlobalConfig,
+    plan_path: &Path,
+    verbose: bool,
+    dry_run: bool,
+    output: &Output,
+) -> Result<Outcome> {
+    let plan = Plan::load(plan_path)?;
+    let linker = Linker::new(config.clone());
+
+    let mut repos = Vec::new();
+    let mut repo_items = Vec::new();
+    for repo_plan in &plan.repos {
+        let Some(repo) = find_repo(config, &repo_plan.name)? else {
+            bail!("Repo in plan not found: {}", repo_plan.name);
+        };
+        if repo.path != repo_plan.path {
+            bail!(
+                "Repo {} moved since planning ({} -> {})",
+                repo.name,
+                repo_plan.path.display(),
+                repo.path.display()
+            );
+        }
+        if !dry_run {
+            recover_interrupted(&repo, output)?;
+        }
+        repo_items.push(repo.items()?);
+        repos.push(repo);
+    }
+
+    // Check everything before changing anything
+    let resolution = collision::resolve(config, &repos, true)?;
+    resolution.ensure_resolved(&repos)?;
+    let mut stale = Vec::new();
+    for (repo_plan, (repo, items)) in plan.repos.iter().zip(repos.iter().zip(&repo_items)) {
+        let var_resolver = VarResolver::new(config, Some(repo));
+        let items: HashMap<&str, &RepoItem> =
+            items.iter().map(|i| (i.relative_path.as_str(), i)).collect();
+
+        for op in &repo_plan.ops {
+            let result = match resolution.shadowed_by(&repo.name, &op.path) {
+                Some(winner) => Err(anyhow!("now shadowed by {}", winner)),
+                None => verify_op(&linker, op, &items, &var_resolver),
+            };
+            if let Err(e) = result {
+                stale.push(format!("  {}/{}: {:#}", repo.name, op.path, e));
+            }
+        }
+    }
+
+    if !stale.is_empty() {
+        bail!(
+            "The filesystem changed since {} was planned, nothing was applied:\n{}\nRun 'homie plan' again.",
+            plan_path.display(),
+            stale.join("\n")
+        );
+    }
+
+    let options = LinkOptions {
+        dry_run,
+        force: false,
+        verbose,
+        no_fetch: true,
+        no_prune: false,
+    };
+
+    // Every repo's changes stay uncommitted until all repos have applied
+    let mut pending: Vec<Transaction> = Vec::new();
+
+    for (repo_plan, (repo, items)) in plan.repos.iter().zip(repos.iter().zip(&repo_items)) {
+        let quiet = !output.is_text() || repo_plan.ops.is_empty();
+        if !quiet {
+            println!("{}:", repo.name.bold());
+        }
+
+        let var_resolver = VarResolver::new(config, Some(repo));
+        // Only planned items change; every other entry stays as it is
+        let mut tx = Transaction::new(repo)?;
+        tx.manifest = Manifest::load(&repo.path)?;
+
+        let result = apply_ops(&linker, &mut tx, repo_plan, items, &var_resolver, options, output);
+        pending.push(tx);
+
+        if let Err(e) = result {
+            rollback_all(pending, output);
+            output.finish()?;
+            return Err(e.context("Apply failed, all changes were rolled back"));
+        }
+
+        if !quiet {
+            println!();
+        }
+    }
+
+    let skipped: usize = pending.iter().map(|tx| tx.skipped).sum();
+    let run = new_run_id();
+    let mut applied = Vec::new();
+    for tx in pending {
+        applied.push(commit(tx, &run, options, config.settings.keep_generations)?);
+    }
+
+    let failed = run_scripts_and_hooks(&linker, config, applied, options, output);
+
+    if output.is_text() {
+        if plan.repos.iter().all(|r| r.ops.is_empty()) {
+            println!("Nothing to apply.");
+        } else if dry_run {
+            println!("{}", "(dry run - no changes made)".dimmed());
+        }
+    }
+
+    output.finish()?;
+
+    if failed > 0 {
+        bail!("Scripts or post-link hooks failed in {} repo(s)", failed);
+    }
+
+    Ok(if skipped > 0 { Outcome::Skipped } else { Outcome::Clean })
+}
+
+fn verify_op(
+    linker: &Linker,
+    op: &PlannedOp,
+    items: &HashMap<&str, &RepoItem>,
+    var_resolver: &VarResolver,
+) -> Result<()> {
+    let item = items.get(op.path.as_str()).copied();
+
+    match (op.action, item) {
+        (Action::RemoveOrphan, Some(_)) => bail!("is back in the repo"),
+        (Action::RemoveOrphan | Action::Skip, None) => op.verify(linker, None, var_resolver),
+        (_, None) => bail!("no longer in the repo"),
+        (_, Some(item)) if item.target != op.target => {
+            bail!("target moved to {}", item.target.display())
+        }
+        (_, Some(item)) => op.verify(linker, Some(item), var_resolver),
+    }
+}
+
+/// Link the planned items of one repo, after its `pre_link` hook
+fn apply_ops(
+    linker: &Linker,
+    tx: &mut Transaction,
+    repo_plan: &RepoPlan,
+    items: &[RepoItem],
+    var_resolver: &VarResolver,
+    options: LinkOptions,
+    output: &Output,
+) -> Result<()> {
+    let repo = tx.repo;
+    run_pre_link_hook(repo, options, output)?;
+
+    for op in &repo_plan.ops {
+        if op.action == Action::Skip {
+            let result = LinkResult::Skipped { reason: op.reason.clone() };
+            if output.is_text() {
+                print_result(&op.path, &result, options.verbose);
+            }
+            output.emit(&ItemRecord {
+                repo: &repo.name,
+                path: &op.path,
+                source: None,
+                target: &op.target,
+                strategy: None,
+                state: &result,
+            })?;
+            tx.skipped += 1;
+            continue;
+        }
+
+        let Some(item) = items.iter().find(|i| i.relative_path == op.path) else {
+            continue;
+        };
+
+        let options = LinkOptions {
+            force: op.action == Action::BackupReplace,
+            ..options
+        };
+        let result = linker
+            .link_item(item, var_resolver, &repo.path, options, &mut tx.journal)
+            .and_then(|result| match result {
+                LinkResult::Skipped { reason } => bail!("{} (the plan expected to link it)", reason),
+                result => Ok(result),
+            });
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                if output.is_text() {
+                    println!("  {} {} ({})", "✗".red(), item.relative_path, e);
+                }
+                output.emit(&ItemRecord::for_item(
+                    &repo.name,
+                    item,
+                    ErrorState::new(format!("{:#}", e)),
+                ))?;
+                return Err(e)
+                    .with_context(|| format!("{}: failed to link {}", repo.name, item.relative_path));
+            }
+        };
+
+        if output.is_text() {
+            print_result(&item.relative_path, &result, options.verbose);
+        }
+        output.emit(&ItemRecord::for_item(&repo.name, item, &result))?;
+
+        let entry = match result {
+            LinkResult::Created { entry } | LinkResult::BackedUp { entry, .. } => {
+                tx.changed.push(item.relative_path.clone());
+                entry
+            }
+            LinkResult::AlreadyCorrect { entry } => entry,
+            LinkResult::Skipped { .. } | LinkResult::Unlinked => continue,
+        };
+        tx.manifest
+            .insert(item.relative_path.clone(), repo.record_for(item, entry)?);
+    }
+
+    // Orphans go last, as in `link`, in the same journal
+    for op in repo_plan.ops.iter().filter(|op| op.action == Action::RemoveOrphan) {
+        remove_orphan(linker, tx, op, options, output)?;
+    }
+
+    Ok(())
+}
+
+fn remove_orphan(
+    linker: &Linker,
+    tx: &mut Transaction,
+    op: &PlannedOp,
+    options: LinkOptions,
+    output: &Output,
+) -> Result<()> {
+    let repo = tx.repo;
+    let Some(record) = tx.manifest.get(&op.path) else {
+        return Ok(());
+    };
+
+    let result = linker.unlink_from_manifest(
+        &op.target,
+        record.kind,
+        &repo.path,
+        options,
+        Some(&mut tx.journal),
+    )?;
+    if output.is_text() {
+        match &result {
+            LinkResult::Skipped { reason } => {
+                println!("  {} {} ({})", "⊘".yellow(), op.path, reason.dimmed());
+            }
+            _ => println!("  {} {} (removed)", "✓".green(), op.path),
+        }
+    }
+    output.emit(&ItemRecord {
+        repo: &repo.name,
+        path: &op.path,
+        source: None,
+        target: &op.target,
+        strategy: None,
+        state: &result,
+    })?;
+
+    if matches!(result, LinkResult::Skipped { .. }) {
+        tx.skipped += 1;
+    } else {
+        tx.manifest.remove(&op.path);
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use std::fs;
+
+use crate::commands::Outcome;
+use crate::config::GlobalConfig;
+use crate::linker::Linker;
+use crate::manifest::Manifest;
+use crate::repo::{discover_repos, find_repo, Repo, RepoItem};
+use crate::sync::{self, PullOutcome};
+use crate::vars::VarResolver;
+
+/// Copy target-side edits of copied files and rendered templates back into
+/// their repo. Files changed on both sides are left alone unless `merge`.
+pub fn run(
+    config: &GlobalConfig,
+    repo_name: Option<&str>,
+    path: Option<&str>,
+    merge: bool,
+    dry_run: bool,
+) -> Result<Outcome> {
+    let linker = Linker::new(config.clone());
+
+    let repos: Vec<Repo> = if let Some(name) = repo_name {
+        match find_repo(config, name)? {
+            Some(repo) => vec![repo],
+            None => bail!("Unknown repo: {}. Run 'homie list' to see available repos.", name),
+        }
+    } else {
+        discover_repos(config)?
+    };
+
+    if repos.is_empty() {
+        println!("No repos found in ~/.homie/repos/");
+        return Ok(Outcome::Clean);
+    }
+
+    let mut matched = 0;
+    let mut held = 0;
+    let mut reported = 0;
+
+    for repo in &repos {
+        let items: Vec<RepoItem> = repo
+            .items()?
+            .into_iter()
+            .filter(|item| path.is_none_or(|p| item.matches_path(p)))
+            .collect();
+        matched += items.len();
+
+        let mut manifest = Manifest::load(&repo.path)?;
+        let var_resolver = VarResolver::new(config, Some(repo));
+        let mut lines = Vec::new();
+
+        for item in &items {
+            let outcome = sync::plan_pull(
+                item,
+                &manifest,
+                &repo.path,
+                linker.template_engine(),
+                &var_resolver,
+                merge,
+            )?;
+
+            let (content, line) = match outcome {
+                PullOutcome::Untracked | PullOutcome::Unchanged => continue,
+                PullOutcome::Conflict => {
+                    held += 1;
+                    lines.push(format!(
+                        "  {} {} ({})",
+                        "⚠".yellow(),
+                        item.relative_path,
+                        "changed in repo and target, use --merge".dimmed()
+                    ));
+                    continue;
+                }
+                PullOutcome::Pull(content) => {
+                    let line = format!("  {} {}", "←".cyan(), item.relative_path);
+                    (content, line)
+                }
+                PullOutcome::Merged { content, conflicts: 0 } => {
+                    let line = format!("  {} {} (merged)", "⇄".cyan(), item.relative_path);
+                    (content, line)
+                }
+                PullOutcome::Merged { content, conflicts } => {
+                    held += 1;
+                    let line = format!(
+                        "  {} {} ({} conflict(s), resolve the markers in the repo)",
+                        "⇄".yellow(),
+                        item.relative_path,
+                        conflicts
+                    );
+                    (content, line)
+                }
+            };
+            lines.push(line);
+
+            if dry_run {
+                continue;
+            }
+
+            // Writing in place keeps the source's permissions
+            fs::write(&item.source, &content)
+                .with_context(|| format!("Failed to write: {}", item.source.display()))?;
+
+            // The target is now the baseline for the next pull or link
+            let target = fs::read(&item.target)
+                .with_context(|| format!("Failed to read: {}", item.target.display()))?;
+            sync::write_base(&repo.path, &item.relative_path, &target)?;
+            manifest.set_hash(&item.relative_path, sync::hash_bytes(&target));
+        }
+
+        if lines.is_empty() {
+            continue;
+        }
+
+        reported += lines.len();
+        println!("{}:", repo.name.bold());
+        for line in lines {
+            println!("{}", line);
+        }
+        println!();
+
+        if !dry_run {
+            manifest.save(&repo.path)?;
+        }
+    }
+
+    if let Some(path) = path {
+        if matched == 0 {
+            bail!("No item matches {}", path);
+        }
+    }
+
+    if reported == 0 {
+        println!("Nothing to pull back.");
+    } else if dry_run {
+        println!("{}", "(dry run - no changes made)".dimmed());
+    }
+
+    Ok(if held > 0 { Outcome::Skipped } else { Outcome::Clean })
+}
+
@@ -0,0 +1,197 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, FixedOffset};
+use colored::Colorize;
+use std::path::Path;
+
+use crate::config::GlobalConfig;
+use crate::journal::{list_generations, load_generation, rollback_generation, JournalOp};
+use crate::repo::{discover_repos, find_repo, Repo};
+
+/// The run that committed a repo's generation `number`
+struct Run {
+    id: Option<String>,
+    repo: String,
+    number: u32,
+    created_at: Option<DateTime<FixedOffset>>,
+}
+
+pub fn run(
+    config: &GlobalConfig,
+    repo_name: Option<&str>,
+    to: Option<u32>,
+    list: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let repos: Vec<Repo> = if let Some(name) = repo_name {
+        match find_repo(config, name)? {
+            Some(repo) => vec![repo],
+            None => bail!("Unknown repo: {}", name),
+        }
+    } else {
+        discover_repos(config)?
+    };
+
+    if repos.is_empty() {
+        println!("No repos found.");
+        return Ok(());
+    }
+
+    if list {
+        for repo in &repos {
+            print_generations(repo, &list_generations(&repo.path)?)?;
+        }
+        return Ok(());
+    }
+
+    // Everything to undo is settled before anything is, so a missing
+    // generation can't leave some repos rolled back and others not
+    let mut to_undo: Vec<(&Repo, Vec<u32>)> = Vec::new();
+    let last_run = match (to, repo_name) {
+        (None, None) => last_run(&repos)?,
+        _ => None,
+    };
+    for repo in &repos {
+        let generations = list_generations(&repo.path)?;
+        let numbers: Vec<u32> = match to {
+            Some(n) => {
+                if n > 0 && !generations.contains(&n) {
+                    bail!("Generation {} not found for {}", n, repo.name);
+                }
+                generations.iter().rev().copied().filter(|g| *g > n).collect()
+            }
+            None => match &last_run {
+                Some(run) => run_generations(repo, &generations, run)?,
+                None if repo_name.is_some() => generations.last().copied().into_iter().collect(),
+                None => Vec::new(),
+            },
+        };
+        to_undo.push((repo, numbers));
+    }
+
+    // Undo in the reverse of link order: repos can share a file through blocks
+    for (repo, numbers) in to_undo.into_iter().rev() {
+        println!("{}:", repo.name.bold());
+
+        if numbers.is_empty() {
+            println!("  (nothing to roll back)");
+            println!();
+            continue;
+        }
+
+        for number in numbers {
+            let journal = load_generation(&repo.path, number)?;
+            println!("  {} generation {}", "↺".yellow(), number);
+            for op in journal.ops().iter().rev() {
+                print_undo(op, &repo.target);
+            }
+
+            if !dry_run {
+                rollback_generation(&repo.path, number)?;
+            }
+        }
+
+        println!();
+    }
+
+    if dry_run {
+        println!("{}", "(dry run - no changes made)".dimmed());
+    }
+
+    Ok(())
+}
+
+/// The most recent run: the one that committed the newest generation of
+/// any repo. A generation from before runs were recorded is a run of its own.
+fn last_run(repos: &[Repo]) -> Result<Option<Run>> {
+    let mut last: Option<Run> = None;
+    for repo in repos {
+        let Some(number) = list_generations(&repo.path)?.last().copied() else {
+            continue;
+        };
+        let journal = load_generation(&repo.path, number)?;
+        let created_at = journal
+            .created_at()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok());
+        if last.as_ref().is_none_or(|run| created_at > run.created_at) {
+            last = Some(Run {
+                id: journal.run().map(str::to_string),
+                repo: repo.name.clone(),
+                number,
+                created_at,
+            });
+        }
+    }
+    Ok(last)
+}
+
+/// A repo's newest generations that `run` committed, newest first
+fn run_generations(repo: &Repo, generations: &[u32], run: &Run) -> Result<Vec<u32>> {
+    let Some(id) = &run.id else {
+        let own = repo.name == run.repo;
+        return Ok(if own { vec![run.number] } else { Vec::new() });
+    };
+
+    let mut numbers = Vec::new();
+    for number in generations.iter().rev().copied() {
+        if load_generation(&repo.path, number)?.run() != Some(id.as_str()) {
+            break;
+        }
+        numbers.push(number);
+    }
+    Ok(numbers)
+}
+
+fn print_generations(repo: &Repo, generations: &[u32]) -> Result<()> {
+    println!("{}:", repo.name.bold());
+
+    if generations.is_empty() {
+        println!("  (no generations)");
+    }
+
+    for number in generations.iter().rev() {
+        let journal = load_generation(&repo.path, *number)?;
+        let created = journal
+            .ops()
+            .iter()
+            .filter(|op| matches!(op, JournalOp::Created { .. }))
+            .count();
+        let backed_up = journal
+            .ops()
+            .iter()
+            .filter(|op| matches!(op, JournalOp::BackedUp { .. }))
+            .count();
+
+        println!(
+            "  {:>3}  {}  {} created, {} backed up",
+            number,
+            journal.created_at().unwrap_or("unknown").dimmed(),
+            created,
+            backed_up
+        );
+    }
+
+    println!();
+    Ok(())
+}
+
+fn print_undo(op: &JournalOp, target: &Path) {
+    let display = |p: &Path| p.strip_prefix(target).unwrap_or(p).display().to_string();
+
+    match op {
+        JournalOp::CreatedDir { .. } => {}
+        JournalOp::Created { path, .. } => {
+            println!("    {} {}", "-".red(), display(path));
+        }
+        JournalOp::Removed { path, .. } | JournalOp::RemovedSymlink { path, .. } => {
+            println!("    {} {} (restore previous)", "+".green(), display(path));
+        }
+        JournalOp::BackedUp { path, backup } => {
+            println!(
+                "    {} {} (from {})",
+                "+".green(),
+                display(path),
+                backup.file_name().unwrap_or_default().to_string_lossy()
+            );
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::GlobalConfig;
+use crate::secret::{self, is_secret_path, Identities, ScratchDir, SECRET_EXTENSION};
+
+pub fn keygen(config: &GlobalConfig, dry_run: bool) -> Result<()> {
+    let path = config.secrets.identity_path()?;
+
+    if dry_run {
+        println!("Would create identity: {}", path.display());
+        println!("{}", "(dry run - no changes made)".dimmed());
+        return Ok(());
+    }
+
+    let public = secret::generate_identity(&path)?;
+    println!("{} Created identity: {}", "✓".green(), path.display());
+    println!("  public key: {}", public);
+    println!();
+    println!("Back this file up somewhere safe; secrets cannot be decrypted without it.");
+
+    Ok(())
+}
+
+/// Encrypt `file` to `file.age`, removing the plaintext unless `keep` is set
+pub fn encrypt(config: &GlobalConfig, file: &str, keep: bool, dry_run: bool) -> Result<()> {
+    let source = PathBuf::from(shellexpand::tilde(file).as_ref());
+
+    if is_secret_path(&source) {
+        bail!("Already encrypted: {}", source.display());
+    }
+    if !source.is_file() || source.is_symlink() {
+        bail!("Not a regular file: {}", source.display());
+    }
+
+    let dest = encrypted_path(&source);
+    if dest.exists() {
+        bail!(
+            "{} already exists. Use 'homie secret edit' to change it.",
+            dest.display()
+        );
+    }
+
+    let recipients = secret::recipients(&config.secrets)?;
+
+    println!("Encrypting {}:", file.bold());
+    println!("  {} -> {}", source.display(), dest.display());
+
+    if dry_run {
+        println!("{}", "(dry run - no changes made)".dimmed());
+        return Ok(());
+    }
+
+    let plaintext =
+        fs::read(&source).with_context(|| format!("Failed to read: {}", source.display()))?;
+    let ciphertext = secret::encrypt(&plaintext, &recipients)?;
+    fs::write(&dest, ciphertext).with_context(|| format!("Failed to write: {}", dest.display()))?;
+
+    if keep {
+        println!("  {} Encrypted", "✓".green());
+    } else {
+        fs::remove_file(&source)
+            .with_context(|| format!("Failed to remove plaintext: {}", source.display()))?;
+        println!("  {} Encrypted and removed plaintext", "✓".green());
+    }
+
+    Ok(())
+}
+
+/// Decrypt `file` to a private temp file, open it in $EDITOR, and
+/// re-encrypt it if it changed. A missing `file` starts out empty.
+pub fn edit(config: &GlobalConfig, file: &str) -> Result<()> {
+    let mut path = PathBuf::from(shellexpand::tilde(file).as_ref());
+    if !is_secret_path(&path) {
+        path = encrypted_path(&path);
+    }
+
+    let recipients = secret::recipients(&config.secrets)?;
+    let original = if path.exists() {
+        let identities = Identities::load(&config.secrets.identity_path()?)?;
+        identities.decrypt_file(&path)?
+    } else {
+        Vec::new()
+    };
+
+    let scratch = ScratchDir::create()?;
+    let plain_name = path.file_stem().context("Path has no filename")?;
+    let plain_path = scratch.path().join(plain_name);
+    secret::write_private(&plain_path, &original)?;
+
+    run_editor(&plain_path)?;
+
+    let edited = fs::read(&plain_path)
+        .with_context(|| format!("Failed to read edited file: {}", plain_path.display()))?;
+
+    if edited == original {
+        println!("{} unchanged, nothing saved", path.display());
+        return Ok(());
+    }
+
+    let ciphertext = secret::encrypt(&edited, &recipients)?;
+    fs::write(&path, ciphertext).with_context(|| format!("Failed to write: {}", path.display()))?;
+    println!("{} Saved {}", "✓".green(), path.display());
+
+    Ok(())
+}
+
+fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(SECRET_EXTENSION);
+    PathBuf::from(name)
+}
+
+fn run_editor(path: &Path) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+
+    // Through the shell so editors with arguments (e.g. "code --wait") work
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run editor: {}", editor))?;
+
+    if !status.success() {
+        bail!("Editor exited with {}, secret not saved", status);
+    }
+
+    Ok(())
+}
@@ -1,74 +1,188 @@
 use anyhow::{bail, Result};
 use colored::Colorize;
+use serde::Serialize;
 use std::collections::HashSet;
 
+use crate::collision;
+use crate::commands::Outcome;
+use crate::config::GlobalConfig;
+use crate::linker::orphan_blocker;
 use crate::manifest::Manifest;
+use crate::output::{ItemRecord, Output};
 use crate::repo::{discover_repos, find_repo, Repo};
-use crate::status::{check_item_status, ItemStatus, RepoStatus};
+use crate::status::{check_item_status, ItemStatus, RepoStatus, StatusContext};
+use crate::template::TemplateEngine;
+use crate::vars::VarResolver;
 
-pub fn run(repo_name: Option<&str>, verbose: bool) -> Result<()> {
+/// Status record for a manifest entry whose item is gone from the repo
+#[derive(Serialize)]
+struct Orphaned<'a> {
+    status: &'static str,
+    reason: &'a str,
+}
+
+/// An item's status, plus the repos whose items it shadows
+#[derive(Serialize)]
+struct Layered<'a> {
+    #[serde(flatten)]
+    status: &'a ItemStatus,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    overrides: Vec<&'a str>,
+}
+
+pub fn run(
+    config: &GlobalConfig,
+    repo_name: Option<&str>,
+    verbose: bool,
+    output: &Output,
+) -> Result<Outcome> {
     let repos: Vec<Repo> = if let Some(name) = repo_name {
-        match find_repo(name)? {
+        match find_repo(config, name)? {
             Some(repo) => vec![repo],
             None => bail!("Unknown repo: {}", name),
         }
     } else {
-        discover_repos()?
+        discover_repos(config)?
     };
 
     if repos.is_empty() {
-        println!("No repos found in ~/.homie/repos/");
-        println!("Run 'homie init <name>' to create a new repo.");
-        return Ok(());
+        if output.is_text() {
+            println!("No repos found in ~/.homie/repos/");
+            println!("Run 'homie init <name>' to create a new repo.");
+        }
+        output.finish()?;
+        return Ok(Outcome::Clean);
+    }
+
+    let mut drift = false;
+    let engine = TemplateEngine::for_config(config);
+
+    let resolution = collision::resolve(config, &repos, repo_name.is_some())?;
+    if let Err(e) = resolution.ensure_resolved(&repos) {
+        drift = true;
+        if output.is_text() {
+            eprintln!("{} {:#}\n", "⚠".yellow(), e);
+        }
     }
 
     for repo in &repos {
         let items = repo.items()?;
         let manifest = Manifest::load(&repo.path).unwrap_or_default();
+        let var_resolver = VarResolver::new(config, Some(repo));
+        let ctx = StatusContext {
+            repo_path: &repo.path,
+            engine: &engine,
+            var_resolver: &var_resolver,
+        };
         let mut status = RepoStatus::default();
         let mut seen_paths: HashSet<String> = HashSet::new();
 
         for item in &items {
             seen_paths.insert(item.relative_path.clone());
-            let item_status = check_item_status(item, &repo.path);
+            let item_status = match resolution.shadowed_by(&repo.name, &item.relative_path) {
+                Some(winner) => ItemStatus::Shadowed { by: winner.to_string() },
+                None => check_item_status(item, manifest.get(&item.relative_path), &ctx),
+            };
 
             match &item_status {
                 ItemStatus::Linked => status.linked += 1,
                 ItemStatus::Copied => status.copied += 1,
-                ItemStatus::External(_) => status.external += 1,
+                ItemStatus::External { .. } => status.external += 1,
                 ItemStatus::Missing => status.missing += 1,
                 ItemStatus::Conflict => status.conflict += 1,
                 ItemStatus::Rendered => status.rendered += 1,
+                ItemStatus::Decrypted => status.decrypted += 1,
+                ItemStatus::Merged => status.merged += 1,
+                ItemStatus::Stale => status.stale += 1,
+                ItemStatus::Modified => status.modified += 1,
+                ItemStatus::Shadowed { .. } => status.shadowed += 1,
             }
 
-            if verbose {
-                let (symbol, note) = match &item_status {
+            let overrides = resolution.overrides(&repo.name, &item.relative_path);
+            output.emit(&ItemRecord::for_item(
+                &repo.name,
+                item,
+                Layered {
+                    status: &item_status,
+                    overrides: overrides.clone(),
+                },
+            ))?;
+
+            if verbose && output.is_text() {
+                let (symbol, mut note) = match &item_status {
                     ItemStatus::Linked => ("✓".green(), String::new()),
                     ItemStatus::Copied => ("✓".blue(), " (copied)".to_string()),
-                    ItemStatus::External(path) => ("⊘".yellow(), format!(" (external: {})", path)),
+                    ItemStatus::External { points_to } => {
+                        ("⊘".yellow(), format!(" (external: {})", points_to))
+                    }
                     ItemStatus::Missing => ("?".red(), String::new()),
                     ItemStatus::Conflict => ("!".red(), String::new()),
                     ItemStatus::Rendered => ("✓".cyan(), " (rendered)".to_string()),
+                    ItemStatus::Decrypted => ("✓".cyan(), " (decrypted)".to_string()),
+                    ItemStatus::Merged => ("✓".cyan(), " (block)".to_string()),
+                    ItemStatus::Stale => ("~".yellow(), " (stale: repo changed since last link)".to_string()),
+                    ItemStatus::Modified => ("✎".yellow(), " (modified at target)".to_string()),
+                    ItemStatus::Shadowed { by } => ("↷".dimmed(), format!(" (shadowed by {})", by)),
                 };
+                if !overrides.is_empty() {
+                    note.push_str(&format!(" (overrides {})", overrides.join(", ")));
+                }
                 println!("  {} {}{}", symbol, item.relative_path, note.dimmed());
             }
         }
 
-        let mut orphaned: Vec<String> = Vec::new();
-        for (path, _entry) in manifest.iter() {
-            if !seen_paths.contains(path) {
-                let target = repo.target.join(path);
-                if !target.exists() && !target.is_symlink() {
-                    orphaned.push(path.clone());
-                }
+        // Entries whose items are gone, whether or not `link` could prune them
+        let mut orphaned: Vec<(String, String)> = Vec::new();
+        for (path, record) in manifest.iter() {
+            if seen_paths.contains(path) {
+                continue;
             }
+            let target = repo.manifest_target(path, record);
+            let reason = if !target.exists() && !target.is_symlink() {
+                "not on disk".to_string()
+            } else {
+                match orphan_blocker(&target, record, &repo.path) {
+                    Some(blocker) => format!("kept by link: {}", blocker),
+                    None => "removed from repo".to_string(),
+                }
+            };
+            output.emit(&ItemRecord {
+                repo: &repo.name,
+                path,
+                source: None,
+                target: &target,
+                strategy: None,
+                state: Orphaned {
+                    status: "orphaned",
+                    reason: &reason,
+                },
+            })?;
+            orphaned.push((path.clone(), reason));
+        }
+
+        if status.missing > 0
+            || status.conflict > 0
+            || status.stale > 0
+            || status.modified > 0
+            || !orphaned.is_empty()
+        {
+            drift = true;
         }
 
-        println!(
-            "{} ({} items):",
-            repo.name.bold(),
-            status.total()
-        );
+        if !output.is_text() {
+            continue;
+        }
+
+        match config.layer_of(&repo.name) {
+            Some(layer) => println!(
+                "{} ({} items, layer {} of {}):",
+                repo.name.bold(),
+                status.total(),
+                layer + 1,
+                config.settings.layers.len()
+            ),
+            None => println!("{} ({} items):", repo.name.bold(), status.total()),
+        }
         println!("  linked:   {}", format_count(status.linked, StatusColor::Green));
         if status.copied > 0 {
             println!("  copied:   {}", format_count(status.copied, StatusColor::Blue));
@@ -76,6 +190,12 @@ pub fn run(repo_name: Option<&str>, verbose: bool) -> Result<()> {
         if status.rendered > 0 {
             println!("  rendered: {}", format_count(status.rendered, StatusColor::Cyan));
         }
+        if status.decrypted > 0 {
+            println!("  secrets:  {}", format_count(status.decrypted, StatusColor::Cyan));
+        }
+        if status.merged > 0 {
+            println!("  blocks:   {}", format_count(status.merged, StatusColor::Cyan));
+        }
         if status.external > 0 {
             println!(
                 "  external: {}  (preserved, pointing outside repos)",
@@ -94,13 +214,33 @@ pub fn run(repo_name: Option<&str>, verbose: bool) -> Result<()> {
                 format_count(status.conflict, StatusColor::Red)
             );
         }
-        for path in &orphaned {
-            println!("  {} {} in manifest but not on disk", "⚠".yellow(), path);
+        if status.stale > 0 {
+            println!(
+                "  stale:    {}  (repo changed since last link)",
+                format_count(status.stale, StatusColor::Yellow)
+            );
+        }
+        if status.modified > 0 {
+            println!(
+                "  modified: {}  (edited at target since last link)",
+                format_count(status.modified, StatusColor::Yellow)
+            );
+        }
+        if status.shadowed > 0 {
+            println!(
+                "  shadowed: {}  (linked by a higher-priority repo or layer)",
+                format_count(status.shadowed, StatusColor::Yellow)
+            );
+        }
+        for (path, reason) in &orphaned {
+            println!("  {} {} orphaned ({})", "⚠".yellow(), path, reason.dimmed());
         }
         println!();
     }
 
-    Ok(())
+    output.finish()?;
+
+    Ok(if drift { Outcome::Drift } else { Outcome::Clean })
 }
 
 enum StatusColor {
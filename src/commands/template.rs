@@ -0,0 +1,227 @@
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::check::{self, Reference};
+use crate::config::GlobalConfig;
+use crate::repo::{discover_repos, find_repo, Repo};
+use crate::template::TemplateEngine;
+use crate::vars::{merge_vars, parse_override, VarResolver};
+
+/// Check every template against this machine's vars. Parse errors and
+/// undefined vars fail the check; unused `[vars]` are only warned about.
+pub fn check(config: &GlobalConfig, repo_name: Option<&str>) -> Result<()> {
+    let repos = if let Some(name) = repo_name {
+        match find_repo(config, name)? {
+            Some(repo) => vec![repo],
+            None => bail!("Unknown repo: {}", name),
+        }
+    } else {
+        discover_repos(config)?
+    };
+
+    if repos.is_empty() {
+        println!("No repos found.");
+        return Ok(());
+    }
+
+    let helpers = TemplateEngine::new().helpers().to_vec();
+    let mut problems = 0;
+    let mut used_anywhere = BTreeSet::new();
+
+    for repo in &repos {
+        println!("{}:", repo.name.bold());
+
+        let vars = VarResolver::new(config, Some(repo)).to_template_data().vars;
+        let mut used = BTreeSet::new();
+
+        for source in templates(repo)? {
+            let path = display_path(repo, &source);
+            let references = match read_references(&source, &helpers)? {
+                Ok(references) => references,
+                Err(problem) => {
+                    println!("  {} {}:{}: {}", "✗".red(), path, problem.line, problem.message);
+                    problems += 1;
+                    continue;
+                }
+            };
+            used.extend(references.iter().map(top_level));
+
+            let undefined = check::undefined(&references, &vars);
+            if undefined.is_empty() {
+                println!("  {} {} {}", "✓".green(), path, names(&references).dimmed());
+            }
+            for reference in &undefined {
+                println!(
+                    "  {} {}:{}: undefined variable `{}`",
+                    "✗".red(),
+                    path,
+                    reference.line,
+                    reference.path
+                );
+            }
+            problems += undefined.len();
+        }
+
+        // Partials are rendered in their caller's context, so only what
+        // they use is known here
+        for source in partials(repo) {
+            match read_references(&source, &helpers)? {
+                Ok(references) => used.extend(references.iter().map(top_level)),
+                Err(problem) => {
+                    let path = display_path(repo, &source);
+                    println!("  {} {}:{}: {}", "✗".red(), path, problem.line, problem.message);
+                    problems += 1;
+                }
+            }
+        }
+
+        let mut unused: Vec<&String> = repo.config.vars.keys().filter(|k| !used.contains(*k)).collect();
+        unused.sort();
+        for name in unused {
+            println!("  {} unused var `{}` ([vars] in homie.toml)", "⚠".yellow(), name);
+        }
+
+        used_anywhere.extend(used);
+        println!();
+    }
+
+    // Whether a global var is used can only be told from every repo
+    if repo_name.is_none() {
+        let mut unused: Vec<&String> = config
+            .vars
+            .keys()
+            .filter(|k| !used_anywhere.contains(*k))
+            .collect();
+        unused.sort();
+        for name in unused {
+            println!("{} unused global var `{}`", "⚠".yellow(), name);
+        }
+    }
+
+    if problems > 0 {
+        bail!("{} problem(s) found in templates", problems);
+    }
+    println!("{} All templates are valid", "✓".green());
+    Ok(())
+}
+
+/// Print `path` in `repo` as `link` would render it, with `overrides`
+/// (`KEY=VALUE`), `host` and `os` standing in for this machine's vars
+pub fn render(
+    config: &GlobalConfig,
+    repo_name: &str,
+    path: &str,
+    overrides: &[String],
+    host: Option<String>,
+    os: Option<String>,
+) -> Result<()> {
+    let mut config = config.clone();
+    if let Some(host) = host {
+        config.overrides.insert("hostname".to_string(), host.into());
+    }
+    if let Some(os) = os {
+        config.overrides.insert("os".to_string(), os.into());
+    }
+    for arg in overrides {
+        merge_vars(&mut config.overrides, &parse_override(arg)?);
+    }
+
+    // Loaded after the overrides are set, so [[when]] blocks see them too
+    let Some(repo) = find_repo(&config, repo_name)? else {
+        bail!("Unknown repo: {}", repo_name);
+    };
+    let source = template_source(&repo, path)?;
+
+    let engine = TemplateEngine::for_config(&config);
+
+    let data = VarResolver::new(&config, Some(&repo)).to_template_data();
+    let rendered = engine
+        .render_file(&source, &data)
+        .with_context(|| format!("Failed to render {}", display_path(&repo, &source)))?;
+    print!("{}", rendered.content);
+    Ok(())
+}
+
+/// The template item `path` selects, or a `.tmpl` file in the repo that is
+/// not linked (a script)
+fn template_source(repo: &Repo, path: &str) -> Result<PathBuf> {
+    if let Some(item) = repo.items()?.into_iter().find(|item| item.matches_path(path)) {
+        if !item.is_template {
+            bail!("Not a template: {}", item.relative_path);
+        }
+        return Ok(item.source);
+    }
+
+    let source = repo.path.join(path);
+    if !source.is_file() || source.extension().is_none_or(|e| e != "tmpl") {
+        bail!("No template {} in {}", path, repo.name);
+    }
+    Ok(source)
+}
+
+/// Template items (the repo's and its imports') and `.tmpl` scripts
+fn templates(repo: &Repo) -> Result<Vec<PathBuf>> {
+    let mut templates: Vec<PathBuf> = repo
+        .items()?
+        .into_iter()
+        .filter(|item| item.is_template)
+        .map(|item| item.source)
+        .collect();
+
+    templates.extend(
+        repo.config
+            .scripts
+            .iter()
+            .filter(|script| script.path.ends_with(".tmpl"))
+            .map(|script| repo.path.join(&script.path)),
+    );
+    Ok(templates)
+}
+
+fn partials(repo: &Repo) -> Vec<PathBuf> {
+    repo.partial_dirs()
+        .iter()
+        .flat_map(|dir| WalkDir::new(&dir.path).sort_by_file_name())
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+fn read_references(
+    source: &Path,
+    helpers: &[&str],
+) -> Result<Result<Vec<Reference>, check::Problem>> {
+    let content = fs::read_to_string(source)
+        .with_context(|| format!("Failed to read template: {}", source.display()))?;
+    Ok(check::references(&content, helpers))
+}
+
+/// The var a reference reads from: `git` for `git.email`
+fn top_level(reference: &Reference) -> String {
+    reference.path.split('.').next().unwrap_or_default().to_string()
+}
+
+/// The distinct vars a template reads, in order of first use
+fn names(references: &[Reference]) -> String {
+    let mut seen = BTreeSet::new();
+    references
+        .iter()
+        .filter(|r| seen.insert(r.path.as_str()))
+        .map(|r| r.path.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Repo-relative if the file is inside the repo
+fn display_path(repo: &Repo, source: &Path) -> String {
+    source
+        .strip_prefix(&repo.path)
+        .unwrap_or(source)
+        .display()
+        .to_string()
+}
@@ -1,7 +1,8 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use colored::Colorize;
 
 use crate::config::GlobalConfig;
+use crate::hooks::HookRunner;
 use crate::linker::{LinkOptions, LinkResult, Linker};
 use crate::manifest::Manifest;
 use crate::repo::{discover_repos, find_repo, Repo};
@@ -14,12 +15,12 @@ pub fn run(
     let linker = Linker::new(config.clone());
 
     let repos: Vec<Repo> = if let Some(name) = repo_name {
-        match find_repo(name)? {
+        match find_repo(config, name)? {
             Some(repo) => vec![repo],
             None => bail!("Unknown repo: {}", name),
         }
     } else {
-        discover_repos()?
+        discover_repos(config)?
     };
 
     if repos.is_empty() {
@@ -27,10 +28,20 @@ pub fn run(
         return Ok(());
     }
 
+    let mut failed_hooks = 0;
+
     for repo in &repos {
         println!("{}:", repo.name.bold());
 
+        let hooks = HookRunner::new(repo, options.dry_run, false);
+        if let Some(command) = &repo.config.hooks.pre_unlink {
+            hooks
+                .run("pre_unlink", command, &[])
+                .with_context(|| format!("{}: unlink aborted", repo.name))?;
+        }
+
         let manifest = Manifest::load(&repo.path).unwrap_or_default();
+        let mut unlinked = Vec::new();
 
         if manifest.is_empty() {
             let items = repo.items()?;
@@ -40,13 +51,19 @@ pub fn run(
             }
 
             for item in &items {
-                let result = linker.unlink_item(item, options);
+                let result = linker.unlink_item(item, &repo.path, options);
+                if matches!(result, Ok(LinkResult::Unlinked)) {
+                    unlinked.push(item.relative_path.clone());
+                }
                 print_unlink_result(&item.relative_path, result, options.verbose);
             }
         } else {
-            for (path, entry) in manifest.iter() {
-                let target = repo.target.join(path);
-                let result = linker.unlink_from_manifest(&target, *entry, options);
+            for (path, record) in manifest.iter() {
+                let target = repo.manifest_target(path, record);
+                let result = linker.unlink_from_manifest(&target, record.kind, &repo.path, options, None);
+                if matches!(result, Ok(LinkResult::Unlinked)) {
+                    unlinked.push(path.clone());
+                }
                 print_unlink_result(path, result, options.verbose);
             }
 
@@ -55,6 +72,13 @@ pub fn run(
             }
         }
 
+        if let Some(command) = &repo.config.hooks.post_unlink {
+            if let Err(e) = hooks.run("post_unlink", command, &unlinked) {
+                println!("  {} {:#}", "✗".red(), e);
+                failed_hooks += 1;
+            }
+        }
+
         println!();
     }
 
@@ -62,6 +86,10 @@ pub fn run(
         println!("{}", "(dry run - no changes made)".dimmed());
     }
 
+    if failed_hooks > 0 {
+        bail!("{} post-unlink hook(s) failed", failed_hooks);
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,440 @@
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use colored::Colorize;
+use notify::{RecursiveMode, Watcher};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::collision;
+use crate::commands::link::{commit, prune_orphans, recover_interrupted, Transaction};
+use crate::config::GlobalConfig;
+use crate::journal::{new_run_id, Journal};
+use crate::linker::{print_result, LinkOptions, LinkResult, Linker};
+use crate::manifest::Manifest;
+use crate::output::{Output, OutputFormat};
+use crate::repo::{discover_repos, find_repo, Repo, RepoItem};
+use crate::strategy::SyncPolicy;
+use crate::sync::{self, PullOutcome};
+use crate::template::PARTIALS_DIR;
+use crate::vars::VarResolver;
+
+/// Quiet period after the last event before a batch is linked
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+pub fn run(config: &GlobalConfig, repo_name: Option<&str>, options: LinkOptions) -> Result<()> {
+    let linker = Linker::new(config.clone());
+
+    let mut repos: Vec<Repo> = if let Some(name) = repo_name {
+        match find_repo(config, name)? {
+            Some(repo) => vec![repo],
+            None => bail!("Unknown repo: {}. Run 'homie list' to see available repos.", name),
+        }
+    } else {
+        discover_repos(config)?
+    };
+
+    if repos.is_empty() {
+        println!("No repos found in ~/.homie/repos/");
+        return Ok(());
+    }
+
+    // A batch or link run killed mid-way leaves its journal behind
+    if !options.dry_run {
+        for repo in &repos {
+            if Journal::load_pending(&repo.path)?.is_some() {
+                println!("{}:", repo.name.bold());
+                recover_interrupted(repo, &Output::new(OutputFormat::Text))?;
+                println!();
+            }
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Failed to start file watcher")?;
+
+    for repo in &repos {
+        for path in watched_paths(repo) {
+            watcher
+                .watch(&path, RecursiveMode::Recursive)
+                .with_context(|| format!("Failed to watch {}", path.display()))?;
+        }
+    }
+
+    let names: Vec<&str> = repos.iter().map(|r| r.name.as_str()).collect();
+    println!("Watching {} (Ctrl-C to stop)", names.join(", ").bold());
+    if options.dry_run {
+        println!("{}", "(dry run - no changes will be made)".dimmed());
+    }
+
+    loop {
+        let mut changed = BTreeSet::new();
+        let first = rx.recv().context("File watcher stopped")?;
+        collect_paths(first, &mut changed);
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            collect_paths(event, &mut changed);
+        }
+
+        for repo in repos.iter_mut() {
+            let repo_changed: Vec<PathBuf> = changed
+                .iter()
+                .filter(|path| is_repo_path(repo, path))
+                .cloned()
+                .collect();
+
+            if repo_changed.is_empty() {
+                continue;
+            }
+
+            let reload = repo_changed.contains(&repo.path.join("homie.toml"));
+            if reload {
+                match Repo::from_path(&repo.path, config) {
+                    Ok(reloaded) => *repo = reloaded,
+                    Err(e) => {
+                        println!("{} {}: {:#}", "✗".red(), repo.name, e);
+                        continue;
+                    }
+                }
+            }
+
+            if let Err(e) = sync_repo(&linker, config, repo, &repo_changed, reload, options) {
+                println!("  {} {:#}", "✗".red(), e);
+                println!();
+            }
+        }
+    }
+}
+
+/// Link the items affected by `changed` and remove targets of deleted ones.
+/// Collisions and orphans are settled the way `link` settles them.
+fn sync_repo(
+    linker: &Linker,
+    config: &GlobalConfig,
+    repo: &Repo,
+    changed: &[PathBuf],
+    relink_all: bool,
+    options: LinkOptions,
+) -> Result<()> {
+    let resolution = collision::resolve(config, slice::from_ref(repo), true)?;
+    resolution.ensure_resolved(slice::from_ref(repo))?;
+    let (items, shadowed) = resolution.partition(&repo.name, repo.items()?);
+    let mut tx = Transaction::new(repo)?;
+
+    let affected: Vec<&RepoItem> = if relink_all {
+        items.iter().collect()
+    } else {
+        affected_items(&items, changed)
+    };
+    let current: HashSet<&str> = items
+        .iter()
+        .chain(shadowed.iter().map(|(item, _)| item))
+        .map(|item| item.relative_path.as_str())
+        .collect();
+    let removed = removed_entries(&current, &tx.previous);
+
+    if affected.is_empty() && (removed.is_empty() || options.no_prune) {
+        return Ok(());
+    }
+
+    // The engine keeps each repo's partials; this batch may have edited them
+    linker.template_engine().forget_partials();
+
+    println!(
+        "{} {}:",
+        Local::now().format("%H:%M:%S").to_string().dimmed(),
+        repo.name.bold()
+    );
+
+    // Items this batch does not touch keep their entries
+    for item in &items {
+        if let Some(record) = tx.previous.get(&item.relative_path) {
+            tx.manifest.insert(item.relative_path.clone(), record.clone());
+        }
+    }
+
+    let var_resolver = VarResolver::new(config, Some(repo));
+    if let Err(e) = link_affected(linker, &mut tx, &affected, &current, &var_resolver, options) {
+        let undone = tx.journal.rollback()?;
+        return Err(e.context(format!("batch rolled back ({} changes undone)", undone)));
+    }
+
+    // A batch that changed nothing records no generation, and batches never
+    // prune: that is left to `link`, so its history outlives an editing session
+    commit(tx, &new_run_id(), options, 0)?;
+    println!();
+    Ok(())
+}
+
+/// Link `affected` into the transaction, then prune orphans into the same
+/// journal so the batch commits or rolls back as a whole
+fn link_affected(
+    linker: &Linker,
+    tx: &mut Transaction,
+    affected: &[&RepoItem],
+    current: &HashSet<&str>,
+    var_resolver: &VarResolver,
+    options: LinkOptions,
+) -> Result<()> {
+    let repo = tx.repo;
+
+    for item in affected {
+        if item.sync == SyncPolicy::Pull && has_target_edits(linker, repo, item, &tx.previous, var_resolver)? {
+            let result = LinkResult::Skipped {
+                reason: "target was edited (run 'homie pull-back')".to_string(),
+            };
+            print_result(&item.relative_path, &result, options.verbose);
+            continue;
+        }
+
+        let result = linker
+            .link_item(item, var_resolver, &repo.path, options, &mut tx.journal)
+            .with_context(|| format!("failed to link {}", item.relative_path))?;
+
+        print_result(&item.relative_path, &result, options.verbose);
+
+        match result {
+            LinkResult::Created { entry }
+            | LinkResult::AlreadyCorrect { entry }
+            | LinkResult::BackedUp { entry, .. } => {
+                tx.manifest
+                    .insert(item.relative_path.clone(), repo.record_for(item, entry)?);
+            }
+            LinkResult::Skipped { .. } | LinkResult::Unlinked => {}
+        }
+    }
+
+    prune_orphans(linker, tx, current, options, &Output::new(OutputFormat::Text))
+}
+
+/// Whether relinking `item` would overwrite edits made at the target
+fn has_target_edits(
+    linker: &Linker,
+    repo: &Repo,
+    item: &RepoItem,
+    manifest: &Manifest,
+    var_resolver: &VarResolver,
+) -> Result<bool> {
+    let outcome = sync::plan_pull(
+        item,
+        manifest,
+        &repo.path,
+        linker.template_engine(),
+        var_resolver,
+        false,
+    )?;
+    Ok(!matches!(outcome, PullOutcome::Untracked | PullOutcome::Unchanged))
+}
+
+/// The repo directory plus every local or fetched import
+fn watched_paths(repo: &Repo) -> Vec<PathBuf> {
+    let mut paths = vec![repo.path.clone()];
+    for import in &repo.imports {
+        let path = import.source_path();
+        if path.exists() && !path.starts_with(&repo.path) {
+            paths.push(path.to_path_buf());
+        }
+    }
+    paths
+}
+
+fn collect_paths(event: notify::Result<notify::Event>, changed: &mut BTreeSet<PathBuf>) {
+    match event {
+        // Reads (including our own copies and renders) are not changes
+        Ok(event) if event.kind.is_access() => {}
+        Ok(event) => changed.extend(event.paths),
+        Err(e) => eprintln!("{} watch error: {}", "⚠".yellow(), e),
+    }
+}
+
+/// Whether a changed path is a source for `repo` (homie's own state is not)
+fn is_repo_path(repo: &Repo, path: &Path) -> bool {
+    if repo
+        .imports
+        .iter()
+        .any(|import| path.starts_with(import.source_path()))
+    {
+        return true;
+    }
+
+    path.starts_with(&repo.path)
+        && !path.starts_with(repo.path.join(".homie"))
+        && !path.starts_with(repo.path.join(".git"))
+}
+
+/// Items whose source is a changed path, or contains one (directory units).
+/// A changed file that is no item's source may be a partial or an `include`d
+/// file, and any template may use it, so it affects them all.
+fn affected_items<'a>(items: &'a [RepoItem], changed: &[PathBuf]) -> Vec<&'a RepoItem> {
+    let is_source = |path: &PathBuf| items.iter().any(|item| path.starts_with(&item.source));
+    let shared_changed = changed.iter().any(|path| {
+        path.components().any(|c| c.as_os_str() == PARTIALS_DIR) || !is_source(path)
+    });
+
+    items
+        .iter()
+        .filter(|item| {
+            (item.is_template && shared_changed)
+                || changed.iter().any(|path| path.starts_with(&item.source))
+        })
+        .collect()
+}
+
+/// Manifest entries whose items no longer exist in the repo
+fn removed_entries(current: &HashSet<&str>, manifest: &Manifest) -> Vec<String> {
+    manifest
+        .iter()
+        .filter(|(path, _)| !current.contains(path.as_str()))
+        .map(|(path, _)| path.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{generations_dir, list_generations};
+    use crate::manifest::{ManifestEntry, ManifestRecord};
+    use crate::strategy::{Strategy, SyncPolicy};
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    fn item(relative: &str, strategy: Strategy) -> RepoItem {
+        RepoItem {
+            source: PathBuf::from("/repo").join(relative),
+            target: PathBuf::from("/home/user").join(relative),
+            relative_path: relative.to_string(),
+            is_template: relative.ends_with(".tmpl"),
+            is_secret: false,
+            strategy,
+            sync: SyncPolicy::Overwrite,
+        }
+    }
+
+    #[test]
+    fn test_affected_items() {
+        let items = vec![
+            item(".zshrc", Strategy::File),
+            item(".gitconfig.tmpl", Strategy::File),
+            item(".config/app", Strategy::Copy),
+        ];
+        let changed = vec![
+            PathBuf::from("/repo/.gitconfig.tmpl"),
+            PathBuf::from("/repo/.config/app/settings.json"),
+            PathBuf::from("/repo/.zshrc.swp"),
+        ];
+
+        let affected: Vec<&str> = affected_items(&items, &changed)
+            .iter()
+            .map(|i| i.relative_path.as_str())
+            .collect();
+        assert_eq!(affected, vec![".gitconfig.tmpl", ".config/app"]);
+    }
+
+    #[test]
+    fn test_changed_partial_or_include_affects_templates() {
+        let items = vec![
+            item(".zshrc", Strategy::File),
+            item(".bashrc.tmpl", Strategy::File),
+            item(".gitconfig.tmpl", Strategy::File),
+        ];
+        let changed = vec![PathBuf::from("/repo/_partials/aliases.sh")];
+
+        let affected: Vec<&str> = affected_items(&items, &changed)
+            .iter()
+            .map(|i| i.relative_path.as_str())
+            .collect();
+        assert_eq!(affected, vec![".bashrc.tmpl", ".gitconfig.tmpl"]);
+
+        // A file that is no item, such as one pulled in with `include`
+        let changed = vec![PathBuf::from("/repo/includes/aliases.sh")];
+        assert_eq!(affected_items(&items, &changed).len(), 2);
+    }
+
+    #[test]
+    fn test_removed_entries() {
+        let current = HashSet::from([".zshrc"]);
+        let mut manifest = Manifest::default();
+        manifest.insert(".zshrc".to_string(), ManifestRecord::new(ManifestEntry::Symlink));
+        manifest.insert(".bashrc".to_string(), ManifestRecord::new(ManifestEntry::Symlink));
+
+        assert_eq!(removed_entries(&current, &manifest), vec![".bashrc".to_string()]);
+    }
+
+    #[test]
+    fn test_batch_prunes_orphans_like_link() {
+        let temp = TempDir::new().unwrap();
+        let home = temp.path().join("home");
+        let repo_path = temp.path().join("dotfiles");
+        fs::create_dir_all(&home).unwrap();
+        fs::create_dir_all(&repo_path).unwrap();
+        fs::write(
+            repo_path.join("homie.toml"),
+            format!("target = \"{}\"", home.display()),
+        )
+        .unwrap();
+
+        // Two items deleted from the repo: a symlink, and a copy edited since
+        symlink(repo_path.join(".bashrc"), home.join(".bashrc")).unwrap();
+        fs::write(home.join(".profile"), "edited").unwrap();
+        let mut manifest = Manifest::default();
+        manifest.insert(".bashrc".to_string(), ManifestRecord::new(ManifestEntry::Symlink));
+        let mut copy = ManifestRecord::new(ManifestEntry::Copy);
+        copy.sha256 = Some(sync::hash_bytes(b"linked"));
+        manifest.insert(".profile".to_string(), copy);
+        manifest.save(&repo_path).unwrap();
+
+        let config = GlobalConfig::default();
+        let repo = Repo::from_path(&repo_path, &config).unwrap();
+        let linker = Linker::new(config.clone());
+        let var_resolver = VarResolver::new(&config, Some(&repo));
+        let mut tx = Transaction::new(&repo).unwrap();
+
+        link_affected(&linker, &mut tx, &[], &HashSet::new(), &var_resolver, LinkOptions::default())
+            .unwrap();
+
+        assert!(!home.join(".bashrc").is_symlink());
+        assert_eq!(fs::read_to_string(home.join(".profile")).unwrap(), "edited");
+        assert!(tx.manifest.get(".profile").is_some());
+        assert!(tx.manifest.get(".bashrc").is_none());
+
+        // The removal is part of the batch's journal
+        tx.journal.rollback().unwrap();
+        assert!(home.join(".bashrc").is_symlink());
+    }
+
+    #[test]
+    fn test_batches_keep_link_history() {
+        let temp = TempDir::new().unwrap();
+        let home = temp.path().join("home");
+        let repo_path = temp.path().join("dotfiles");
+        fs::create_dir_all(&home).unwrap();
+        fs::create_dir_all(&repo_path).unwrap();
+        fs::write(
+            repo_path.join("homie.toml"),
+            format!("target = \"{}\"", home.display()),
+        )
+        .unwrap();
+        fs::write(repo_path.join(".zshrc"), "export EDITOR=vi\n").unwrap();
+
+        // Two generations left by `link` runs
+        for number in ["1", "2"] {
+            fs::create_dir_all(generations_dir(&repo_path).join(number)).unwrap();
+        }
+
+        let mut config = GlobalConfig::default();
+        config.settings.keep_generations = 1;
+        let repo = Repo::from_path(&repo_path, &config).unwrap();
+        let linker = Linker::new(config.clone());
+        let changed = vec![repo_path.join(".zshrc")];
+
+        sync_repo(&linker, &config, &repo, &changed, false, LinkOptions::default()).unwrap();
+        assert!(home.join(".zshrc").is_symlink());
+        assert_eq!(list_generations(&repo_path).unwrap(), vec![1, 2, 3]);
+
+        // Nothing to change: no generation
+        sync_repo(&linker, &config, &repo, &changed, false, LinkOptions::default()).unwrap();
+        assert_eq!(list_generations(&repo_path).unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -1,10 +1,13 @@
 use anyhow::{bail, Context, Result};
 use serde::Deserialize;
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use crate::strategy::Strategy;
+use crate::scripts::ScriptConfig;
+use crate::strategy::{Strategy, StrategyEntry, SyncPolicy};
+use crate::vars::{as_text, lookup, merge_vars, Vars};
 
 /// Global config at ~/.config/homie/config.toml
 /// Optional - only for shared settings, vars, and env passthrough
@@ -13,9 +16,24 @@ pub struct GlobalConfig {
     #[serde(default)]
     pub settings: Settings,
     #[serde(default)]
-    pub vars: HashMap<String, String>,
+    pub vars: Vars,
     #[serde(default)]
     pub env: EnvConfig,
+    /// Named machine profiles selecting which repos are active
+    #[serde(default)]
+    pub profiles: HashMap<String, ProfileConfig>,
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+    /// Per-repo priorities overriding the `priority` in each repo's homie.toml
+    #[serde(default)]
+    pub priority: HashMap<String, i32>,
+    /// Profile selected for this run (set by `activate_profile`)
+    #[serde(skip)]
+    pub active_profile: Option<String>,
+    /// Vars given on the command line (`template render --var`), over every
+    /// other layer
+    #[serde(skip)]
+    pub overrides: Vars,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -24,6 +42,16 @@ pub struct Settings {
     pub backup_suffix: String,
     #[serde(default)]
     pub replaceable_paths: Vec<String>,
+    /// Repo names from lowest to highest layer; a higher layer wins target
+    /// collisions with lower layers of the same priority
+    #[serde(default)]
+    pub layers: Vec<String>,
+    /// Fail on undefined template variables instead of rendering them empty
+    #[serde(default = "default_strict_templates")]
+    pub strict_templates: bool,
+    /// How many generations each repo keeps for `rollback` (0 keeps all)
+    #[serde(default = "default_keep_generations")]
+    pub keep_generations: usize,
 }
 
 impl Default for Settings {
@@ -31,6 +59,9 @@ impl Default for Settings {
         Self {
             backup_suffix: default_backup_suffix(),
             replaceable_paths: Vec::new(),
+            layers: Vec::new(),
+            strict_templates: default_strict_templates(),
+            keep_generations: default_keep_generations(),
         }
     }
 }
@@ -39,12 +70,56 @@ fn default_backup_suffix() -> String {
     ".backup.%Y%m%d%H%M%S".to_string()
 }
 
+fn default_strict_templates() -> bool {
+    true
+}
+
+fn default_keep_generations() -> usize {
+    20
+}
+
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct EnvConfig {
     #[serde(default)]
     pub pass_through: Vec<String>,
 }
 
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ProfileConfig {
+    /// Repos linked under this profile (names or glob patterns)
+    #[serde(default)]
+    pub repos: Vec<String>,
+    /// Vars overriding global vars while this profile is active
+    #[serde(default)]
+    pub vars: Vars,
+    /// Hostname globs that select this profile automatically
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct SecretsConfig {
+    /// age identity file used to decrypt `.age` sources
+    /// (default: ~/.config/homie/identity.txt)
+    pub identity: Option<String>,
+    /// Extra age public keys that `homie secret` encrypts to
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// Command printing the value for `{{secret "key"}}` in templates, e.g.
+    /// "pass show" or "op read op://Private/{key}". The key replaces `{key}`,
+    /// or is appended as the last argument.
+    pub provider: Option<String>,
+}
+
+impl SecretsConfig {
+    pub fn identity_path(&self) -> Result<PathBuf> {
+        match &self.identity {
+            Some(path) => Ok(PathBuf::from(shellexpand::tilde(path).as_ref())),
+            None => Ok(GlobalConfig::config_path()?.with_file_name("identity.txt")),
+        }
+    }
+}
+
 /// Per-repo config at <repo>/homie.toml
 #[derive(Debug, Deserialize)]
 pub struct RepoConfig {
@@ -52,16 +127,28 @@ pub struct RepoConfig {
     pub target: String,
     /// Optional: repo-specific variables
     #[serde(default)]
-    pub vars: HashMap<String, String>,
+    pub vars: Vars,
+    /// Optional: wins target collisions with repos of a lower priority
+    #[serde(default)]
+    pub priority: i32,
     #[serde(default)]
     pub defaults: RepoDefaults,
     #[serde(default)]
-    pub strategies: HashMap<String, Strategy>,
+    pub strategies: HashMap<String, StrategyEntry>,
     #[serde(default)]
     pub ignore: IgnoreConfig,
     /// Optional: external imports (local paths or git repos)
     #[serde(default)]
     pub imports: Vec<ImportConfig>,
+    /// Optional: sections that only apply on matching machines
+    #[serde(default)]
+    pub when: Vec<WhenConfig>,
+    /// Optional: commands run around link and unlink
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    /// Optional: bootstrap scripts run by link (never linked themselves)
+    #[serde(default)]
+    pub scripts: Vec<ScriptConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -84,6 +171,80 @@ pub struct IgnoreConfig {
     pub paths: Vec<String>,
 }
 
+/// Shell commands run from the repo directory. A failing `pre_*` hook aborts.
+#[derive(Debug, Deserialize, Default)]
+pub struct HooksConfig {
+    pub pre_link: Option<String>,
+    pub post_link: Option<String>,
+    pub pre_unlink: Option<String>,
+    pub post_unlink: Option<String>,
+    /// Run after link when items under a path (or matching a glob) were
+    /// created or backed up
+    #[serde(default)]
+    pub paths: BTreeMap<String, String>,
+}
+
+impl HooksConfig {
+    /// Path hooks whose pattern matches any of `changed`, with the matching paths
+    pub fn path_hooks_for<'a>(&'a self, changed: &[String]) -> Vec<(&'a str, &'a str, Vec<String>)> {
+        self.paths
+            .iter()
+            .filter_map(|(pattern, command)| {
+                let matched: Vec<String> = changed
+                    .iter()
+                    .filter(|path| path_matches(pattern, path))
+                    .cloned()
+                    .collect();
+                (!matched.is_empty()).then_some((pattern.as_str(), command.as_str(), matched))
+            })
+            .collect()
+    }
+}
+
+/// A `[[when]]` block. Every condition given must match (values are globs)
+/// for its ignores, strategies and vars to be merged into the repo config.
+#[derive(Debug, Deserialize, Default)]
+pub struct WhenConfig {
+    pub os: Option<String>,
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    /// Conditions on any other resolved var, e.g. `match = { role = "server" }`
+    #[serde(default, rename = "match")]
+    pub matches: HashMap<String, String>,
+    #[serde(default)]
+    pub ignore: IgnoreConfig,
+    #[serde(default)]
+    pub strategies: HashMap<String, StrategyEntry>,
+    #[serde(default)]
+    pub vars: Vars,
+}
+
+impl WhenConfig {
+    pub fn is_active(&self, vars: &Value) -> bool {
+        let builtins = [
+            ("os", &self.os),
+            ("hostname", &self.hostname),
+            ("user", &self.user),
+        ];
+
+        let conditions = builtins
+            .into_iter()
+            .filter_map(|(name, pattern)| pattern.as_ref().map(|p| (name, p)))
+            .chain(self.matches.iter().map(|(k, v)| (k.as_str(), v)));
+
+        for (name, pattern) in conditions {
+            let Some(value) = lookup(vars, name).and_then(as_text) else {
+                return false;
+            };
+            if !glob_match(pattern, &value) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct PathRemap {
     pub from: String,
@@ -120,6 +281,10 @@ const DEFAULT_IGNORES: &[&str] = &[
     "LICENSE",
     "LICENSE.md",
     ".gitignore",
+    "_partials",
+    "_partials/**",
+    "_includes",
+    "_includes/**",
 ];
 
 impl GlobalConfig {
@@ -145,6 +310,67 @@ impl GlobalConfig {
         Ok(config_dir.join("config.toml"))
     }
 
+    /// Select a profile by name, or by matching `hostname` against each
+    /// profile's `hosts` when no name is given. Merges the profile's vars.
+    pub fn activate_profile(&mut self, requested: Option<&str>, hostname: &str) -> Result<()> {
+        let name = match requested {
+            Some(name) => {
+                if !self.profiles.contains_key(name) {
+                    bail!("Unknown profile: {}", name);
+                }
+                name.to_string()
+            }
+            None => {
+                let mut names: Vec<&String> = self.profiles.keys().collect();
+                names.sort();
+
+                let matched = names.into_iter().find(|name| {
+                    self.profiles[*name]
+                        .hosts
+                        .iter()
+                        .any(|pattern| glob_match(pattern, hostname))
+                });
+
+                match matched {
+                    Some(name) => name.clone(),
+                    None => return Ok(()),
+                }
+            }
+        };
+
+        let profile = &self.profiles[&name];
+        merge_vars(&mut self.vars, &profile.vars);
+        self.active_profile = Some(name);
+
+        Ok(())
+    }
+
+    /// Whether a repo should be linked under the active profile (all are without one)
+    pub fn is_repo_active(&self, repo_name: &str) -> bool {
+        let Some(profile) = self.active_profile.as_ref().and_then(|p| self.profiles.get(p)) else {
+            return true;
+        };
+
+        profile
+            .repos
+            .iter()
+            .any(|pattern| glob_match(pattern, repo_name))
+    }
+
+    /// The priority of a repo: the global `[priority]` entry if set,
+    /// otherwise the repo's own `priority`
+    pub fn repo_priority(&self, repo_name: &str, repo_config: &RepoConfig) -> i32 {
+        self.priority
+            .get(repo_name)
+            .copied()
+            .unwrap_or(repo_config.priority)
+    }
+
+    /// Position of a repo in `settings.layers` (0 is the lowest layer)
+    pub fn layer_of(&self, repo_name: &str) -> Option<usize> {
+        self.settings.layers.iter().position(|name| name == repo_name)
+    }
+
     pub fn expanded_replaceable_paths(&self) -> Vec<PathBuf> {
         self.settings
             .replaceable_paths
@@ -178,29 +404,53 @@ impl RepoConfig {
             .with_context(|| format!("Failed to parse repo config: {}", config_path.display()))
     }
 
+    /// Merge every `[[when]]` block whose conditions match `vars`.
+    /// Later blocks override earlier ones.
+    pub fn apply_conditions(&mut self, vars: &Value) {
+        let active: Vec<usize> = (0..self.when.len())
+            .filter(|&i| self.when[i].is_active(vars))
+            .collect();
+
+        for i in active {
+            let block = &self.when[i];
+            self.ignore.paths.extend(block.ignore.paths.iter().cloned());
+            self.strategies
+                .extend(block.strategies.iter().map(|(k, v)| (k.clone(), *v)));
+            merge_vars(&mut self.vars, &block.vars);
+        }
+    }
+
     pub fn expanded_target(&self) -> PathBuf {
         let expanded = shellexpand::tilde(&self.target);
         PathBuf::from(expanded.as_ref())
     }
 
     pub fn strategy_for_path(&self, path: &str) -> Strategy {
-        let mut glob_match_result: Option<Strategy> = None;
+        self.entry_for_path(path)
+            .map(|entry| entry.strategy())
+            .unwrap_or(self.defaults.strategy)
+    }
 
-        for (pattern, strategy) in &self.strategies {
-            if path == pattern || path.starts_with(&format!("{}/", pattern)) {
-                return *strategy;
+    pub fn sync_for_path(&self, path: &str) -> SyncPolicy {
+        self.entry_for_path(path)
+            .map(|entry| entry.sync())
+            .unwrap_or_default()
+    }
+
+    fn entry_for_path(&self, path: &str) -> Option<StrategyEntry> {
+        let mut glob_match_result: Option<StrategyEntry> = None;
+
+        for (pattern, entry) in &self.strategies {
+            if is_path_or_child(pattern, path) {
+                return Some(*entry);
             }
 
             if glob_match_result.is_none() && is_glob_pattern(pattern) && glob_matches(pattern, path) {
-                glob_match_result = Some(*strategy);
+                glob_match_result = Some(*entry);
             }
         }
 
-        if let Some(strategy) = glob_match_result {
-            return strategy;
-        }
-
-        self.defaults.strategy
+        glob_match_result
     }
 
     pub fn is_ignored(&self, path: &str) -> bool {
@@ -218,7 +468,7 @@ impl RepoConfig {
             }
         }
 
-        false
+        self.scripts.iter().any(|script| script.path == path)
     }
 }
 
@@ -230,6 +480,15 @@ fn glob_match(pattern: &str, path: &str) -> bool {
     }
 }
 
+fn is_path_or_child(pattern: &str, path: &str) -> bool {
+    path == pattern || path.starts_with(&format!("{}/", pattern))
+}
+
+/// Whether `path` is `pattern`, lies under it, or matches it as a glob
+fn path_matches(pattern: &str, path: &str) -> bool {
+    is_path_or_child(pattern, path) || (is_glob_pattern(pattern) && glob_matches(pattern, path))
+}
+
 fn is_glob_pattern(pattern: &str) -> bool {
     pattern.contains('*') || pattern.contains('?') || pattern.contains('[')
 }
@@ -254,6 +513,7 @@ fn glob_matches(pattern: &str, path: &str) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
 
     #[test]
     fn test_default_global_config() {
@@ -282,6 +542,26 @@ pass_through = ["API_KEY"]
         assert_eq!(config.env.pass_through, vec!["API_KEY"]);
     }
 
+    #[test]
+    fn test_parse_layering() {
+        let toml = r#"
+[settings]
+layers = ["base", "work"]
+
+[priority]
+scratch = 50
+"#;
+
+        let config: GlobalConfig = toml::from_str(toml).unwrap();
+        assert_eq!(config.layer_of("base"), Some(0));
+        assert_eq!(config.layer_of("work"), Some(1));
+        assert_eq!(config.layer_of("scratch"), None);
+
+        let repo: RepoConfig = toml::from_str("target = \"~\"\npriority = 5").unwrap();
+        assert_eq!(config.repo_priority("scratch", &repo), 50);
+        assert_eq!(config.repo_priority("work", &repo), 5);
+    }
+
     #[test]
     fn test_parse_repo_config() {
         let toml = r#"
@@ -306,8 +586,8 @@ paths = ["*.swp"]
         assert_eq!(config.vars.get("git_user").unwrap(), "testuser");
         assert_eq!(config.defaults.strategy, Strategy::File);
         assert_eq!(
-            config.strategies.get(".config/nvim"),
-            Some(&Strategy::Directory)
+            config.strategies.get(".config/nvim").map(StrategyEntry::strategy),
+            Some(Strategy::Directory)
         );
         assert!(config.is_ignored("test.swp"));
         assert!(config.is_ignored(".git"));
@@ -323,6 +603,8 @@ paths = ["*.swp"]
         assert!(config.is_ignored(".git"));
         assert!(config.is_ignored("README.md"));
         assert!(config.is_ignored("LICENSE"));
+        assert!(config.is_ignored("_partials/gitaliases"));
+        assert!(config.is_ignored("_includes/shell/aliases.sh"));
         assert!(!config.is_ignored(".zshrc"));
     }
 
@@ -425,6 +707,214 @@ target = "~"
 "#;
 
         let config: RepoConfig = toml::from_str(toml).unwrap();
-        assert_eq!(config.strategies.get(".config/app"), Some(&Strategy::Copy));
+        assert_eq!(
+            config.strategies.get(".config/app").map(StrategyEntry::strategy),
+            Some(Strategy::Copy)
+        );
+    }
+
+    #[test]
+    fn test_parse_sync_policy() {
+        let toml = r#"
+target = "~"
+
+[strategies]
+".config/app" = "copy"
+"*.json" = { strategy = "copy", sync = "pull" }
+"#;
+
+        let config: RepoConfig = toml::from_str(toml).unwrap();
+        assert_eq!(config.sync_for_path(".config/app/settings.json"), SyncPolicy::Overwrite);
+        assert_eq!(config.sync_for_path("other/settings.json"), SyncPolicy::Pull);
+        assert_eq!(config.strategy_for_path("other/settings.json"), Strategy::Copy);
+        assert_eq!(config.sync_for_path(".zshrc"), SyncPolicy::Overwrite);
+    }
+
+    #[test]
+    fn test_parse_when_blocks() {
+        let toml = r#"
+target = "~"
+
+[[when]]
+os = "macos"
+hostname = "workstation-*"
+
+[when.ignore]
+paths = [".config/i3"]
+
+[when.strategies]
+"Library/Preferences" = "copy"
+
+[[when]]
+match = { role = "server" }
+vars = { editor = "vi" }
+"#;
+
+        let config: RepoConfig = toml::from_str(toml).unwrap();
+        assert_eq!(config.when.len(), 2);
+        assert_eq!(config.when[0].os.as_deref(), Some("macos"));
+        assert_eq!(config.when[0].hostname.as_deref(), Some("workstation-*"));
+        assert_eq!(config.when[0].ignore.paths, vec![".config/i3"]);
+        assert_eq!(config.when[1].matches.get("role").unwrap(), "server");
+        assert_eq!(config.when[1].vars.get("editor").unwrap(), "vi");
+    }
+
+    #[test]
+    fn test_when_is_active() {
+        let vars = json!({
+            "os": "macos",
+            "hostname": "workstation-42",
+            "user": "alice",
+            "machine": { "cores": 16, "laptop": true },
+        });
+
+        let toml = r#"
+target = "~"
+
+[[when]]
+os = "macos"
+hostname = "workstation-*"
+
+[[when]]
+os = "linux"
+
+[[when]]
+match = { role = "server" }
+
+[[when]]
+user = "alice"
+
+[[when]]
+match = { "machine.laptop" = "true", "machine.cores" = "1*" }
+"#;
+
+        let config: RepoConfig = toml::from_str(toml).unwrap();
+        assert!(config.when[0].is_active(&vars));
+        assert!(!config.when[1].is_active(&vars));
+        assert!(!config.when[2].is_active(&vars));
+        assert!(config.when[3].is_active(&vars));
+        assert!(config.when[4].is_active(&vars));
+    }
+
+    #[test]
+    fn test_apply_conditions() {
+        let vars = json!({ "os": "linux" });
+
+        let toml = r#"
+target = "~"
+
+[vars]
+editor = "nvim"
+
+[strategies]
+".config/app" = "directory"
+
+[[when]]
+os = "macos"
+ignore = { paths = [".config/i3"] }
+
+[[when]]
+os = "linux"
+ignore = { paths = [".config/karabiner"] }
+strategies = { ".config/app" = "copy" }
+vars = { editor = "vi" }
+"#;
+
+        let mut config: RepoConfig = toml::from_str(toml).unwrap();
+        config.apply_conditions(&vars);
+
+        assert!(config.is_ignored(".config/karabiner"));
+        assert!(!config.is_ignored(".config/i3"));
+        assert_eq!(config.strategy_for_path(".config/app"), Strategy::Copy);
+        assert_eq!(config.vars.get("editor").unwrap(), "vi");
+    }
+
+    #[test]
+    fn test_parse_profiles() {
+        let toml = r#"
+[profiles.ci]
+repos = ["base", "ci-*"]
+hosts = ["runner-*"]
+vars = { editor = "vi" }
+
+[profiles.laptop]
+repos = ["base", "personal"]
+"#;
+
+        let config: GlobalConfig = toml::from_str(toml).unwrap();
+        assert_eq!(config.profiles.len(), 2);
+        assert_eq!(config.profiles["ci"].repos, vec!["base", "ci-*"]);
+        assert_eq!(config.profiles["ci"].hosts, vec!["runner-*"]);
+        assert!(config.profiles["laptop"].hosts.is_empty());
+        assert!(config.active_profile.is_none());
+    }
+
+    #[test]
+    fn test_activate_profile_by_name() {
+        let toml = r#"
+[vars]
+editor = "nvim"
+
+[profiles.ci]
+repos = ["base", "ci-*"]
+vars = { editor = "vi" }
+"#;
+
+        let mut config: GlobalConfig = toml::from_str(toml).unwrap();
+        assert!(config.is_repo_active("personal"));
+
+        config.activate_profile(Some("ci"), "laptop").unwrap();
+        assert_eq!(config.active_profile.as_deref(), Some("ci"));
+        assert_eq!(config.vars.get("editor").unwrap(), "vi");
+        assert!(config.is_repo_active("base"));
+        assert!(config.is_repo_active("ci-tools"));
+        assert!(!config.is_repo_active("personal"));
+
+        assert!(config.activate_profile(Some("missing"), "laptop").is_err());
+    }
+
+    #[test]
+    fn test_activate_profile_by_hostname() {
+        let toml = r#"
+[profiles.ci]
+repos = ["base"]
+hosts = ["runner-*"]
+"#;
+
+        let mut config: GlobalConfig = toml::from_str(toml).unwrap();
+        config.activate_profile(None, "my-laptop").unwrap();
+        assert!(config.active_profile.is_none());
+
+        config.activate_profile(None, "runner-07").unwrap();
+        assert_eq!(config.active_profile.as_deref(), Some("ci"));
+    }
+
+    #[test]
+    fn test_path_hooks_for() {
+        let toml = r#"
+target = "~"
+
+[hooks]
+post_link = "echo done"
+
+[hooks.paths]
+".config/nvim" = "nvim --headless +qa"
+"*.conf" = "tmux source-file ~/.tmux.conf"
+".zshrc" = "echo zsh"
+"#;
+
+        let config: RepoConfig = toml::from_str(toml).unwrap();
+        assert_eq!(config.hooks.post_link.as_deref(), Some("echo done"));
+
+        let changed = vec![
+            ".config/nvim/init.lua".to_string(),
+            ".tmux.conf".to_string(),
+        ];
+        let hooks = config.hooks.path_hooks_for(&changed);
+
+        assert_eq!(hooks.len(), 2);
+        assert_eq!(hooks[0], ("*.conf", "tmux source-file ~/.tmux.conf", vec![".tmux.conf".to_string()]));
+        assert_eq!(hooks[1].0, ".config/nvim");
+        assert_eq!(hooks[1].2, vec![".config/nvim/init.lua".to_string()]);
     }
 }
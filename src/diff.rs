@@ -0,0 +1,382 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::block;
+use crate::repo::RepoItem;
+use crate::strategy::Strategy;
+use crate::template::{self, TemplateEngine};
+use crate::vars::VarResolver;
+
+/// Bytes checked for NUL when deciding whether content is binary
+const BINARY_SNIFF_LEN: usize = 8000;
+
+/// A file whose content on disk differs from what `link` would place
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileDiff {
+    /// Repo-relative path, extended with the file's path inside copied directories
+    pub path: String,
+    pub target: PathBuf,
+    /// Content at the target (`None` if it does not exist)
+    pub current: Option<Vec<u8>>,
+    /// Content `link` would write (`None` if it would remove the file)
+    pub expected: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct FileStat {
+    pub path: String,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub binary: bool,
+}
+
+impl FileDiff {
+    pub fn is_binary(&self) -> bool {
+        [&self.current, &self.expected]
+            .into_iter()
+            .flatten()
+            .any(|content| is_binary(content))
+    }
+
+    pub fn stat(&self) -> FileStat {
+        let mut stat = FileStat {
+            path: self.path.clone(),
+            insertions: 0,
+            deletions: 0,
+            binary: self.is_binary(),
+        };
+
+        if !stat.binary {
+            let (old, new) = self.texts();
+            for change in TextDiff::from_lines(old, new).iter_all_changes() {
+                match change.tag() {
+                    ChangeTag::Insert => stat.insertions += 1,
+                    ChangeTag::Delete => stat.deletions += 1,
+                    ChangeTag::Equal => {}
+                }
+            }
+        }
+
+        stat
+    }
+
+    /// A unified diff from the target to what `link` would write
+    pub fn unified(&self) -> String {
+        let old_label = match self.current {
+            Some(_) => self.target.display().to_string(),
+            None => "/dev/null".to_string(),
+        };
+        let new_label = match self.expected {
+            Some(_) => self.path.clone(),
+            None => "/dev/null".to_string(),
+        };
+
+        if self.is_binary() {
+            let size = |content: &Option<Vec<u8>>| content.as_ref().map_or(0, Vec::len);
+            return format!(
+                "--- {}\n+++ {}\nBinary files differ ({} -> {} bytes)\n",
+                old_label,
+                new_label,
+                size(&self.current),
+                size(&self.expected)
+            );
+        }
+
+        let (old, new) = self.texts();
+        TextDiff::from_lines(old, new)
+            .unified_diff()
+            .header(&old_label, &new_label)
+            .to_string()
+    }
+
+    fn texts(&self) -> (&str, &str) {
+        (text(&self.current), text(&self.expected))
+    }
+}
+
+fn text(content: &Option<Vec<u8>>) -> &str {
+    content
+        .as_deref()
+        .and_then(|c| std::str::from_utf8(c).ok())
+        .unwrap_or("")
+}
+
+pub fn is_binary(content: &[u8]) -> bool {
+    content[..content.len().min(BINARY_SNIFF_LEN)].contains(&0)
+        || std::str::from_utf8(content).is_err()
+}
+
+/// Differences between what `link` would place for `item` and what is on
+/// disk. Templates are rendered in memory; secrets, templates using
+/// `{{secret}}` and targets that are (or would become) symlinks are skipped.
+/// Blocks are diffed as the whole file with the repo's block updated.
+pub fn item_diffs(
+    item: &RepoItem,
+    repo_path: &Path,
+    engine: &TemplateEngine,
+    var_resolver: &VarResolver,
+) -> Result<Vec<FileDiff>> {
+    if item.is_secret || (item.source.is_symlink() && !item.source.exists()) {
+        return Ok(Vec::new());
+    }
+
+    if item.is_template {
+        let template = fs::read_to_string(&item.source)
+            .with_context(|| format!("Failed to read template: {}", item.source.display()))?;
+        if template::uses_secrets(&template) {
+            return Ok(Vec::new());
+        }
+
+        let vars = var_resolver.to_template_data();
+        let rendered = engine
+            .render(&template, &vars)
+            .with_context(|| format!("Failed to render {}", item.relative_path))?;
+        // A secret reached through an include or partial: never shown
+        if rendered.has_secrets {
+            return Ok(Vec::new());
+        }
+        if item.strategy == Strategy::Block {
+            return block_diff(item, repo_path, &rendered.content);
+        }
+        return Ok(file_diff(item, Some(rendered.content.into_bytes())).into_iter().collect());
+    }
+
+    if item.strategy == Strategy::Block {
+        if !item.source.is_file() {
+            return Ok(Vec::new());
+        }
+        let body = fs::read_to_string(&item.source)
+            .with_context(|| format!("Failed to read: {}", item.source.display()))?;
+        return block_diff(item, repo_path, &body);
+    }
+
+    // Symlinked items only differ when a real file is in the way
+    if !item.strategy.is_copy() && (item.target.is_symlink() || !item.target.exists()) {
+        return Ok(Vec::new());
+    }
+
+    if item.source.is_dir() {
+        if item.target.is_file() {
+            return Ok(Vec::new());
+        }
+        return dir_diffs(&item.source, &item.target, &item.relative_path);
+    }
+
+    if item.target.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let source = fs::read(&item.source)
+        .with_context(|| format!("Failed to read: {}", item.source.display()))?;
+    Ok(file_diff(item, Some(source)).into_iter().collect())
+}
+
+fn block_diff(item: &RepoItem, repo_path: &Path, body: &str) -> Result<Vec<FileDiff>> {
+    if item.target.is_symlink() || item.target.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let current = read_file(&item.target);
+    let existing = text(&current);
+    let updated = block::upsert(existing, &block::owner(repo_path), body)
+        .with_context(|| format!("Failed to update block in {}", item.target.display()))?;
+    Ok(file_diff(item, Some(updated.into_bytes())).into_iter().collect())
+}
+
+fn file_diff(item: &RepoItem, expected: Option<Vec<u8>>) -> Option<FileDiff> {
+    let current = read_file(&item.target);
+    (current != expected).then(|| FileDiff {
+        path: item.relative_path.clone(),
+        target: item.target.clone(),
+        current,
+        expected,
+    })
+}
+
+/// File-by-file differences between a source directory and its target.
+/// Files only in the target count as removed, since copies replace the tree.
+fn dir_diffs(source: &Path, target: &Path, prefix: &str) -> Result<Vec<FileDiff>> {
+    let mut relative_paths = BTreeSet::new();
+    for root in [source, target] {
+        for entry in WalkDir::new(root).min_depth(1).into_iter().filter_map(|e| e.ok()) {
+            if entry.file_type().is_file() {
+                let relative = entry
+                    .path()
+                    .strip_prefix(root)
+                    .context("Failed to strip prefix")?;
+                relative_paths.insert(relative.to_path_buf());
+            }
+        }
+    }
+
+    let mut diffs = Vec::new();
+    for relative in relative_paths {
+        let current = read_file(&target.join(&relative));
+        let expected = read_file(&source.join(&relative));
+        if current != expected {
+            diffs.push(FileDiff {
+                path: Path::new(prefix).join(&relative).to_string_lossy().to_string(),
+                target: target.join(&relative),
+                current,
+                expected,
+            });
+        }
+    }
+
+    Ok(diffs)
+}
+
+fn read_file(path: &Path) -> Option<Vec<u8>> {
+    if path.is_symlink() || !path.is_file() {
+        return None;
+    }
+    fs::read(path).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GlobalConfig;
+    use crate::repo::Repo;
+    use crate::strategy::SyncPolicy;
+    use tempfile::TempDir;
+
+    fn item(temp: &TempDir, relative: &str, strategy: Strategy) -> RepoItem {
+        RepoItem {
+            source: temp.path().join("repo").join(relative),
+            target: temp.path().join("home").join(relative.trim_end_matches(".tmpl")),
+            relative_path: relative.to_string(),
+            is_template: relative.ends_with(".tmpl"),
+            is_secret: false,
+            strategy,
+            sync: SyncPolicy::Overwrite,
+        }
+    }
+
+    fn write(path: &Path, content: &[u8]) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn diffs(item: &RepoItem) -> Vec<FileDiff> {
+        let config = GlobalConfig::default();
+        let var_resolver = VarResolver::new(&config, None);
+        item_diffs(item, Path::new("repo"), &TemplateEngine::new(), &var_resolver).unwrap()
+    }
+
+    #[test]
+    fn test_template_diff_is_rendered() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".gitconfig.tmpl", Strategy::File);
+        write(&item.source, b"[core]\neditor = {{#if true}}nvim{{/if}}\n");
+        write(&item.target, b"[core]\neditor = vim\n");
+
+        let found = diffs(&item);
+        assert_eq!(found.len(), 1);
+        let patch = found[0].unified();
+        assert!(patch.contains("-editor = vim\n"));
+        assert!(patch.contains("+editor = nvim\n"));
+        assert_eq!(
+            found[0].stat(),
+            FileStat {
+                path: ".gitconfig.tmpl".to_string(),
+                insertions: 1,
+                deletions: 1,
+                binary: false
+            }
+        );
+
+        write(&item.target, b"[core]\neditor = nvim\n");
+        assert!(diffs(&item).is_empty());
+    }
+
+    #[test]
+    fn test_copied_directory_diffs() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".config/app", Strategy::Copy);
+        write(&item.source.join("same.toml"), b"a\n");
+        write(&item.target.join("same.toml"), b"a\n");
+        write(&item.source.join("new.toml"), b"b\n");
+        write(&item.target.join("extra.toml"), b"c\n");
+
+        let diffs = diffs(&item);
+        let paths: Vec<&str> = diffs.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec![".config/app/extra.toml", ".config/app/new.toml"]);
+        assert!(diffs[0].expected.is_none());
+        assert!(diffs[1].current.is_none());
+        assert!(diffs[1].unified().starts_with("--- /dev/null\n"));
+    }
+
+    #[test]
+    fn test_symlink_item_with_regular_file_in_the_way() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".zshrc", Strategy::File);
+        write(&item.source, b"repo\n");
+        assert!(diffs(&item).is_empty());
+
+        write(&item.target, b"local\n");
+        assert_eq!(diffs(&item).len(), 1);
+    }
+
+    #[test]
+    fn test_binary_files_are_summarized() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, "icon.png", Strategy::Copy);
+        write(&item.source, b"\x89PNG\x00\x01");
+        write(&item.target, b"\x89PNG\x00\x02\x03");
+
+        let diffs = diffs(&item);
+        assert!(diffs[0].is_binary());
+        assert!(diffs[0].unified().contains("Binary files differ (7 -> 6 bytes)"));
+        assert!(diffs[0].stat().binary);
+    }
+
+    #[test]
+    fn test_secret_templates_are_skipped() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".netrc.tmpl", Strategy::File);
+        write(&item.source, b"password {{secret \"netrc\"}}\n");
+        write(&item.target, b"password hunter2\n");
+        assert!(diffs(&item).is_empty());
+    }
+
+    #[test]
+    fn test_templates_including_secrets_are_skipped() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".netrc.tmpl", Strategy::File);
+        write(&item.source, b"{{include \"inc/netrc\"}}");
+        write(&temp.path().join("repo/inc/netrc"), b"password {{secret \"netrc\"}}\n");
+        write(&item.target, b"password old\n");
+
+        write(&temp.path().join("repo/homie.toml"), b"target = \"~\"\n");
+
+        let mut config = GlobalConfig::default();
+        config.secrets.provider = Some("echo hunter2 #".to_string());
+        let repo = Repo::from_path(&temp.path().join("repo"), &config).unwrap();
+        let var_resolver = VarResolver::new(&config, Some(&repo));
+        let engine = TemplateEngine::for_config(&config);
+        assert!(item_diffs(&item, Path::new("repo"), &engine, &var_resolver).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_block_diff_keeps_the_rest_of_the_file() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".bashrc", Strategy::Block);
+        write(&item.source, b"alias ll='ls -l'\n");
+        write(&item.target, b"# installer\n");
+
+        let found = diffs(&item);
+        assert_eq!(found.len(), 1);
+        let patch = found[0].unified();
+        assert!(patch.contains(" # installer\n"));
+        assert!(patch.contains("+# BEGIN homie:repo\n+alias ll='ls -l'\n+# END homie:repo\n"));
+
+        write(&item.target, b"# installer\n# BEGIN homie:repo\nalias ll='ls -l'\n# END homie:repo\n");
+        assert!(diffs(&item).is_empty());
+    }
+}
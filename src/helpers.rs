@@ -0,0 +1,292 @@
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperDef, RenderContext, RenderError,
+    RenderErrorReason, ScopedJson,
+};
+use serde_json::Value;
+use std::cell::Cell;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::template::preprocess_template;
+use crate::vars::as_text;
+
+/// How deep `include` may nest before a template is assumed to include itself
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+/// Helpers Handlebars registers itself
+pub const BUILT_IN: &[&str] = &[
+    "if", "unless", "each", "with", "lookup", "raw", "log", "eq", "ne", "gt", "gte", "lt", "lte",
+    "and", "or", "not", "len",
+];
+
+/// Register homie's helpers and return their names. `eq`, `ne`, `and`, `or`
+/// and `not` are built into Handlebars already.
+pub fn register(handlebars: &mut Handlebars) -> Vec<&'static str> {
+    let helpers: Vec<(&'static str, Box<dyn HelperDef + Send + Sync>)> = vec![
+        ("contains", Box::new(contains)),
+        ("matches", Box::new(MatchesHelper)),
+        ("upper", Box::new(upper)),
+        ("lower", Box::new(lower)),
+        ("trim", Box::new(trim)),
+        ("replace", Box::new(replace)),
+        ("join", Box::new(join)),
+        ("path_join", Box::new(path_join)),
+        ("exists", Box::new(exists)),
+        ("which", Box::new(which)),
+        ("default", Box::new(DefaultHelper)),
+        ("include", Box::new(IncludeHelper { root: None })),
+    ];
+
+    let names = helpers.iter().map(|(name, _)| *name).collect();
+    for (name, helper) in helpers {
+        handlebars.register_helper(name, helper);
+    }
+    names
+}
+
+handlebars_helper!(contains: |haystack: Json, needle: Json| match haystack {
+    Value::String(s) => needle.as_str().is_some_and(|n| s.contains(n)),
+    Value::Array(items) => items.contains(needle),
+    Value::Object(map) => needle.as_str().is_some_and(|key| map.contains_key(key)),
+    _ => false,
+});
+
+handlebars_helper!(upper: |s: str| s.to_uppercase());
+handlebars_helper!(lower: |s: str| s.to_lowercase());
+handlebars_helper!(trim: |s: str| s.trim());
+handlebars_helper!(replace: |s: str, from: str, to: str| s.replace(from, to));
+
+handlebars_helper!(join: |items: array, separator: str| items
+    .iter()
+    .map(|item| as_text(item).unwrap_or_else(|| item.to_string()))
+    .collect::<Vec<_>>()
+    .join(separator));
+
+handlebars_helper!(path_join: |*parts| parts
+    .iter()
+    .filter_map(|part| as_text(part))
+    .fold(PathBuf::new(), |path, part| path.join(part))
+    .to_string_lossy()
+    .to_string());
+
+handlebars_helper!(exists: |path: str| Path::new(shellexpand::tilde(path).as_ref()).exists());
+
+// `null` when not found, so it works as a condition: {{#if (which "nvim")}}
+handlebars_helper!(which: |command: str| find_in_path(command)
+    .map(|path| Value::String(path.to_string_lossy().to_string()))
+    .unwrap_or(Value::Null));
+
+/// `default <value> <fallback>`: `fallback` when `value` is missing (even in
+/// strict mode), `null` or `""`
+struct DefaultHelper;
+
+impl HelperDef for DefaultHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let fallback = h
+            .param(1)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("default", 1))?;
+        let value = h.param(0).map(|p| p.value()).unwrap_or(&Value::Null);
+
+        let unset = match value {
+            Value::Null => true,
+            Value::String(s) => s.is_empty(),
+            _ => false,
+        };
+        Ok(ScopedJson::Derived(if unset {
+            fallback.value().clone()
+        } else {
+            value.clone()
+        }))
+    }
+}
+
+/// `matches "<text>" "<regex>"`: whether the regex matches anywhere in the text
+struct MatchesHelper;
+
+impl HelperDef for MatchesHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let text = string_param(h, "matches", 0)?;
+        let pattern = string_param(h, "matches", 1)?;
+        let regex = regex_lite::Regex::new(pattern).map_err(|e| {
+            RenderErrorReason::Other(format!("matches: invalid regex {:?}: {}", pattern, e))
+        })?;
+        Ok(ScopedJson::Derived(Value::Bool(regex.is_match(text))))
+    }
+}
+
+/// `include "<path>"`: a file from the repo, rendered with the current vars.
+/// Only available when rendering a repo's template (`root` is the repo).
+pub struct IncludeHelper {
+    pub root: Option<PathBuf>,
+}
+
+thread_local! {
+    /// How many includes the render on this thread is inside. Each include
+    /// renders through a context of its own, so the count lives outside it.
+    static INCLUDE_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+impl HelperDef for IncludeHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let relative = string_param(h, "include", 0)?;
+        let Some(root) = &self.root else {
+            return Err(RenderErrorReason::Other(format!(
+                "include \"{}\": only available in repo templates",
+                relative
+            ))
+            .into());
+        };
+
+        let escapes = Path::new(relative)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+        if escapes {
+            return Err(RenderErrorReason::Other(format!(
+                "include \"{}\": path must be relative to the repo",
+                relative
+            ))
+            .into());
+        }
+
+        let path = root.join(relative);
+        let template = fs::read_to_string(&path).map_err(|e| {
+            RenderErrorReason::Other(format!("include \"{}\": {}", relative, e))
+        })?;
+
+        let depth = INCLUDE_DEPTH.get();
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(RenderErrorReason::Other(format!(
+                "include \"{}\": nested more than {} levels deep",
+                relative, MAX_INCLUDE_DEPTH
+            ))
+            .into());
+        }
+        let processed = preprocess_template(&template, ctx.data());
+        INCLUDE_DEPTH.set(depth + 1);
+        let rendered = r.render_template_with_context(&processed, ctx);
+        INCLUDE_DEPTH.set(depth);
+
+        Ok(ScopedJson::Derived(Value::String(rendered?)))
+    }
+}
+
+fn string_param<'a>(h: &'a Helper, helper: &'static str, index: usize) -> Result<&'a str, RenderError> {
+    h.param(index)
+        .and_then(|p| p.value().as_str())
+        .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex(helper, index).into())
+}
+
+/// First executable named `command` in `$PATH`
+fn find_in_path(command: &str) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if command.contains('/') {
+        let path = PathBuf::from(command);
+        return path.is_file().then_some(path);
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(command))
+        .find(|path| {
+            fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::template::{TemplateData, TemplateEngine};
+    use serde_json::json;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn render(template: &str, vars: serde_json::Value) -> String {
+        TemplateEngine::new()
+            .render_string(template, &TemplateData::from(vars))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_logic_helpers() {
+        let vars = json!({ "os": "macos", "hostname": "work-laptop", "tags": ["gpu"] });
+        assert_eq!(render(r#"{{#if (eq os "macos")}}mac{{/if}}"#, vars.clone()), "mac");
+        assert_eq!(
+            render(r#"{{#if (and (ne os "linux") (contains hostname "work"))}}yes{{/if}}"#, vars.clone()),
+            "yes"
+        );
+        assert_eq!(render(r#"{{#if (contains tags "gpu")}}cuda{{/if}}"#, vars.clone()), "cuda");
+        assert_eq!(render(r#"{{#if (matches hostname "^work-")}}w{{/if}}"#, vars.clone()), "w");
+        assert_eq!(render(r#"{{#unless (or (not true) false)}}x{{/unless}}"#, vars), "x");
+    }
+
+    #[test]
+    fn test_string_helpers() {
+        let vars = json!({ "name": "  Alice  ", "hosts": ["a", "b", 3], "empty": "" });
+        assert_eq!(render("{{upper (trim name)}}|{{lower \"ABC\"}}", vars.clone()), "ALICE|abc");
+        assert_eq!(render(r#"{{replace "a-b-c" "-" "_"}}"#, vars.clone()), "a_b_c");
+        assert_eq!(render(r#"{{join hosts ", "}}"#, vars.clone()), "a, b, 3");
+        assert_eq!(render(r#"{{path_join "/etc" "ssh" "config"}}"#, vars.clone()), "/etc/ssh/config");
+        assert_eq!(render(r#"{{default empty "fallback"}}|{{default missing 1}}"#, vars), "fallback|1");
+    }
+
+    #[test]
+    fn test_system_helpers() {
+        let temp = TempDir::new().unwrap();
+        let vars = json!({ "dir": temp.path() });
+        assert_eq!(render("{{exists dir}}", vars.clone()), "true");
+        assert_eq!(render(r#"{{exists "/definitely/not/here"}}"#, vars), "false");
+        assert_eq!(render(r#"{{#if (which "sh")}}found{{/if}}"#, json!({})), "found");
+        assert_eq!(render(r#"{{#if (which "no-such-command-xyz")}}found{{/if}}"#, json!({})), "");
+    }
+
+    #[test]
+    fn test_invalid_regex_is_an_error() {
+        let result = TemplateEngine::new()
+            .render_string(r#"{{matches "a" "("}}"#, &TemplateData::from(json!({})));
+        assert!(format!("{:#}", result.unwrap_err()).contains("invalid regex"));
+    }
+
+    #[test]
+    fn test_include() {
+        let temp = TempDir::new().unwrap();
+        fs::create_dir_all(temp.path().join("shared")).unwrap();
+        fs::write(temp.path().join("shared/aliases.sh"), "alias e={{editor:vi}}\n").unwrap();
+        fs::write(temp.path().join("loop.tmpl"), "{{include \"loop.tmpl\"}}").unwrap();
+
+        let engine = TemplateEngine::new();
+        let mut data = TemplateData::from(json!({ "editor": "nvim" }));
+        data.root = Some(temp.path().to_path_buf());
+
+        let result = engine.render_string("# aliases\n{{include \"shared/aliases.sh\"}}", &data).unwrap();
+        assert_eq!(result, "# aliases\nalias e=nvim\n");
+
+        assert!(engine.render_string("{{include \"../etc/passwd\"}}", &data).is_err());
+        assert!(engine.render_string("{{include \"loop.tmpl\"}}", &data).is_err());
+
+        // The failed loop leaves no depth behind for the next render
+        let result = engine.render_string("{{include \"shared/aliases.sh\"}}", &data).unwrap();
+        assert_eq!(result, "alias e=nvim\n");
+
+        data.root = None;
+        let err = engine.render_string("{{include \"shared/aliases.sh\"}}", &data).unwrap_err();
+        assert!(format!("{:#}", err).contains("only available in repo templates"));
+    }
+}
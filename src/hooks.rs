@@ -0,0 +1,151 @@
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::repo::Repo;
+
+/// Runs a repo's hook commands with context passed through env vars:
+///
+/// - `HOMIE_REPO` / `HOMIE_REPO_PATH`: repo name and directory
+/// - `HOMIE_TARGET`: the repo's target directory
+/// - `HOMIE_CHANGED`: changed paths (repo-relative), one per line
+pub struct HookRunner {
+    repo_name: String,
+    repo_path: PathBuf,
+    target: PathBuf,
+    dry_run: bool,
+    /// Send hook output to stderr and print nothing ourselves (JSON output)
+    quiet: bool,
+}
+
+impl HookRunner {
+    pub fn new(repo: &Repo, dry_run: bool, quiet: bool) -> Self {
+        Self {
+            repo_name: repo.name.clone(),
+            repo_path: repo.path.clone(),
+            target: repo.target.clone(),
+            dry_run,
+            quiet,
+        }
+    }
+
+    pub fn run(&self, name: &str, command: &str, changed: &[String]) -> Result<()> {
+        if self.dry_run {
+            if !self.quiet {
+                println!("  {} would run {} hook: {}", "▸".cyan(), name, command.dimmed());
+            }
+            return Ok(());
+        }
+
+        if !self.quiet {
+            println!("  {} {} hook", "▸".cyan(), name);
+        }
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        self.execute(cmd, changed)
+            .with_context(|| format!("{} hook failed: {}", name, command))
+    }
+
+    /// Run a script file: directly if it starts with `#!`, otherwise with `sh`
+    pub fn run_script(&self, name: &str, script: &Path, changed: &[String]) -> Result<()> {
+        if self.dry_run {
+            if !self.quiet {
+                println!("  {} would run script {}", "▸".cyan(), name);
+            }
+            return Ok(());
+        }
+
+        if !self.quiet {
+            println!("  {} script {}", "▸".cyan(), name);
+        }
+
+        let has_shebang = fs::read(script)
+            .with_context(|| format!("Failed to read script: {}", script.display()))?
+            .starts_with(b"#!");
+        let cmd = if has_shebang {
+            Command::new(script)
+        } else {
+            let mut cmd = Command::new("sh");
+            cmd.arg(script);
+            cmd
+        };
+
+        self.execute(cmd, changed)
+            .with_context(|| format!("Script {} failed", name))
+    }
+
+    fn execute(&self, mut cmd: Command, changed: &[String]) -> Result<()> {
+        let stdout = if self.quiet {
+            Stdio::from(std::io::stderr())
+        } else {
+            Stdio::inherit()
+        };
+
+        let status = cmd
+            .current_dir(&self.repo_path)
+            .env("HOMIE_REPO", &self.repo_name)
+            .env("HOMIE_REPO_PATH", &self.repo_path)
+            .env("HOMIE_TARGET", &self.target)
+            .env("HOMIE_CHANGED", changed.join("\n"))
+            .stdout(stdout)
+            .status()
+            .context("Failed to start")?;
+
+        if !status.success() {
+            bail!("{}", status);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn runner(temp: &TempDir, dry_run: bool) -> HookRunner {
+        HookRunner {
+            repo_name: "dotfiles".to_string(),
+            repo_path: temp.path().to_path_buf(),
+            target: PathBuf::from("/home/user"),
+            dry_run,
+            quiet: true,
+        }
+    }
+
+    #[test]
+    fn test_hook_receives_context() {
+        let temp = TempDir::new().unwrap();
+        let changed = vec![".zshrc".to_string(), ".config/nvim".to_string()];
+
+        runner(&temp, false)
+            .run(
+                "post_link",
+                "printf '%s|%s|%s' \"$HOMIE_REPO\" \"$HOMIE_TARGET\" \"$HOMIE_CHANGED\" > out",
+                &changed,
+            )
+            .unwrap();
+
+        let out = fs::read_to_string(temp.path().join("out")).unwrap();
+        assert_eq!(out, "dotfiles|/home/user|.zshrc\n.config/nvim");
+    }
+
+    #[test]
+    fn test_hook_failure() {
+        let temp = TempDir::new().unwrap();
+        let err = runner(&temp, false).run("pre_link", "exit 3", &[]).unwrap_err();
+        assert!(err.to_string().contains("pre_link hook failed"));
+        assert!(format!("{:#}", err).contains("exit status: 3"));
+    }
+
+    #[test]
+    fn test_hook_dry_run_does_not_run() {
+        let temp = TempDir::new().unwrap();
+        runner(&temp, true).run("post_link", "touch ran", &[]).unwrap();
+        assert!(!temp.path().join("ran").exists());
+    }
+}
@@ -21,7 +21,7 @@ pub enum ImportSource {
 }
 
 impl Import {
-    pub fn ensure_available(&self, repo_path: &Path, dry_run: bool) -> Result<()> {
+    pub fn ensure_available(&self, repo_path: &Path, dry_run: bool, quiet: bool) -> Result<()> {
         match &self.source {
             ImportSource::Local(path) => {
                 if !path.exists() {
@@ -34,13 +34,17 @@ impl Import {
 
                 if import_dir.exists() {
                     // Pull latest
-                    println!("  {} {} (pulling)", "↓".cyan(), self.name);
+                    if !quiet {
+                        println!("  {} {} (pulling)", "↓".cyan(), self.name);
+                    }
                     if !dry_run {
                         git_pull(&import_dir, git_ref.as_deref())?;
                     }
                 } else {
                     // Clone
-                    println!("  {} {} (cloning)", "↓".cyan(), self.name);
+                    if !quiet {
+                        println!("  {} {} (cloning)", "↓".cyan(), self.name);
+                    }
                     if !dry_run {
                         git_clone(url, &import_dir, git_ref.as_deref())?;
                     }
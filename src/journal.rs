@@ -0,0 +1,632 @@
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs as unix_fs;
+use std::path::{Path, PathBuf};
+
+use crate::manifest::Manifest;
+use crate::sync::{fingerprint, Fingerprint};
+
+/// A single filesystem mutation applied during a link run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalOp {
+    /// A directory was created (only recorded for directories that did not exist)
+    CreatedDir { path: PathBuf },
+    /// A symlink, file or directory was created at `path`. `fingerprint` is
+    /// what was created, so a rollback can tell it was edited since.
+    Created {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<Fingerprint>,
+    },
+    /// An existing file or directory was moved into the stash before being replaced.
+    /// `stash` is relative to the journal directory.
+    Removed { path: PathBuf, stash: PathBuf },
+    /// An existing symlink was removed before being replaced
+    RemovedSymlink { path: PathBuf, link: PathBuf },
+    /// An existing file or directory was renamed to a backup (`--force`)
+    BackedUp { path: PathBuf, backup: PathBuf },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct JournalFile {
+    created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    run: Option<String>,
+    #[serde(default)]
+    ops: Vec<JournalOp>,
+}
+
+/// Records every mutation of a link run so it can be undone.
+///
+/// The journal lives at `<repo>/.homie/journal/` and is written after every
+/// step, so an interrupted run can be rolled back on the next invocation.
+/// Nothing touches disk until the first mutation is recorded. On commit the
+/// journal becomes a numbered generation under `<repo>/.homie/generations/`.
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    created_at: Option<String>,
+    /// The run that committed it; one run can commit a generation in
+    /// several repos
+    run: Option<String>,
+    ops: Vec<JournalOp>,
+    /// Whether `dir` belongs to this journal: it was loaded from there, or
+    /// this run wrote it. Only then may it be removed.
+    owned: bool,
+}
+
+impl Journal {
+    pub fn new(repo_path: &Path) -> Self {
+        Self {
+            dir: Self::dir_for_repo(repo_path),
+            created_at: None,
+            run: None,
+            ops: Vec::new(),
+            owned: false,
+        }
+    }
+
+    pub fn dir_for_repo(repo_path: &Path) -> PathBuf {
+        repo_path.join(".homie/journal")
+    }
+
+    /// Load a journal left behind by an interrupted run, if any
+    pub fn load_pending(repo_path: &Path) -> Result<Option<Self>> {
+        Self::load_from(&Self::dir_for_repo(repo_path))
+    }
+
+    pub fn load_from(dir: &Path) -> Result<Option<Self>> {
+        let journal_path = dir.join("journal.toml");
+
+        if !journal_path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&journal_path)
+            .with_context(|| format!("Failed to read journal: {}", journal_path.display()))?;
+        let file: JournalFile = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse journal: {}", journal_path.display()))?;
+
+        Ok(Some(Self {
+            dir: dir.to_path_buf(),
+            created_at: file.created_at,
+            run: file.run,
+            ops: file.ops,
+            owned: true,
+        }))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[JournalOp] {
+        &self.ops
+    }
+
+    pub fn created_at(&self) -> Option<&str> {
+        self.created_at.as_deref()
+    }
+
+    pub fn run(&self) -> Option<&str> {
+        self.run.as_deref()
+    }
+
+    /// Create `path` and any missing ancestors, recording each one created
+    pub fn create_dir_all(&mut self, path: &Path) -> Result<()> {
+        let missing: Vec<PathBuf> = path
+            .ancestors()
+            .take_while(|a| !a.as_os_str().is_empty() && !a.exists())
+            .map(Path::to_path_buf)
+            .collect();
+
+        for dir in missing.into_iter().rev() {
+            fs::create_dir(&dir)
+                .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+            self.record(JournalOp::CreatedDir { path: dir })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn record_created(&mut self, path: &Path) -> Result<()> {
+        self.record(JournalOp::Created {
+            path: path.to_path_buf(),
+            fingerprint: Some(fingerprint(path)?),
+        })
+    }
+
+    /// Move `path` out of the way so it can be restored on rollback
+    pub fn remove(&mut self, path: &Path) -> Result<()> {
+        if path.is_symlink() {
+            let link = fs::read_link(path)
+                .with_context(|| format!("Failed to read symlink: {}", path.display()))?;
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove: {}", path.display()))?;
+            return self.record(JournalOp::RemovedSymlink {
+                path: path.to_path_buf(),
+                link,
+            });
+        }
+
+        let stash_dir = self.dir.join("stash");
+        fs::create_dir_all(&stash_dir)
+            .with_context(|| format!("Failed to create stash: {}", stash_dir.display()))?;
+        let stash = Path::new("stash").join(self.ops.len().to_string());
+
+        move_path(path, &self.dir.join(&stash))?;
+        self.record(JournalOp::Removed {
+            path: path.to_path_buf(),
+            stash,
+        })
+    }
+
+    pub fn backup(&mut self, path: &Path, backup: &Path) -> Result<()> {
+        fs::rename(path, backup)
+            .with_context(|| format!("Failed to backup: {}", path.display()))?;
+        self.record(JournalOp::BackedUp {
+            path: path.to_path_buf(),
+            backup: backup.to_path_buf(),
+        })
+    }
+
+    /// Undo every recorded step in reverse order, then discard the journal.
+    /// Returns the number of steps undone.
+    ///
+    /// The journal is saved after every step. A step that fails stops the
+    /// rollback with it and everything before it still recorded (and the
+    /// stash kept), so running it again picks up where it stopped.
+    pub fn rollback(mut self) -> Result<usize> {
+        let mut undone = 0;
+
+        while let Some(op) = self.ops.last() {
+            undo(op, &self.dir).with_context(|| {
+                format!(
+                    "Rollback incomplete, {} step(s) left in {}",
+                    self.ops.len(),
+                    self.dir.display()
+                )
+            })?;
+            self.ops.pop();
+            self.save()?;
+            undone += 1;
+        }
+
+        self.discard()?;
+        Ok(undone)
+    }
+
+    /// Finish the transaction successfully, keeping it as the next generation
+    /// tagged with `run` (see [`new_run_id`]). `manifest_path` is snapshotted
+    /// so a rollback can restore it. Returns the generation number, or `None`
+    /// if the run changed nothing.
+    pub fn commit(mut self, repo_path: &Path, manifest_path: &Path, run: &str) -> Result<Option<u32>> {
+        if self.ops.is_empty() {
+            self.discard()?;
+            return Ok(None);
+        }
+
+        self.created_at = Some(Local::now().to_rfc3339());
+        self.run = Some(run.to_string());
+        self.save()?;
+
+        if manifest_path.exists() {
+            fs::copy(manifest_path, self.dir.join("manifest.toml"))
+                .with_context(|| format!("Failed to snapshot manifest: {}", manifest_path.display()))?;
+        }
+
+        let generations_dir = generations_dir(repo_path);
+        fs::create_dir_all(&generations_dir)
+            .with_context(|| format!("Failed to create: {}", generations_dir.display()))?;
+
+        let number = list_generations(repo_path)?.last().copied().unwrap_or(0) + 1;
+        let generation_dir = generations_dir.join(number.to_string());
+        fs::rename(&self.dir, &generation_dir)
+            .with_context(|| format!("Failed to record generation: {}", generation_dir.display()))?;
+
+        Ok(Some(number))
+    }
+
+    /// The manifest as it was before this journal's run, if it was snapshotted
+    pub fn manifest_snapshot(&self) -> PathBuf {
+        self.dir.join("manifest.toml")
+    }
+
+    /// Remove the journal directory, unless it belongs to another run (a
+    /// journal left by an interrupted run that has not been recovered yet)
+    fn discard(&self) -> Result<()> {
+        if self.owned && self.dir.exists() {
+            fs::remove_dir_all(&self.dir)
+                .with_context(|| format!("Failed to remove journal: {}", self.dir.display()))?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, op: JournalOp) -> Result<()> {
+        self.ops.push(op);
+        self.owned = true;
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create journal dir: {}", self.dir.display()))?;
+
+        let journal_path = self.dir.join("journal.toml");
+        let file = JournalFile {
+            created_at: self.created_at.clone(),
+            run: self.run.clone(),
+            ops: self.ops.clone(),
+        };
+        let content = toml::to_string_pretty(&file).context("Failed to serialize journal")?;
+
+        let temp_path = journal_path.with_extension("toml.tmp");
+        fs::write(&temp_path, content)
+            .with_context(|| format!("Failed to write journal: {}", temp_path.display()))?;
+        fs::rename(&temp_path, &journal_path)
+            .with_context(|| format!("Failed to rename journal: {}", journal_path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Identifies one run across the repos it commits generations in
+pub fn new_run_id() -> String {
+    format!("{}-{}", Local::now().format("%Y%m%dT%H%M%S%.6f"), std::process::id())
+}
+
+pub fn generations_dir(repo_path: &Path) -> PathBuf {
+    repo_path.join(".homie/generations")
+}
+
+/// Generation numbers recorded for a repo, oldest first
+pub fn list_generations(repo_path: &Path) -> Result<Vec<u32>> {
+    let dir = generations_dir(repo_path);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut numbers: Vec<u32> = fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read: {}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_string_lossy().parse().ok())
+        .collect();
+    numbers.sort_unstable();
+
+    Ok(numbers)
+}
+
+/// Remove all but the newest `keep` generations, stashes included. `0`
+/// keeps every generation.
+pub fn prune_generations(repo_path: &Path, keep: usize) -> Result<()> {
+    let generations = list_generations(repo_path)?;
+    if keep == 0 || generations.len() <= keep {
+        return Ok(());
+    }
+
+    for number in &generations[..generations.len() - keep] {
+        let dir = generations_dir(repo_path).join(number.to_string());
+        fs::remove_dir_all(&dir)
+            .with_context(|| format!("Failed to remove generation: {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+pub fn load_generation(repo_path: &Path, number: u32) -> Result<Journal> {
+    let dir = generations_dir(repo_path).join(number.to_string());
+    Journal::load_from(&dir)?
+        .with_context(|| format!("Generation {} not found", number))
+}
+
+/// Undo a committed generation and restore the manifest it replaced
+pub fn rollback_generation(repo_path: &Path, number: u32) -> Result<usize> {
+    let journal = load_generation(repo_path, number)?;
+    let snapshot = fs::read_to_string(journal.manifest_snapshot()).ok();
+
+    let undone = journal.rollback()?;
+
+    let manifest_path = Manifest::path_for_repo(repo_path);
+    match snapshot {
+        Some(content) => fs::write(&manifest_path, content)
+            .with_context(|| format!("Failed to restore manifest: {}", manifest_path.display()))?,
+        None if manifest_path.exists() => fs::remove_file(&manifest_path)
+            .with_context(|| format!("Failed to remove manifest: {}", manifest_path.display()))?,
+        None => {}
+    }
+
+    Ok(undone)
+}
+
+fn undo(op: &JournalOp, journal_dir: &Path) -> Result<()> {
+    match op {
+        JournalOp::CreatedDir { path } => {
+            // Only remove if nothing else has been placed inside since
+            if path.is_dir() && fs::read_dir(path)?.next().is_none() {
+                fs::remove_dir(path)
+                    .with_context(|| format!("Failed to remove directory: {}", path.display()))?;
+            }
+        }
+        JournalOp::Created { path, fingerprint: created } => {
+            // Never throw away edits made after the run
+            if let Some(created) = created {
+                let current = fingerprint(path)?;
+                if current != Fingerprint::Missing && current != *created {
+                    bail!(
+                        "{} was modified after it was linked; move it away to roll back",
+                        path.display()
+                    );
+                }
+            }
+            remove_any(path)?;
+        }
+        JournalOp::Removed { path, stash } => {
+            remove_any(path)?;
+            move_path(&journal_dir.join(stash), path)?;
+        }
+        JournalOp::RemovedSymlink { path, link } => {
+            remove_any(path)?;
+            unix_fs::symlink(link, path)
+                .with_context(|| format!("Failed to restore symlink: {}", path.display()))?;
+        }
+        JournalOp::BackedUp { path, backup } => {
+            remove_any(path)?;
+            fs::rename(backup, path)
+                .with_context(|| format!("Failed to restore backup: {}", backup.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_any(path: &Path) -> Result<()> {
+    if path.is_symlink() || path.is_file() {
+        fs::remove_file(path).with_context(|| format!("Failed to remove: {}", path.display()))?;
+    } else if path.is_dir() {
+        fs::remove_dir_all(path)
+            .with_context(|| format!("Failed to remove: {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Rename, falling back to copy + delete when crossing filesystems
+fn move_path(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    if from.is_dir() {
+        crate::linker::copy_dir_recursive(from, to)?;
+        fs::remove_dir_all(from)
+            .with_context(|| format!("Failed to remove: {}", from.display()))?;
+    } else {
+        fs::copy(from, to)
+            .with_context(|| format!("Failed to copy {} to {}", from.display(), to.display()))?;
+        fs::remove_file(from).with_context(|| format!("Failed to remove: {}", from.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_rollback_created_and_dirs() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let target = temp.path().join("home/.config/app/file");
+
+        let mut journal = Journal::new(&repo);
+        journal.create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(&target, "x").unwrap();
+        journal.record_created(&target).unwrap();
+
+        assert!(Journal::dir_for_repo(&repo).join("journal.toml").exists());
+
+        let undone = journal.rollback().unwrap();
+        assert_eq!(undone, 4);
+        assert!(!temp.path().join("home").exists());
+        assert!(!Journal::dir_for_repo(&repo).exists());
+    }
+
+    #[test]
+    fn test_rollback_restores_removed_file_and_symlink() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let file = temp.path().join("file");
+        let link = temp.path().join("link");
+        fs::write(&file, "original").unwrap();
+        unix_fs::symlink("/somewhere", &link).unwrap();
+
+        let mut journal = Journal::new(&repo);
+        journal.remove(&file).unwrap();
+        journal.remove(&link).unwrap();
+        fs::write(&file, "replacement").unwrap();
+        assert!(!link.is_symlink());
+
+        journal.rollback().unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "original");
+        assert_eq!(fs::read_link(&link).unwrap(), PathBuf::from("/somewhere"));
+    }
+
+    #[test]
+    fn test_rollback_restores_backup() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let file = temp.path().join(".zshrc");
+        let backup = temp.path().join(".zshrc.bak");
+        fs::write(&file, "mine").unwrap();
+
+        let mut journal = Journal::new(&repo);
+        journal.backup(&file, &backup).unwrap();
+        unix_fs::symlink("/repo/.zshrc", &file).unwrap();
+        journal.record_created(&file).unwrap();
+
+        journal.rollback().unwrap();
+        assert!(!file.is_symlink());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "mine");
+        assert!(!backup.exists());
+    }
+
+    #[test]
+    fn test_load_pending() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let target = temp.path().join("file");
+
+        assert!(Journal::load_pending(&repo).unwrap().is_none());
+
+        let mut journal = Journal::new(&repo);
+        fs::write(&target, "x").unwrap();
+        journal.record_created(&target).unwrap();
+
+        let pending = Journal::load_pending(&repo).unwrap().unwrap();
+        assert_eq!(pending.ops(), journal.ops());
+        pending.rollback().unwrap();
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn test_commit_records_generation() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let manifest = temp.path().join("manifest.toml");
+        let target = temp.path().join("file");
+
+        let mut journal = Journal::new(&repo);
+        fs::write(&target, "x").unwrap();
+        journal.record_created(&target).unwrap();
+        assert_eq!(journal.commit(&repo, &manifest, "run").unwrap(), Some(1));
+
+        assert!(target.exists());
+        assert!(Journal::load_pending(&repo).unwrap().is_none());
+        assert_eq!(list_generations(&repo).unwrap(), vec![1]);
+
+        let generation = load_generation(&repo, 1).unwrap();
+        assert!(generation.created_at().is_some());
+        generation.rollback().unwrap();
+        assert!(!target.exists());
+        assert!(list_generations(&repo).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_commit_empty_journal_records_nothing() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let manifest = temp.path().join("manifest.toml");
+
+        let journal = Journal::new(&repo);
+        assert_eq!(journal.commit(&repo, &manifest, "run").unwrap(), None);
+        assert!(list_generations(&repo).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_empty_commit_keeps_pending_journal() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let manifest = temp.path().join("manifest.toml");
+        let file = temp.path().join("settings.json");
+        fs::write(&file, "original").unwrap();
+
+        // A run killed after stashing the user's file
+        let mut interrupted = Journal::new(&repo);
+        interrupted.remove(&file).unwrap();
+        drop(interrupted);
+
+        assert_eq!(Journal::new(&repo).commit(&repo, &manifest, "run").unwrap(), None);
+
+        let pending = Journal::load_pending(&repo).unwrap().unwrap();
+        pending.rollback().unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "original");
+    }
+
+    #[test]
+    fn test_generation_stash_survives_commit() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let manifest = temp.path().join("manifest.toml");
+        let file = temp.path().join("settings.json");
+        fs::write(&file, "old").unwrap();
+
+        let mut journal = Journal::new(&repo);
+        journal.remove(&file).unwrap();
+        fs::write(&file, "new").unwrap();
+        journal.record_created(&file).unwrap();
+        journal.commit(&repo, &manifest, "run").unwrap();
+
+        load_generation(&repo, 1).unwrap().rollback().unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_rollback_keeps_edited_file_and_resumes() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let manifest = temp.path().join("manifest.toml");
+        let file = temp.path().join("settings.json");
+        fs::write(&file, "old").unwrap();
+
+        let mut journal = Journal::new(&repo);
+        journal.remove(&file).unwrap();
+        fs::write(&file, "new").unwrap();
+        journal.record_created(&file).unwrap();
+        journal.commit(&repo, &manifest, "run").unwrap();
+
+        fs::write(&file, "edited").unwrap();
+        assert!(rollback_generation(&repo, 1).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "edited");
+
+        // Nothing was undone, so the whole generation is still recorded
+        assert_eq!(load_generation(&repo, 1).unwrap().ops().len(), 2);
+
+        fs::remove_file(&file).unwrap();
+        assert_eq!(rollback_generation(&repo, 1).unwrap(), 2);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_rollback_saves_progress() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let first = temp.path().join("first");
+        let second = temp.path().join("second");
+
+        let mut journal = Journal::new(&repo);
+        fs::write(&first, "x").unwrap();
+        journal.record_created(&first).unwrap();
+        fs::write(&second, "y").unwrap();
+        journal.record_created(&second).unwrap();
+
+        fs::write(&first, "edited").unwrap();
+        assert!(journal.rollback().is_err());
+        assert!(!second.exists());
+
+        let pending = Journal::load_pending(&repo).unwrap().unwrap();
+        assert_eq!(pending.ops().len(), 1);
+    }
+
+    #[test]
+    fn test_prune_generations() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        let manifest = temp.path().join("manifest.toml");
+
+        for i in 0..4 {
+            let mut journal = Journal::new(&repo);
+            let file = temp.path().join(i.to_string());
+            fs::write(&file, "x").unwrap();
+            journal.record_created(&file).unwrap();
+            journal.commit(&repo, &manifest, "run").unwrap();
+        }
+
+        prune_generations(&repo, 0).unwrap();
+        assert_eq!(list_generations(&repo).unwrap(), vec![1, 2, 3, 4]);
+
+        prune_generations(&repo, 2).unwrap();
+        assert_eq!(list_generations(&repo).unwrap(), vec![3, 4]);
+    }
+}
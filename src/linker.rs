@@ -1,13 +1,21 @@
 use anyhow::{Context, Result};
 use chrono::Local;
 use colored::Colorize;
+use serde::Serialize;
+use std::cell::OnceCell;
 use std::fs;
 use std::os::unix::fs as unix_fs;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
-use crate::config::GlobalConfig;
-use crate::manifest::ManifestEntry;
+use crate::block;
+use crate::config::{repos_dir, GlobalConfig};
+use crate::journal::Journal;
+use crate::manifest::{ManifestEntry, ManifestRecord};
 use crate::repo::RepoItem;
+use crate::secret::{self, Identities};
+use crate::strategy::Strategy;
+use crate::sync;
 use crate::template::TemplateEngine;
 use crate::vars::VarResolver;
 
@@ -17,9 +25,11 @@ pub struct LinkOptions {
     pub force: bool,
     pub verbose: bool,
     pub no_fetch: bool,
+    pub no_prune: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
 pub enum LinkResult {
     Created { entry: ManifestEntry },
     AlreadyCorrect { entry: ManifestEntry },
@@ -32,27 +42,53 @@ pub struct Linker {
     config: GlobalConfig,
     template_engine: TemplateEngine,
     replaceable_paths: Vec<PathBuf>,
+    identities: OnceCell<Identities>,
 }
 
 impl Linker {
     pub fn new(config: GlobalConfig) -> Self {
-        let replaceable_paths = config.expanded_replaceable_paths();
+        let mut replaceable_paths = config.expanded_replaceable_paths();
+        // Symlinks into other repos were placed by homie; collisions decide who owns them
+        if let Ok(repos) = repos_dir() {
+            replaceable_paths.push(repos);
+        }
         Self {
+            template_engine: TemplateEngine::for_config(&config),
             config,
-            template_engine: TemplateEngine::new(),
             replaceable_paths,
+            identities: OnceCell::new(),
         }
     }
 
+    pub fn template_engine(&self) -> &TemplateEngine {
+        &self.template_engine
+    }
+
     pub fn link_item(
         &self,
         item: &RepoItem,
         var_resolver: &VarResolver,
         repo_path: &Path,
         options: LinkOptions,
+        journal: &mut Journal,
     ) -> Result<LinkResult> {
+        if item.strategy == Strategy::Block && !item.is_secret {
+            return self.merge_block(item, var_resolver, repo_path, options, journal);
+        }
+
+        // Never spread an unfinished `pull-back --merge` to the target
+        if (item.is_template || item.strategy.is_copy()) && sync::has_conflict_markers(&item.source) {
+            return Ok(LinkResult::Skipped {
+                reason: "unresolved conflict markers in the repo file".to_string(),
+            });
+        }
+
         if item.is_template {
-            return self.render_template(item, var_resolver, options);
+            return self.render_template(item, var_resolver, options, journal);
+        }
+
+        if item.is_secret {
+            return self.decrypt_secret(item, options, journal);
         }
 
         if item.source.is_symlink() && !item.source.exists() {
@@ -62,9 +98,9 @@ impl Linker {
         }
 
         if item.strategy.is_copy() {
-            self.copy_item(item, options)
+            self.copy_item(item, options, journal)
         } else {
-            self.symlink_item(item, repo_path, options)
+            self.symlink_item(item, repo_path, options, journal)
         }
     }
 
@@ -73,19 +109,22 @@ impl Linker {
         item: &RepoItem,
         repo_path: &Path,
         options: LinkOptions,
+        journal: &mut Journal,
     ) -> Result<LinkResult> {
         let target_state = classify_target(&item.target, repo_path, &self.replaceable_paths)?;
 
         match target_state {
             TargetState::NotExists => {
-                self.create_symlink(&item.source, &item.target, options)
+                self.create_symlink(&item.source, &item.target, options, journal)
+            }
+            TargetState::SymlinkToRepo if symlink_points_to(&item.target, &item.source) => {
+                Ok(LinkResult::AlreadyCorrect { entry: ManifestEntry::Symlink })
             }
             TargetState::SymlinkToRepo | TargetState::SymlinkToReplaceable => {
                 if !options.dry_run {
-                    fs::remove_file(&item.target)
-                        .with_context(|| format!("Failed to remove: {}", item.target.display()))?;
+                    journal.remove(&item.target)?;
                 }
-                self.create_symlink(&item.source, &item.target, options)
+                self.create_symlink(&item.source, &item.target, options, journal)
             }
             TargetState::SymlinkToExternal(path) => {
                 Ok(LinkResult::Skipped {
@@ -94,19 +133,17 @@ impl Linker {
             }
             TargetState::BrokenSymlink => {
                 if !options.dry_run {
-                    fs::remove_file(&item.target)
-                        .with_context(|| format!("Failed to remove broken symlink: {}", item.target.display()))?;
+                    journal.remove(&item.target)?;
                 }
-                self.create_symlink(&item.source, &item.target, options)
+                self.create_symlink(&item.source, &item.target, options, journal)
             }
             TargetState::RegularFile | TargetState::Directory => {
                 if options.force {
                     let backup_path = self.backup_path(&item.target)?;
                     if !options.dry_run {
-                        fs::rename(&item.target, &backup_path)
-                            .with_context(|| format!("Failed to backup: {}", item.target.display()))?;
+                        journal.backup(&item.target, &backup_path)?;
                     }
-                    self.create_symlink(&item.source, &item.target, options)?;
+                    self.create_symlink(&item.source, &item.target, options, journal)?;
                     Ok(LinkResult::BackedUp { backup_path, entry: ManifestEntry::Symlink })
                 } else {
                     Ok(LinkResult::Skipped {
@@ -117,52 +154,56 @@ impl Linker {
         }
     }
 
-    fn copy_item(&self, item: &RepoItem, options: LinkOptions) -> Result<LinkResult> {
-        if !options.dry_run {
-            if let Some(parent) = item.target.parent() {
-                fs::create_dir_all(parent)
-                    .with_context(|| format!("Failed to create parent dir: {}", parent.display()))?;
-            }
-        }
-
-        if item.target.is_symlink() {
-            if !options.dry_run {
-                fs::remove_file(&item.target)
-                    .with_context(|| format!("Failed to remove existing symlink: {}", item.target.display()))?;
-            }
-        } else if item.target.exists() {
-            if item.source.is_dir() {
-                if !options.dry_run {
-                    fs::remove_dir_all(&item.target)
-                        .with_context(|| format!("Failed to remove existing dir: {}", item.target.display()))?;
-                }
-            } else if files_are_identical(&item.source, &item.target)? {
-                return Ok(LinkResult::AlreadyCorrect { entry: ManifestEntry::Copy });
-            }
+    fn copy_item(
+        &self,
+        item: &RepoItem,
+        options: LinkOptions,
+        journal: &mut Journal,
+    ) -> Result<LinkResult> {
+        if !item.target.is_symlink()
+            && item.target.is_file()
+            && item.source.is_file()
+            && files_are_identical(&item.source, &item.target)?
+        {
+            return Ok(LinkResult::AlreadyCorrect { entry: ManifestEntry::Copy });
         }
 
         if options.dry_run {
             return Ok(LinkResult::Created { entry: ManifestEntry::Copy });
         }
 
+        if let Some(parent) = item.target.parent() {
+            journal.create_dir_all(parent)?;
+        }
+
+        if item.target.exists() || item.target.is_symlink() {
+            journal.remove(&item.target)?;
+        }
+
         if item.source.is_dir() {
             copy_dir_recursive(&item.source, &item.target)?;
         } else {
             copy_file_with_perms(&item.source, &item.target)?;
         }
+        journal.record_created(&item.target)?;
 
         Ok(LinkResult::Created { entry: ManifestEntry::Copy })
     }
 
-    fn create_symlink(&self, source: &Path, target: &Path, options: LinkOptions) -> Result<LinkResult> {
+    fn create_symlink(
+        &self,
+        source: &Path,
+        target: &Path,
+        options: LinkOptions,
+        journal: &mut Journal,
+    ) -> Result<LinkResult> {
         if options.dry_run {
             return Ok(LinkResult::Created { entry: ManifestEntry::Symlink });
         }
 
         // Ensure parent directory exists
         if let Some(parent) = target.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("Failed to create parent dir: {}", parent.display()))?;
+            journal.create_dir_all(parent)?;
         }
 
         // Resolve source to absolute path if it's a relative symlink
@@ -175,6 +216,7 @@ impl Linker {
 
         unix_fs::symlink(&resolved_source, target)
             .with_context(|| format!("Failed to create symlink: {} -> {}", target.display(), resolved_source.display()))?;
+        journal.record_created(target)?;
 
         Ok(LinkResult::Created { entry: ManifestEntry::Symlink })
     }
@@ -184,38 +226,185 @@ impl Linker {
         item: &RepoItem,
         var_resolver: &VarResolver,
         options: LinkOptions,
+        journal: &mut Journal,
     ) -> Result<LinkResult> {
         let vars = var_resolver.to_template_data();
         let rendered = self.template_engine.render_file(&item.source, &vars)?;
 
-        let entry = if item.strategy.is_copy() {
+        let entry = if rendered.has_secrets {
+            ManifestEntry::Secret
+        } else if item.strategy.is_copy() {
             ManifestEntry::Copy
         } else {
             ManifestEntry::Rendered
         };
 
+        if item.target.exists() {
+            let existing = fs::read_to_string(&item.target).unwrap_or_default();
+            if existing == rendered.content && (!rendered.has_secrets || is_private(&item.target)) {
+                return Ok(LinkResult::AlreadyCorrect { entry });
+            }
+        }
+
         if options.dry_run {
             return Ok(LinkResult::Created { entry });
         }
 
         if let Some(parent) = item.target.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("Failed to create parent dir: {}", parent.display()))?;
+            journal.create_dir_all(parent)?;
         }
 
-        if item.target.exists() {
-            let existing = fs::read_to_string(&item.target).unwrap_or_default();
-            if existing == rendered {
+        if item.target.exists() || item.target.is_symlink() {
+            journal.remove(&item.target)?;
+        }
+
+        if rendered.has_secrets {
+            secret::write_private(&item.target, rendered.content.as_bytes())?;
+        } else {
+            fs::write(&item.target, &rendered.content)
+                .with_context(|| format!("Failed to write: {}", item.target.display()))?;
+        }
+        journal.record_created(&item.target)?;
+
+        Ok(LinkResult::Created { entry })
+    }
+
+    fn decrypt_secret(
+        &self,
+        item: &RepoItem,
+        options: LinkOptions,
+        journal: &mut Journal,
+    ) -> Result<LinkResult> {
+        let plaintext = self.identities()?.decrypt_file(&item.source)?;
+        let entry = ManifestEntry::Secret;
+
+        if !item.target.is_symlink() && item.target.is_file() {
+            let existing = fs::read(&item.target).unwrap_or_default();
+            if existing == plaintext && is_private(&item.target) {
                 return Ok(LinkResult::AlreadyCorrect { entry });
             }
         }
 
-        fs::write(&item.target, &rendered)
-            .with_context(|| format!("Failed to write: {}", item.target.display()))?;
+        if options.dry_run {
+            return Ok(LinkResult::Created { entry });
+        }
+
+        if let Some(parent) = item.target.parent() {
+            journal.create_dir_all(parent)?;
+        }
+
+        if item.target.exists() || item.target.is_symlink() {
+            journal.remove(&item.target)?;
+        }
+
+        secret::write_private(&item.target, &plaintext)?;
+        journal.record_created(&item.target)?;
+
+        Ok(LinkResult::Created { entry })
+    }
+
+    /// Insert or update this repo's block in the target, keeping the rest of
+    /// the file as it is
+    fn merge_block(
+        &self,
+        item: &RepoItem,
+        var_resolver: &VarResolver,
+        repo_path: &Path,
+        options: LinkOptions,
+        journal: &mut Journal,
+    ) -> Result<LinkResult> {
+        let entry = ManifestEntry::Block;
+
+        if !item.source.is_file() {
+            return Ok(LinkResult::Skipped {
+                reason: "block source is not a file".to_string(),
+            });
+        }
+        if item.target.is_symlink() || item.target.is_dir() {
+            return Ok(LinkResult::Skipped {
+                reason: "block target is not a regular file".to_string(),
+            });
+        }
+
+        let body = if item.is_template {
+            let vars = var_resolver.to_template_data();
+            let rendered = self.template_engine.render_file(&item.source, &vars)?;
+            if rendered.has_secrets {
+                return Ok(LinkResult::Skipped {
+                    reason: "templates using {{secret}} cannot be blocks".to_string(),
+                });
+            }
+            rendered.content
+        } else {
+            fs::read_to_string(&item.source)
+                .with_context(|| format!("Failed to read: {}", item.source.display()))?
+        };
+
+        let existing = if item.target.exists() {
+            fs::read_to_string(&item.target)
+                .with_context(|| format!("Failed to read: {}", item.target.display()))?
+        } else {
+            String::new()
+        };
+        let updated = block::upsert(&existing, &block::owner(repo_path), &body)
+            .with_context(|| format!("Failed to update block in {}", item.target.display()))?;
+
+        if item.target.exists() && updated == existing {
+            return Ok(LinkResult::AlreadyCorrect { entry });
+        }
+
+        if options.dry_run {
+            return Ok(LinkResult::Created { entry });
+        }
+
+        if let Some(parent) = item.target.parent() {
+            journal.create_dir_all(parent)?;
+        }
+        rewrite_file(&item.target, &updated, Some(journal))?;
 
         Ok(LinkResult::Created { entry })
     }
 
+    /// Take this repo's block out of `target`, leaving the rest of the file
+    fn remove_block(
+        &self,
+        target: &Path,
+        repo_path: &Path,
+        options: LinkOptions,
+        journal: Option<&mut Journal>,
+    ) -> Result<LinkResult> {
+        if target.is_symlink() || !target.is_file() {
+            return Ok(LinkResult::Skipped {
+                reason: "not a regular file".to_string(),
+            });
+        }
+
+        let content = fs::read_to_string(target)
+            .with_context(|| format!("Failed to read: {}", target.display()))?;
+        let Some(updated) = block::remove(&content, &block::owner(repo_path))
+            .with_context(|| format!("Failed to remove block from {}", target.display()))?
+        else {
+            return Ok(LinkResult::Skipped {
+                reason: "no homie block".to_string(),
+            });
+        };
+
+        if !options.dry_run {
+            rewrite_file(target, &updated, journal)?;
+        }
+
+        Ok(LinkResult::Unlinked)
+    }
+
+    fn identities(&self) -> Result<&Identities> {
+        if let Some(identities) = self.identities.get() {
+            return Ok(identities);
+        }
+
+        let identities = Identities::load(&self.config.secrets.identity_path()?)?;
+        Ok(self.identities.get_or_init(|| identities))
+    }
+
     fn backup_path(&self, path: &Path) -> Result<PathBuf> {
         let suffix = Local::now()
             .format(&self.config.settings.backup_suffix)
@@ -232,13 +421,17 @@ impl Linker {
         Ok(backup_path)
     }
 
-    pub fn unlink_item(&self, item: &RepoItem, options: LinkOptions) -> Result<LinkResult> {
+    pub fn unlink_item(&self, item: &RepoItem, repo_path: &Path, options: LinkOptions) -> Result<LinkResult> {
         if !item.target.exists() && !item.target.is_symlink() {
             return Ok(LinkResult::Skipped {
                 reason: "does not exist".to_string(),
             });
         }
 
+        if item.strategy == Strategy::Block && !item.is_secret {
+            return self.remove_block(&item.target, repo_path, options, None);
+        }
+
         if item.target.is_symlink() {
             let link_target = fs::read_link(&item.target)?;
             let resolved = if link_target.is_absolute() {
@@ -247,12 +440,12 @@ impl Linker {
                 item.target.parent().unwrap_or(Path::new("")).join(&link_target)
             };
 
-            if resolved != item.source && !item.is_template {
+            if resolved != item.source && !item.is_template && !item.is_secret {
                 return Ok(LinkResult::Skipped {
                     reason: "symlink points elsewhere".to_string(),
                 });
             }
-        } else if !item.is_template && !item.strategy.is_copy() {
+        } else if !item.is_template && !item.is_secret && !item.strategy.is_copy() {
             return Ok(LinkResult::Skipped {
                 reason: "not a symlink".to_string(),
             });
@@ -269,11 +462,16 @@ impl Linker {
         Ok(LinkResult::Unlinked)
     }
 
+    /// Remove a target recorded in the manifest, or only the repo's block in
+    /// it. With a journal the target is stashed rather than deleted, so the
+    /// run can still be rolled back.
     pub fn unlink_from_manifest(
         &self,
         target: &Path,
         entry: ManifestEntry,
+        repo_path: &Path,
         options: LinkOptions,
+        journal: Option<&mut Journal>,
     ) -> Result<LinkResult> {
         if !target.exists() && !target.is_symlink() {
             return Ok(LinkResult::Skipped {
@@ -281,34 +479,34 @@ impl Linker {
             });
         }
 
-        if !options.dry_run {
-            match entry {
-                ManifestEntry::Symlink => {
-                    if target.is_symlink() {
-                        fs::remove_file(target)?;
-                    } else {
-                        return Ok(LinkResult::Skipped {
-                            reason: "expected symlink, found regular file".to_string(),
-                        });
-                    }
-                }
-                ManifestEntry::Copy => {
-                    if target.is_dir() && !target.is_symlink() {
-                        fs::remove_dir_all(target)?;
-                    } else {
-                        fs::remove_file(target)?;
-                    }
-                }
-                ManifestEntry::Rendered => {
-                    if target.is_file() {
-                        fs::remove_file(target)?;
-                    } else {
-                        return Ok(LinkResult::Skipped {
-                            reason: "expected file, found directory".to_string(),
-                        });
-                    }
-                }
+        if entry == ManifestEntry::Block {
+            return self.remove_block(target, repo_path, options, journal);
+        }
+
+        match entry {
+            ManifestEntry::Symlink if !target.is_symlink() => {
+                return Ok(LinkResult::Skipped {
+                    reason: "expected symlink, found regular file".to_string(),
+                });
+            }
+            ManifestEntry::Rendered | ManifestEntry::Secret if !target.is_file() => {
+                return Ok(LinkResult::Skipped {
+                    reason: "expected file, found directory".to_string(),
+                });
             }
+            _ => {}
+        }
+
+        if options.dry_run {
+            return Ok(LinkResult::Unlinked);
+        }
+
+        if let Some(journal) = journal {
+            journal.remove(target)?;
+        } else if target.is_dir() && !target.is_symlink() {
+            fs::remove_dir_all(target)?;
+        } else {
+            fs::remove_file(target)?;
         }
 
         Ok(LinkResult::Unlinked)
@@ -365,6 +563,88 @@ fn classify_target(target: &Path, repo_path: &Path, replaceable_paths: &[PathBuf
     }
 }
 
+/// Why the target of an orphaned manifest entry should be left in place:
+/// a symlink that no longer points into the repo, or a file edited since
+/// homie wrote it. `None` if it is safe to remove.
+pub fn orphan_blocker(target: &Path, record: &ManifestRecord, repo_path: &Path) -> Option<String> {
+    if record.kind == ManifestEntry::Block {
+        let content = fs::read_to_string(target).ok()?;
+        let body = match block::extract(&content, &block::owner(repo_path)) {
+            Ok(Some(body)) => body,
+            Ok(None) => return Some("no homie block".to_string()),
+            Err(e) => return Some(e.to_string()),
+        };
+        let recorded = record.sha256.as_ref()?;
+        return (sync::hash_bytes(body.as_bytes()) != *recorded)
+            .then(|| "block modified since last link".to_string());
+    }
+
+    if target.is_symlink() {
+        if record.kind != ManifestEntry::Symlink {
+            return None;
+        }
+        let link_target = fs::read_link(target).ok()?;
+        let resolved = if link_target.is_absolute() {
+            link_target
+        } else {
+            target.parent().unwrap_or(Path::new("")).join(&link_target)
+        };
+        let ours = resolved.starts_with(repo_path) || record.source.as_ref() == Some(&resolved);
+        return (!ours).then(|| format!("symlink points elsewhere: {}", resolved.display()));
+    }
+
+    let recorded = record.sha256.as_ref()?;
+    let current = if target.is_dir() {
+        sync::hash_dir(target).ok()?
+    } else {
+        sync::hash_bytes(&fs::read(target).ok()?)
+    };
+    (current != *recorded).then(|| "modified since last link".to_string())
+}
+
+/// Write `content` over an existing file, keeping its permissions. With a
+/// journal the old file is stashed first so it can be restored.
+fn rewrite_file(target: &Path, content: &str, journal: Option<&mut Journal>) -> Result<()> {
+    let perms = fs::metadata(target).ok().map(|m| m.permissions());
+
+    if let Some(journal) = journal {
+        if target.exists() {
+            journal.remove(target)?;
+        }
+        fs::write(target, content)
+            .with_context(|| format!("Failed to write: {}", target.display()))?;
+        journal.record_created(target)?;
+    } else {
+        fs::write(target, content)
+            .with_context(|| format!("Failed to write: {}", target.display()))?;
+    }
+
+    if let Some(perms) = perms {
+        fs::set_permissions(target, perms)
+            .with_context(|| format!("Failed to set permissions: {}", target.display()))?;
+    }
+    Ok(())
+}
+
+fn symlink_points_to(link: &Path, source: &Path) -> bool {
+    let Ok(link_target) = fs::read_link(link) else {
+        return false;
+    };
+
+    let expected = if source.is_symlink() {
+        source.canonicalize().unwrap_or_else(|_| source.to_path_buf())
+    } else {
+        source.to_path_buf()
+    };
+
+    link_target == expected
+}
+
+/// Whether a file is readable by its owner only (0600)
+fn is_private(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.permissions().mode() & 0o777 == 0o600)
+}
+
 fn files_are_identical(source: &Path, target: &Path) -> Result<bool> {
     let source_content = fs::read(source)
         .with_context(|| format!("Failed to read source: {}", source.display()))?;
@@ -386,7 +666,7 @@ fn copy_file_with_perms(source: &Path, target: &Path) -> Result<()> {
     Ok(())
 }
 
-fn copy_dir_recursive(source: &Path, target: &Path) -> Result<()> {
+pub fn copy_dir_recursive(source: &Path, target: &Path) -> Result<()> {
     fs::create_dir_all(target)
         .with_context(|| format!("Failed to create directory: {}", target.display()))?;
 
@@ -420,6 +700,8 @@ pub fn print_result(relative_path: &str, result: &LinkResult, verbose: bool) {
                 ManifestEntry::Symlink => "",
                 ManifestEntry::Copy => " (copied)",
                 ManifestEntry::Rendered => " (rendered)",
+                ManifestEntry::Secret => " (secret)",
+                ManifestEntry::Block => " (block)",
             };
             println!("  {} {}{}", "✓".green(), relative_path, suffix);
         }
@@ -448,7 +730,8 @@ pub fn print_result(relative_path: &str, result: &LinkResult, verbose: bool) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::strategy::Strategy;
+    use crate::repo::Repo;
+    use crate::strategy::{Strategy, SyncPolicy};
     use std::os::unix::fs::symlink;
     use tempfile::TempDir;
 
@@ -562,7 +845,7 @@ mod tests {
         let target = temp.path().join("target.txt");
 
         let linker = create_test_linker();
-        let result = linker.create_symlink(&source, &target, default_options()).unwrap();
+        let result = linker.create_symlink(&source, &target, default_options(), &mut Journal::new(temp.path())).unwrap();
 
         assert!(matches!(result, LinkResult::Created { .. }));
         assert!(target.is_symlink());
@@ -577,7 +860,7 @@ mod tests {
         let target = temp.path().join("nested/deep/target.txt");
 
         let linker = create_test_linker();
-        let result = linker.create_symlink(&source, &target, default_options()).unwrap();
+        let result = linker.create_symlink(&source, &target, default_options(), &mut Journal::new(temp.path())).unwrap();
 
         assert!(matches!(result, LinkResult::Created { .. }));
         assert!(target.is_symlink());
@@ -592,7 +875,7 @@ mod tests {
 
         let linker = create_test_linker();
         let options = LinkOptions { dry_run: true, ..default_options() };
-        let result = linker.create_symlink(&source, &target, options).unwrap();
+        let result = linker.create_symlink(&source, &target, options, &mut Journal::new(temp.path())).unwrap();
 
         assert!(matches!(result, LinkResult::Created { .. }));
         assert!(!target.exists()); // Should not actually create
@@ -614,12 +897,14 @@ mod tests {
             target: target.clone(),
             relative_path: "file.txt".to_string(),
             is_template: false,
+            is_secret: false,
             strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
         };
 
         let linker = create_test_linker();
         let var_resolver = VarResolver::new(&GlobalConfig::default(), None);
-        let result = linker.link_item(&item, &var_resolver, &repo_path, default_options()).unwrap();
+        let result = linker.link_item(&item, &var_resolver, &repo_path, default_options(), &mut Journal::new(&repo_path)).unwrap();
 
         assert!(matches!(result, LinkResult::Created { .. }));
         assert!(target.is_symlink());
@@ -641,12 +926,14 @@ mod tests {
             target: target.clone(),
             relative_path: "file.txt".to_string(),
             is_template: false,
+            is_secret: false,
             strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
         };
 
         let linker = create_test_linker();
         let var_resolver = VarResolver::new(&GlobalConfig::default(), None);
-        let result = linker.link_item(&item, &var_resolver, &repo_path, default_options()).unwrap();
+        let result = linker.link_item(&item, &var_resolver, &repo_path, default_options(), &mut Journal::new(&repo_path)).unwrap();
 
         assert!(matches!(result, LinkResult::Skipped { .. }));
         assert!(!target.is_symlink()); // Should remain a regular file
@@ -668,13 +955,15 @@ mod tests {
             target: target.clone(),
             relative_path: "file.txt".to_string(),
             is_template: false,
+            is_secret: false,
             strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
         };
 
         let linker = create_test_linker();
         let var_resolver = VarResolver::new(&GlobalConfig::default(), None);
         let options = LinkOptions { force: true, ..default_options() };
-        let result = linker.link_item(&item, &var_resolver, &repo_path, options).unwrap();
+        let result = linker.link_item(&item, &var_resolver, &repo_path, options, &mut Journal::new(&repo_path)).unwrap();
 
         assert!(matches!(result, LinkResult::BackedUp { .. }));
         assert!(target.is_symlink()); // Should now be a symlink
@@ -696,12 +985,14 @@ mod tests {
             target: target.clone(),
             relative_path: "file.txt".to_string(),
             is_template: false,
+            is_secret: false,
             strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
         };
 
         let linker = create_test_linker();
         let var_resolver = VarResolver::new(&GlobalConfig::default(), None);
-        let result = linker.link_item(&item, &var_resolver, &repo_path, default_options()).unwrap();
+        let result = linker.link_item(&item, &var_resolver, &repo_path, default_options(), &mut Journal::new(&repo_path)).unwrap();
 
         assert!(matches!(result, LinkResult::Created { .. }));
         assert_eq!(fs::read_to_string(&target).unwrap(), "content");
@@ -728,18 +1019,146 @@ mod tests {
             target: target.clone(),
             relative_path: "link.txt".to_string(),
             is_template: false,
+            is_secret: false,
             strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
         };
 
         let linker = create_test_linker();
         let var_resolver = VarResolver::new(&GlobalConfig::default(), None);
-        let result = linker.link_item(&item, &var_resolver, &repo_path, default_options()).unwrap();
+        let result = linker.link_item(&item, &var_resolver, &repo_path, default_options(), &mut Journal::new(&repo_path)).unwrap();
 
         assert!(matches!(result, LinkResult::Skipped { .. }));
         // Should still point to external
         assert_eq!(fs::read_to_string(&target).unwrap(), "external content");
     }
 
+    #[test]
+    fn test_link_item_already_correct_symlink() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        fs::create_dir(&repo_path).unwrap();
+        let source = repo_path.join("file.txt");
+        fs::write(&source, "content").unwrap();
+
+        let target = temp.path().join("file.txt");
+        symlink(&source, &target).unwrap();
+
+        let item = RepoItem {
+            source,
+            target,
+            relative_path: "file.txt".to_string(),
+            is_template: false,
+            is_secret: false,
+            strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let linker = create_test_linker();
+        let var_resolver = VarResolver::new(&GlobalConfig::default(), None);
+        let mut journal = Journal::new(&repo_path);
+        let result = linker.link_item(&item, &var_resolver, &repo_path, default_options(), &mut journal).unwrap();
+
+        assert!(matches!(result, LinkResult::AlreadyCorrect { .. }));
+        assert!(journal.is_empty());
+    }
+
+    #[test]
+    fn test_link_item_rollback_restores_backed_up_file() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        fs::create_dir(&repo_path).unwrap();
+        let source = repo_path.join("file.txt");
+        fs::write(&source, "repo content").unwrap();
+
+        let target = temp.path().join("file.txt");
+        fs::write(&target, "existing content").unwrap();
+
+        let item = RepoItem {
+            source,
+            target: target.clone(),
+            relative_path: "file.txt".to_string(),
+            is_template: false,
+            is_secret: false,
+            strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let linker = create_test_linker();
+        let var_resolver = VarResolver::new(&GlobalConfig::default(), None);
+        let options = LinkOptions { force: true, ..default_options() };
+        let mut journal = Journal::new(&repo_path);
+        linker.link_item(&item, &var_resolver, &repo_path, options, &mut journal).unwrap();
+        assert!(target.is_symlink());
+
+        journal.rollback().unwrap();
+        assert!(!target.is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "existing content");
+    }
+
+    #[test]
+    fn test_copy_item_rollback_restores_previous_copy() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        fs::create_dir(&repo_path).unwrap();
+        let source = repo_path.join("settings.json");
+        fs::write(&source, "new").unwrap();
+
+        let target = temp.path().join("settings.json");
+        fs::write(&target, "old").unwrap();
+
+        let item = RepoItem {
+            source,
+            target: target.clone(),
+            relative_path: "settings.json".to_string(),
+            is_template: false,
+            is_secret: false,
+            strategy: Strategy::Copy,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let linker = create_test_linker();
+        let var_resolver = VarResolver::new(&GlobalConfig::default(), None);
+        let mut journal = Journal::new(&repo_path);
+        linker.link_item(&item, &var_resolver, &repo_path, default_options(), &mut journal).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+
+        journal.rollback().unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_copy_item_skips_unresolved_conflict() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        fs::create_dir(&repo_path).unwrap();
+        let source = repo_path.join("settings");
+        fs::write(&source, "<<<<<<< repo\nrepo\n=======\ntarget\n>>>>>>> target\n").unwrap();
+
+        let target = temp.path().join("settings");
+        fs::write(&target, "target\n").unwrap();
+
+        let item = RepoItem {
+            source,
+            target: target.clone(),
+            relative_path: "settings".to_string(),
+            is_template: false,
+            is_secret: false,
+            strategy: Strategy::Copy,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let linker = create_test_linker();
+        let var_resolver = VarResolver::new(&GlobalConfig::default(), None);
+        let mut journal = Journal::new(&repo_path);
+        let result = linker
+            .link_item(&item, &var_resolver, &repo_path, default_options(), &mut journal)
+            .unwrap();
+
+        assert!(matches!(result, LinkResult::Skipped { .. }));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "target\n");
+    }
+
     // Tests for Linker::unlink_item
 
     #[test]
@@ -755,11 +1174,13 @@ mod tests {
             target: target.clone(),
             relative_path: "target.txt".to_string(),
             is_template: false,
+            is_secret: false,
             strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
         };
 
         let linker = create_test_linker();
-        let result = linker.unlink_item(&item, default_options()).unwrap();
+        let result = linker.unlink_item(&item, temp.path(), default_options()).unwrap();
 
         assert!(matches!(result, LinkResult::Unlinked));
         assert!(!target.exists());
@@ -776,11 +1197,13 @@ mod tests {
             target,
             relative_path: "nonexistent.txt".to_string(),
             is_template: false,
+            is_secret: false,
             strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
         };
 
         let linker = create_test_linker();
-        let result = linker.unlink_item(&item, default_options()).unwrap();
+        let result = linker.unlink_item(&item, temp.path(), default_options()).unwrap();
 
         assert!(matches!(result, LinkResult::Skipped { .. }));
     }
@@ -799,16 +1222,320 @@ mod tests {
             target: target.clone(),
             relative_path: "target.txt".to_string(),
             is_template: false,
+            is_secret: false,
             strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
         };
 
         let linker = create_test_linker();
-        let result = linker.unlink_item(&item, default_options()).unwrap();
+        let result = linker.unlink_item(&item, temp.path(), default_options()).unwrap();
 
         assert!(matches!(result, LinkResult::Skipped { .. }));
         assert!(target.exists()); // Should not remove
     }
 
+    #[test]
+    fn test_link_item_decrypts_secret() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        fs::create_dir(&repo_path).unwrap();
+
+        let identity_path = temp.path().join("identity.txt");
+        secret::generate_identity(&identity_path).unwrap();
+        let mut config = GlobalConfig::default();
+        config.secrets.identity = Some(identity_path.to_string_lossy().to_string());
+
+        let recipients = secret::recipients(&config.secrets).unwrap();
+        let source = repo_path.join("token.age");
+        fs::write(&source, secret::encrypt(b"hunter2", &recipients).unwrap()).unwrap();
+
+        let target = temp.path().join("token");
+        let item = RepoItem {
+            source,
+            target: target.clone(),
+            relative_path: "token.age".to_string(),
+            is_template: false,
+            is_secret: true,
+            strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let linker = Linker::new(config.clone());
+        let var_resolver = VarResolver::new(&config, None);
+        let mut journal = Journal::new(&repo_path);
+        let result = linker
+            .link_item(&item, &var_resolver, &repo_path, default_options(), &mut journal)
+            .unwrap();
+
+        assert!(matches!(result, LinkResult::Created { entry: ManifestEntry::Secret }));
+        assert!(!target.is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "hunter2");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let result = linker
+            .link_item(&item, &var_resolver, &repo_path, default_options(), &mut journal)
+            .unwrap();
+        assert!(matches!(result, LinkResult::AlreadyCorrect { .. }));
+    }
+
+    #[test]
+    fn test_render_template_with_secret_is_private() {
+        let temp = TempDir::new().unwrap();
+        let source = temp.path().join("netrc.tmpl");
+        fs::write(&source, "password {{secret \"netrc\"}}\n").unwrap();
+
+        let mut config = GlobalConfig::default();
+        config.secrets.provider = Some("echo hunter2 #".to_string());
+
+        let target = temp.path().join("netrc");
+        let item = RepoItem {
+            source,
+            target: target.clone(),
+            relative_path: "netrc.tmpl".to_string(),
+            is_template: true,
+            is_secret: false,
+            strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let linker = Linker::new(config.clone());
+        let var_resolver = VarResolver::new(&config, None);
+        let mut journal = Journal::new(temp.path());
+        let result = linker
+            .link_item(&item, &var_resolver, temp.path(), default_options(), &mut journal)
+            .unwrap();
+
+        assert!(matches!(result, LinkResult::Created { entry: ManifestEntry::Secret }));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "password hunter2\n");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    /// Link `hub.tmpl` (`template`) from a repo that also holds `file`, a
+    /// file calling `{{secret}}`, and return the target
+    fn link_secret_reaching_template(temp: &TempDir, template: &str, file: &str) -> PathBuf {
+        let repo_path = temp.path().join("repo");
+        let file = repo_path.join(file);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "token={{secret \"gh\"}}\n").unwrap();
+        fs::write(repo_path.join("homie.toml"), "target = \"~\"\n").unwrap();
+        let source = repo_path.join("hub.tmpl");
+        fs::write(&source, template).unwrap();
+
+        let mut config = GlobalConfig::default();
+        config.secrets.provider = Some("echo hunter2 #".to_string());
+        let repo = Repo::from_path(&repo_path, &config).unwrap();
+
+        let target = temp.path().join("hub");
+        let item = RepoItem {
+            source,
+            target: target.clone(),
+            relative_path: "hub.tmpl".to_string(),
+            is_template: true,
+            is_secret: false,
+            strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let linker = Linker::new(config.clone());
+        let var_resolver = VarResolver::new(&config, Some(&repo));
+        let mut journal = Journal::new(&repo_path);
+        let result = linker
+            .link_item(&item, &var_resolver, &repo_path, default_options(), &mut journal)
+            .unwrap();
+
+        assert!(matches!(result, LinkResult::Created { entry: ManifestEntry::Secret }));
+        target
+    }
+
+    #[test]
+    fn test_render_template_including_secret_is_private() {
+        let temp = TempDir::new().unwrap();
+        let target = link_secret_reaching_template(&temp, "{{include \"inc/token\"}}", "inc/token");
+
+        assert_eq!(fs::read_to_string(&target).unwrap(), "token=hunter2\n");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_render_template_with_secret_partial_is_private() {
+        let temp = TempDir::new().unwrap();
+        let target = link_secret_reaching_template(&temp, "{{> token}}", "_partials/token");
+
+        assert_eq!(fs::read_to_string(&target).unwrap(), "token=hunter2\n");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_unlink_from_manifest_with_journal_can_roll_back() {
+        let temp = TempDir::new().unwrap();
+        let target = temp.path().join("settings.json");
+        fs::write(&target, "{}").unwrap();
+
+        let linker = create_test_linker();
+        let mut journal = Journal::new(temp.path());
+        let result = linker
+            .unlink_from_manifest(&target, ManifestEntry::Copy, temp.path(), default_options(), Some(&mut journal))
+            .unwrap();
+
+        assert!(matches!(result, LinkResult::Unlinked));
+        assert!(!target.exists());
+
+        journal.rollback().unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_merge_block_and_unlink() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("dotfiles");
+        let source = repo.join(".bashrc");
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(&source, "alias ll='ls -l'\n").unwrap();
+        let target = temp.path().join("home/.bashrc");
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(&target, "# managed by an installer\n").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let item = RepoItem {
+            source,
+            target: target.clone(),
+            relative_path: ".bashrc".to_string(),
+            is_template: false,
+            is_secret: false,
+            strategy: Strategy::Block,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let linker = create_test_linker();
+        let config = GlobalConfig::default();
+        let var_resolver = VarResolver::new(&config, None);
+        let mut journal = Journal::new(&repo);
+        let result = linker
+            .link_item(&item, &var_resolver, &repo, default_options(), &mut journal)
+            .unwrap();
+
+        assert!(matches!(result, LinkResult::Created { entry: ManifestEntry::Block }));
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "# managed by an installer\n# BEGIN homie:dotfiles\nalias ll='ls -l'\n# END homie:dotfiles\n"
+        );
+        assert!(!is_private(&target));
+        assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o777, 0o640);
+
+        let result = linker
+            .link_item(&item, &var_resolver, &repo, default_options(), &mut journal)
+            .unwrap();
+        assert!(matches!(result, LinkResult::AlreadyCorrect { entry: ManifestEntry::Block }));
+
+        fs::write(&target, format!("{}export EDITOR=vi\n", fs::read_to_string(&target).unwrap())).unwrap();
+        let result = linker.unlink_item(&item, &repo, default_options()).unwrap();
+        assert!(matches!(result, LinkResult::Unlinked));
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "# managed by an installer\nexport EDITOR=vi\n"
+        );
+
+        // Edited since the block was merged, so rollback leaves it alone
+        assert!(journal.rollback().is_err());
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "# managed by an installer\nexport EDITOR=vi\n"
+        );
+    }
+
+    #[test]
+    fn test_orphan_blocker() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path().join("repo");
+        fs::create_dir_all(&repo).unwrap();
+
+        let link = temp.path().join("zshrc");
+        symlink(repo.join("zshrc"), &link).unwrap();
+        let record = ManifestRecord::new(ManifestEntry::Symlink);
+        assert_eq!(orphan_blocker(&link, &record, &repo), None);
+
+        fs::remove_file(&link).unwrap();
+        symlink("/etc/zshrc", &link).unwrap();
+        assert_eq!(
+            orphan_blocker(&link, &record, &repo).as_deref(),
+            Some("symlink points elsewhere: /etc/zshrc")
+        );
+
+        let copy = temp.path().join("settings.json");
+        fs::write(&copy, "{}").unwrap();
+        let mut record = ManifestRecord::new(ManifestEntry::Copy);
+        record.sha256 = Some(sync::hash_bytes(b"{}"));
+        assert_eq!(orphan_blocker(&copy, &record, &repo), None);
+
+        fs::write(&copy, "{\"edited\": true}").unwrap();
+        assert_eq!(
+            orphan_blocker(&copy, &record, &repo).as_deref(),
+            Some("modified since last link")
+        );
+    }
+
+    #[test]
+    fn test_render_template_dry_run_reports_unchanged() {
+        let temp = TempDir::new().unwrap();
+        let source = temp.path().join("gitconfig.tmpl");
+        fs::write(&source, "editor = {{#if true}}nvim{{/if}}\n").unwrap();
+
+        let target = temp.path().join("gitconfig");
+        fs::write(&target, "editor = nvim\n").unwrap();
+        let item = RepoItem {
+            source,
+            target,
+            relative_path: "gitconfig.tmpl".to_string(),
+            is_template: true,
+            is_secret: false,
+            strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let config = GlobalConfig::default();
+        let linker = Linker::new(config.clone());
+        let var_resolver = VarResolver::new(&config, None);
+        let mut journal = Journal::new(temp.path());
+        let options = LinkOptions { dry_run: true, ..default_options() };
+        let result = linker
+            .link_item(&item, &var_resolver, temp.path(), options, &mut journal)
+            .unwrap();
+
+        assert!(matches!(result, LinkResult::AlreadyCorrect { entry: ManifestEntry::Rendered }));
+    }
+
+    #[test]
+    fn test_link_item_secret_without_identity_fails() {
+        let temp = TempDir::new().unwrap();
+        let source = temp.path().join("token.age");
+        fs::write(&source, "not really encrypted").unwrap();
+
+        let mut config = GlobalConfig::default();
+        config.secrets.identity = Some(temp.path().join("missing.txt").to_string_lossy().to_string());
+
+        let item = RepoItem {
+            source,
+            target: temp.path().join("token"),
+            relative_path: "token.age".to_string(),
+            is_template: false,
+            is_secret: true,
+            strategy: Strategy::File,
+            sync: SyncPolicy::Overwrite,
+        };
+
+        let linker = Linker::new(config.clone());
+        let var_resolver = VarResolver::new(&config, None);
+        let mut journal = Journal::new(temp.path());
+        let result = linker.link_item(&item, &var_resolver, temp.path(), default_options(), &mut journal);
+
+        assert!(result.is_err());
+        assert!(!temp.path().join("token").exists());
+    }
+
     // Tests for backup_path
 
     #[test]
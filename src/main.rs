@@ -1,24 +1,42 @@
+mod block;
+mod check;
+mod collision;
 mod commands;
 mod config;
+mod diff;
+mod helpers;
+mod hooks;
 mod import;
+mod journal;
 mod linker;
 mod manifest;
+mod output;
+mod plan;
 mod repo;
+mod scripts;
+mod secret;
 mod status;
 mod strategy;
+mod sync;
 mod template;
 mod vars;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
+use crate::commands::diff::DiffFormat;
+use crate::commands::Outcome;
 use crate::config::GlobalConfig;
 use crate::linker::LinkOptions;
+use crate::output::{Output, OutputFormat};
 
 #[derive(Parser)]
 #[command(name = "homie")]
 #[command(about = "Dotfiles symlink orchestrator with templates and multiple repo support")]
 #[command(version)]
+#[command(after_help = "Exit codes:\n  0  success, nothing to report\n  1  error\n  2  drift: missing, conflicting, stale, modified or orphaned items (status), changes planned (plan)\n  3  some items were skipped (link, apply, pull-back)")]
 struct Cli {
     /// Show what would happen without making changes
     #[arg(short = 'n', long, global = true)]
@@ -28,6 +46,14 @@ struct Cli {
     #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// Output format for status, link, plan, apply, diff and list
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Machine profile to use (default: matched by hostname)
+    #[arg(long, global = true, env = "HOMIE_PROFILE")]
+    profile: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -46,6 +72,10 @@ enum Commands {
         /// Skip fetching git imports
         #[arg(long)]
         no_fetch: bool,
+
+        /// Keep targets of items that were removed from the repo
+        #[arg(long)]
+        no_prune: bool,
     },
 
     /// Remove symlinks for one or all repos
@@ -58,6 +88,10 @@ enum Commands {
     Status {
         /// Repo name (optional, shows all if not specified)
         repo: Option<String>,
+
+        /// Print nothing; only report drift through the exit code
+        #[arg(long)]
+        check: bool,
     },
 
     /// Add a file to a repo (move + symlink)
@@ -69,10 +103,16 @@ enum Commands {
         file: String,
     },
 
-    /// Show differences between repo and target
+    /// Show differences between what link would place and what is on disk
     Diff {
         /// Repo name (optional, shows all if not specified)
         repo: Option<String>,
+        /// Show changed lines per file instead of the diff
+        #[arg(long, conflicts_with = "name_only")]
+        stat: bool,
+        /// Show only the paths of changed files
+        #[arg(long)]
+        name_only: bool,
     },
 
     /// Initialize a new repo
@@ -97,21 +137,162 @@ enum Commands {
 
     /// List discovered repos
     List,
+
+    /// Show the operations link would perform, optionally saving them for apply
+    Plan {
+        /// Repo name (optional, plans all if not specified)
+        repo: Option<String>,
+
+        /// Plan to replace conflicts with backup
+        #[arg(short, long)]
+        force: bool,
+
+        /// Leave targets of items removed from the repo alone
+        #[arg(long)]
+        no_prune: bool,
+
+        /// Save the plan to this file
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+
+    /// Apply a saved plan, refusing if the filesystem changed since
+    Apply {
+        /// Plan file written by 'homie plan --out'
+        plan: PathBuf,
+    },
+
+    /// Copy edits made to copied or rendered targets back into the repo
+    PullBack {
+        /// Repo name (optional, checks all if not specified)
+        repo: Option<String>,
+        /// Only this item (repo-relative or target path)
+        path: Option<String>,
+        /// Three-way merge files changed on both sides
+        #[arg(long)]
+        merge: bool,
+    },
+    /// Undo the most recent link run(s), restoring backups
+    Rollback {
+        /// Repo name (optional, rolls back all if not specified)
+        repo: Option<String>,
+
+        /// Roll back every run after generation N (0 undoes all)
+        #[arg(long, value_name = "N", requires = "repo")]
+        to: Option<u32>,
+
+        /// List recorded generations instead of rolling back
+        #[arg(short, long)]
+        list: bool,
+    },
+
+    /// Watch repos and re-link items as their sources change
+    Watch {
+        /// Repo name (optional, watches all if not specified)
+        repo: Option<String>,
+
+        /// Replace conflicts with backup
+        #[arg(short, long)]
+        force: bool,
+
+        /// Keep targets of items that were removed from the repo
+        #[arg(long)]
+        no_prune: bool,
+    },
+
+    /// Manage age-encrypted secret files
+    Secret {
+        #[command(subcommand)]
+        action: SecretAction,
+    },
+    /// Validate and preview templates
+    Template {
+        #[command(subcommand)]
+        action: TemplateAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum SecretAction {
+    /// Generate the identity used to decrypt secrets
+    Keygen,
+
+    /// Encrypt a file to <file>.age
+    Encrypt {
+        /// File to encrypt
+        file: String,
+
+        /// Keep the plaintext file
+        #[arg(long)]
+        keep: bool,
+    },
+
+    /// Decrypt a secret into $EDITOR and re-encrypt it on save
+    Edit {
+        /// Secret to edit (created if it does not exist)
+        file: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum TemplateAction {
+    /// Report undefined vars and parse errors in templates, and unused vars
+    Check {
+        /// Repo name (optional, checks all if not specified)
+        repo: Option<String>,
+    },
+    /// Print a rendered template without touching its target
+    Render {
+        /// Repo the template is in
+        repo: String,
+        /// Template path in the repo (`.tmpl` optional) or its target path
+        path: String,
+        /// Set a var, over every other layer (repeatable; VALUE is TOML or a string)
+        #[arg(long = "var", value_name = "KEY=VALUE")]
+        vars: Vec<String>,
+        /// Render as if on this host: sets `hostname` and selects its profile
+        #[arg(long)]
+        host: Option<String>,
+        /// Render as if on this OS: sets `os`
+        #[arg(long)]
+        os: Option<String>,
+    },
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(outcome) => outcome.exit_code(),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let config = GlobalConfig::load()?;
+fn run(cli: Cli) -> Result<Outcome> {
+    let mut config = GlobalConfig::load()?;
+    // `template render --host` previews another machine, profile included
+    let hostname = match &cli.command {
+        Commands::Template {
+            action: TemplateAction::Render { host: Some(host), .. },
+        } => host.clone(),
+        _ => vars::get_hostname(),
+    };
+    config.activate_profile(cli.profile.as_deref(), &hostname)?;
+    let output = Output::new(cli.format);
+
+    let clean = |result: Result<()>| result.map(|()| Outcome::Clean);
 
     match cli.command {
-        Commands::Link { repo, force, no_fetch } => {
+        Commands::Link { repo, force, no_fetch, no_prune } => {
             let options = LinkOptions {
                 dry_run: cli.dry_run,
                 force,
                 verbose: cli.verbose,
                 no_fetch,
+                no_prune,
             };
-            commands::link::run(&config, repo.as_deref(), options)
+            commands::link::run(&config, repo.as_deref(), options, &output)
         }
 
         Commands::Unlink { repo } => {
@@ -120,24 +301,86 @@ fn main() -> Result<()> {
                 force: false,
                 verbose: cli.verbose,
                 no_fetch: false,
+                no_prune: false,
             };
-            commands::unlink::run(&config, repo.as_deref(), options)
+            clean(commands::unlink::run(&config, repo.as_deref(), options))
         }
 
-        Commands::Status { repo } => commands::status::run(repo.as_deref(), cli.verbose),
+        Commands::Status { repo, check } => {
+            let output = if check { output.quiet() } else { output };
+            commands::status::run(&config, repo.as_deref(), cli.verbose, &output)
+        }
 
-        Commands::Add { repo, file } => commands::add::run(&repo, &file, cli.dry_run),
+        Commands::Add { repo, file } => clean(commands::add::run(&config, &repo, &file, cli.dry_run)),
 
-        Commands::Diff { repo } => commands::diff::run(repo.as_deref()),
+        Commands::Diff { repo, stat, name_only } => {
+            let format = if stat {
+                DiffFormat::Stat
+            } else if name_only {
+                DiffFormat::NameOnly
+            } else {
+                DiffFormat::Patch
+            };
+            clean(commands::diff::run(&config, repo.as_deref(), format, &output))
+        }
 
         Commands::Init { name, target } => {
-            commands::init::run(&name, target.as_deref(), cli.dry_run)
+            clean(commands::init::run(&name, target.as_deref(), cli.dry_run))
         }
 
         Commands::Clone { url, name } => {
-            commands::clone::run(&url, name.as_deref(), cli.dry_run)
+            clean(commands::clone::run(&url, name.as_deref(), cli.dry_run))
+        }
+
+        Commands::List => clean(commands::list::run(&config, &output)),
+
+        Commands::Plan { repo, force, no_prune, out } => commands::plan::run(
+            &config,
+            repo.as_deref(),
+            force,
+            !no_prune,
+            out.as_deref(),
+            &output,
+        ),
+
+        Commands::Apply { plan } => {
+            commands::plan::apply(&config, &plan, cli.verbose, cli.dry_run, &output)
+        }
+
+        Commands::PullBack { repo, path, merge } => commands::pull_back::run(
+            &config,
+            repo.as_deref(),
+            path.as_deref(),
+            merge,
+            cli.dry_run,
+        ),
+        Commands::Rollback { repo, to, list } => {
+            clean(commands::rollback::run(&config, repo.as_deref(), to, list, cli.dry_run))
+        }
+
+        Commands::Watch { repo, force, no_prune } => {
+            let options = LinkOptions {
+                dry_run: cli.dry_run,
+                force,
+                verbose: cli.verbose,
+                no_fetch: true,
+                no_prune,
+            };
+            clean(commands::watch::run(&config, repo.as_deref(), options))
         }
 
-        Commands::List => commands::list::run(),
+        Commands::Secret { action } => clean(match action {
+            SecretAction::Keygen => commands::secret::keygen(&config, cli.dry_run),
+            SecretAction::Encrypt { file, keep } => {
+                commands::secret::encrypt(&config, &file, keep, cli.dry_run)
+            }
+            SecretAction::Edit { file } => commands::secret::edit(&config, &file),
+        }),
+        Commands::Template { action } => clean(match action {
+            TemplateAction::Check { repo } => commands::template::check(&config, repo.as_deref()),
+            TemplateAction::Render { repo, path, vars, host, os } => {
+                commands::template::render(&config, &repo, &path, &vars, host, os)
+            }
+        }),
     }
 }
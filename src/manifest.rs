@@ -1,24 +1,188 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use chrono::Local;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::fs;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
+use crate::repo::RepoItem;
+use crate::strategy::Strategy;
+use crate::sync::{hash_bytes, hash_dir};
+
+/// Current manifest format. Version 1 mapped each path straight to its kind.
+pub const MANIFEST_VERSION: u32 = 2;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ManifestEntry {
     Symlink,
     Copy,
     Rendered,
+    /// A decrypted `.age` source or a template using `{{secret}}`, written 0600
+    Secret,
+    /// A `# BEGIN homie:<repo>` block inside a file homie does not own
+    Block,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// What homie placed for one item. Fields a version 1 manifest did not
+/// record are empty until the item is linked again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestRecord {
+    pub kind: ManifestEntry,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+    /// The import the source came from, if not the repo itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<Strategy>,
+    /// sha256 of the file written, of a copied directory's tree, or of the
+    /// lines inside a block (not recorded for symlinks or secrets)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Permission bits of the written file or directory, stored as octal
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "octal")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linked_at: Option<String>,
+}
+
+impl ManifestRecord {
+    /// A record with only the kind known
+    pub fn new(kind: ManifestEntry) -> Self {
+        Self {
+            kind,
+            target: None,
+            source: None,
+            import: None,
+            strategy: None,
+            sha256: None,
+            mode: None,
+            linked_at: None,
+        }
+    }
+
+    /// Describe what was just placed at `item.target`
+    pub fn for_item(item: &RepoItem, kind: ManifestEntry, import: Option<&str>) -> Result<Self> {
+        let mut record = Self::new(kind);
+        record.target = Some(item.target.clone());
+        record.source = Some(item.source.clone());
+        record.import = import.map(str::to_string);
+        record.strategy = Some(item.strategy);
+        record.linked_at = Some(Local::now().to_rfc3339());
+
+        if kind != ManifestEntry::Symlink && !item.target.is_symlink() {
+            if let Ok(metadata) = fs::metadata(&item.target) {
+                record.mode = Some(metadata.permissions().mode() & 0o7777);
+                if metadata.is_file() && kind != ManifestEntry::Secret {
+                    let content = fs::read(&item.target).with_context(|| {
+                        format!("Failed to read: {}", item.target.display())
+                    })?;
+                    record.sha256 = Some(hash_bytes(&content));
+                } else if metadata.is_dir() && kind == ManifestEntry::Copy {
+                    record.sha256 = Some(hash_dir(&item.target)?);
+                }
+            }
+        }
+
+        Ok(record)
+    }
+}
+
+/// `mode = "0644"` rather than `mode = 420`
+mod octal {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+        match mode {
+            Some(mode) => serializer.serialize_str(&format!("{:04o}", mode)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+        let Some(mode) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        u32::from_str_radix(&mode, 8)
+            .map(Some)
+            .map_err(|_| D::Error::custom(format!("invalid mode: {}", mode)))
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct Manifest {
+    pub version: u32,
+    pub files: BTreeMap<String, ManifestRecord>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+/// Any manifest version as found on disk
+#[derive(Deserialize)]
+struct StoredManifest {
+    version: Option<u32>,
+    #[serde(default)]
+    files: BTreeMap<String, StoredEntry>,
+    /// Version 1 kept hashes in a separate table
     #[serde(default)]
-    pub files: BTreeMap<String, ManifestEntry>,
+    hashes: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredEntry {
+    Kind(ManifestEntry),
+    Record(ManifestRecord),
+}
+
+impl StoredManifest {
+    fn migrate(self) -> Result<Manifest> {
+        let version = self.version.unwrap_or(1);
+        if version > MANIFEST_VERSION {
+            bail!(
+                "Manifest version {} is newer than this homie supports ({})",
+                version,
+                MANIFEST_VERSION
+            );
+        }
+
+        let mut hashes = self.hashes;
+        let files = self
+            .files
+            .into_iter()
+            .map(|(path, entry)| {
+                let record = match entry {
+                    StoredEntry::Record(record) => record,
+                    StoredEntry::Kind(kind) => {
+                        let mut record = ManifestRecord::new(kind);
+                        record.sha256 = hashes.remove(&path);
+                        record
+                    }
+                };
+                (path, record)
+            })
+            .collect();
+
+        Ok(Manifest {
+            version: MANIFEST_VERSION,
+            files,
+        })
+    }
 }
 
 impl Manifest {
+    /// Load a manifest, migrating older formats (saved in the current one)
     pub fn load(repo_path: &Path) -> Result<Self> {
         let manifest_path = Self::path_for_repo(repo_path);
 
@@ -29,8 +193,11 @@ impl Manifest {
         let content = fs::read_to_string(&manifest_path)
             .with_context(|| format!("Failed to read manifest: {}", manifest_path.display()))?;
 
-        toml::from_str(&content)
-            .with_context(|| format!("Failed to parse manifest: {}", manifest_path.display()))
+        let stored: StoredManifest = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse manifest: {}", manifest_path.display()))?;
+        stored
+            .migrate()
+            .with_context(|| format!("Failed to load manifest: {}", manifest_path.display()))
     }
 
     pub fn save(&self, repo_path: &Path) -> Result<()> {
@@ -61,25 +228,37 @@ impl Manifest {
         repo_path.join(".homie/manifest.toml")
     }
 
-    pub fn insert(&mut self, relative_path: String, entry: ManifestEntry) {
-        self.files.insert(relative_path, entry);
+    pub fn insert(&mut self, relative_path: String, record: ManifestRecord) {
+        self.files.insert(relative_path, record);
     }
 
-    #[allow(dead_code)]
     pub fn remove(&mut self, relative_path: &str) {
         self.files.remove(relative_path);
     }
 
-    #[allow(dead_code)]
-    pub fn get(&self, relative_path: &str) -> Option<ManifestEntry> {
-        self.files.get(relative_path).copied()
+    pub fn get(&self, relative_path: &str) -> Option<&ManifestRecord> {
+        self.files.get(relative_path)
+    }
+
+    pub fn kind(&self, relative_path: &str) -> Option<ManifestEntry> {
+        self.get(relative_path).map(|record| record.kind)
+    }
+
+    pub fn hash(&self, relative_path: &str) -> Option<&str> {
+        self.get(relative_path)?.sha256.as_deref()
+    }
+
+    pub fn set_hash(&mut self, relative_path: &str, hash: String) {
+        if let Some(record) = self.files.get_mut(relative_path) {
+            record.sha256 = Some(hash);
+        }
     }
 
     pub fn is_empty(&self) -> bool {
         self.files.is_empty()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&String, &ManifestEntry)> {
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &ManifestRecord)> {
         self.files.iter()
     }
 }
@@ -100,17 +279,20 @@ mod tests {
     fn test_manifest_save_and_load() {
         let temp = TempDir::new().unwrap();
         let mut manifest = Manifest::default();
-        manifest.insert(".zshrc".to_string(), ManifestEntry::Symlink);
-        manifest.insert(".config/app/settings.json".to_string(), ManifestEntry::Copy);
-        manifest.insert(".gitconfig".to_string(), ManifestEntry::Rendered);
+        manifest.insert(".zshrc".to_string(), ManifestRecord::new(ManifestEntry::Symlink));
+        manifest.insert(
+            ".config/app/settings.json".to_string(),
+            ManifestRecord::new(ManifestEntry::Copy),
+        );
+        manifest.insert(".gitconfig".to_string(), ManifestRecord::new(ManifestEntry::Rendered));
 
         manifest.save(temp.path()).unwrap();
 
         let loaded = Manifest::load(temp.path()).unwrap();
         assert_eq!(loaded.files.len(), 3);
-        assert_eq!(loaded.get(".zshrc"), Some(ManifestEntry::Symlink));
-        assert_eq!(loaded.get(".config/app/settings.json"), Some(ManifestEntry::Copy));
-        assert_eq!(loaded.get(".gitconfig"), Some(ManifestEntry::Rendered));
+        assert_eq!(loaded.kind(".zshrc"), Some(ManifestEntry::Symlink));
+        assert_eq!(loaded.kind(".config/app/settings.json"), Some(ManifestEntry::Copy));
+        assert_eq!(loaded.kind(".gitconfig"), Some(ManifestEntry::Rendered));
     }
 
     #[test]
@@ -126,10 +308,85 @@ mod tests {
     #[test]
     fn test_manifest_remove() {
         let mut manifest = Manifest::default();
-        manifest.insert(".zshrc".to_string(), ManifestEntry::Symlink);
+        manifest.insert(".zshrc".to_string(), ManifestRecord::new(ManifestEntry::Symlink));
         assert!(manifest.get(".zshrc").is_some());
 
         manifest.remove(".zshrc");
         assert!(manifest.get(".zshrc").is_none());
     }
+
+    #[test]
+    fn test_manifest_migrates_version_1() {
+        let temp = TempDir::new().unwrap();
+        fs::create_dir_all(temp.path().join(".homie")).unwrap();
+        fs::write(
+            Manifest::path_for_repo(temp.path()),
+            r#"
+[files]
+".zshrc" = "symlink"
+".config/app/settings.json" = "copy"
+
+[hashes]
+".config/app/settings.json" = "abc"
+"#,
+        )
+        .unwrap();
+
+        let manifest = Manifest::load(temp.path()).unwrap();
+        assert_eq!(manifest.version, MANIFEST_VERSION);
+        assert_eq!(manifest.kind(".zshrc"), Some(ManifestEntry::Symlink));
+        assert_eq!(manifest.hash(".config/app/settings.json"), Some("abc"));
+        assert!(manifest.get(".zshrc").unwrap().target.is_none());
+
+        manifest.save(temp.path()).unwrap();
+        let content = fs::read_to_string(Manifest::path_for_repo(temp.path())).unwrap();
+        assert!(content.contains("version = 2"));
+        assert!(!content.contains("[hashes]"));
+        assert_eq!(Manifest::load(temp.path()).unwrap().files, manifest.files);
+    }
+
+    #[test]
+    fn test_manifest_rejects_newer_version() {
+        let temp = TempDir::new().unwrap();
+        fs::create_dir_all(temp.path().join(".homie")).unwrap();
+        fs::write(Manifest::path_for_repo(temp.path()), "version = 99\n").unwrap();
+
+        let err = Manifest::load(temp.path()).unwrap_err();
+        assert!(format!("{:#}", err).contains("newer than this homie supports"));
+    }
+
+    #[test]
+    fn test_record_for_item() {
+        let temp = TempDir::new().unwrap();
+        let target = temp.path().join("settings.json");
+        fs::write(&target, "{}").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+        let item = RepoItem {
+            source: temp.path().join("repo/settings.json"),
+            target: target.clone(),
+            relative_path: "settings.json".to_string(),
+            is_template: false,
+            is_secret: false,
+            strategy: Strategy::Copy,
+            sync: crate::strategy::SyncPolicy::Overwrite,
+        };
+
+        let record = ManifestRecord::for_item(&item, ManifestEntry::Copy, Some("work")).unwrap();
+        let mut manifest = Manifest::default();
+        manifest.insert("settings.json".to_string(), record.clone());
+        manifest.save(temp.path()).unwrap();
+        let content = fs::read_to_string(Manifest::path_for_repo(temp.path())).unwrap();
+        assert!(content.contains("mode = \"0640\""));
+        assert_eq!(Manifest::load(temp.path()).unwrap().get("settings.json"), Some(&record));
+
+        assert_eq!(record.target, Some(target));
+        assert_eq!(record.import.as_deref(), Some("work"));
+        assert_eq!(record.strategy, Some(Strategy::Copy));
+        assert_eq!(record.sha256, Some(hash_bytes(b"{}")));
+        assert_eq!(record.mode, Some(0o640));
+        assert!(record.linked_at.is_some());
+
+        let secret = ManifestRecord::for_item(&item, ManifestEntry::Secret, None).unwrap();
+        assert!(secret.sha256.is_none());
+    }
 }
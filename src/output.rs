@@ -0,0 +1,142 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::path::Path;
+
+use crate::repo::RepoItem;
+use crate::strategy::Strategy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable, colored output
+    #[default]
+    Text,
+    /// A single JSON array of records, printed when the command finishes
+    Json,
+    /// One JSON record per line, printed as soon as it is known
+    Ndjson,
+}
+
+/// Where command results go. In text mode commands print as before and
+/// records are ignored; in JSON modes only records are printed.
+pub struct Output {
+    format: OutputFormat,
+    quiet: bool,
+    records: RefCell<Vec<serde_json::Value>>,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            quiet: false,
+            records: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Suppress text output (records are still emitted in JSON modes)
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text && !self.quiet
+    }
+
+    pub fn emit<T: Serialize>(&self, record: &T) -> Result<()> {
+        match self.format {
+            OutputFormat::Text => {}
+            OutputFormat::Json => {
+                let value = serde_json::to_value(record).context("Failed to serialize record")?;
+                self.records.borrow_mut().push(value);
+            }
+            OutputFormat::Ndjson => {
+                let line = serde_json::to_string(record).context("Failed to serialize record")?;
+                println!("{}", line);
+            }
+        }
+        Ok(())
+    }
+
+    /// Print buffered records. Safe to call more than once.
+    pub fn finish(&self) -> Result<()> {
+        if self.format == OutputFormat::Json {
+            let records = self.records.take();
+            let json = serde_json::to_string_pretty(&records).context("Failed to serialize output")?;
+            println!("{}", json);
+        }
+        Ok(())
+    }
+}
+
+/// One repo item, with command-specific state flattened in
+/// (`LinkResult`, `ItemStatus`, ...)
+#[derive(Debug, Serialize)]
+pub struct ItemRecord<'a, S: Serialize> {
+    pub repo: &'a str,
+    pub path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<&'a Path>,
+    pub target: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<Strategy>,
+    #[serde(flatten)]
+    pub state: S,
+}
+
+impl<'a, S: Serialize> ItemRecord<'a, S> {
+    pub fn for_item(repo: &'a str, item: &'a RepoItem, state: S) -> Self {
+        Self {
+            repo,
+            path: &item.relative_path,
+            source: Some(&item.source),
+            target: &item.target,
+            strategy: Some(item.strategy),
+            state,
+        }
+    }
+}
+
+/// State for an item whose operation failed
+#[derive(Debug, Serialize)]
+pub struct ErrorState {
+    pub result: &'static str,
+    pub reason: String,
+}
+
+impl ErrorState {
+    pub fn new(reason: String) -> Self {
+        Self {
+            result: "error",
+            reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_item_record_flattens_state() {
+        let target = PathBuf::from("/home/user/.zshrc");
+        let record = ItemRecord {
+            repo: "dotfiles",
+            path: ".zshrc",
+            source: None,
+            target: &target,
+            strategy: Some(Strategy::File),
+            state: ErrorState::new("boom".to_string()),
+        };
+
+        let value = serde_json::to_value(&record).unwrap();
+        assert_eq!(value["repo"], "dotfiles");
+        assert_eq!(value["strategy"], "file");
+        assert_eq!(value["result"], "error");
+        assert_eq!(value["reason"], "boom");
+        assert!(value.get("source").is_none());
+    }
+}
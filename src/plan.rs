@@ -0,0 +1,396 @@
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::collision::Resolution;
+use crate::journal::Journal;
+use crate::linker::{orphan_blocker, LinkOptions, LinkResult, Linker};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::repo::{Repo, RepoItem};
+use crate::strategy::SyncPolicy;
+use crate::sync::{self, fingerprint, Fingerprint, PullOutcome};
+use crate::template;
+use crate::vars::VarResolver;
+
+/// Bumped when the plan file format changes incompatibly
+pub const PLAN_VERSION: u32 = 1;
+
+/// The operations `link` would perform, saved for review and `homie apply`.
+///
+/// Every operation records what its target and source looked like when the
+/// plan was made, so a plan is only applied to the filesystem it describes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    pub created_at: String,
+    /// Planned with `--force`: conflicting files are backed up and replaced
+    pub force: bool,
+    #[serde(default)]
+    pub repos: Vec<RepoPlan>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepoPlan {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub ops: Vec<PlannedOp>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    CreateSymlink,
+    ReplaceSymlink,
+    BackupReplace,
+    Copy,
+    Render,
+    Decrypt,
+    /// Insert or update the repo's block in a file
+    MergeBlock,
+    RemoveOrphan,
+    /// Left alone; listed so the reason is part of the review
+    Skip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedOp {
+    /// Repo-relative path of the item (or manifest entry for orphans)
+    pub path: String,
+    pub action: Action,
+    pub reason: String,
+    pub target: PathBuf,
+    /// The target when the plan was made
+    pub before: Fingerprint,
+    /// The source when the plan was made (rendered output for templates)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Fingerprint>,
+}
+
+impl Plan {
+    pub fn new(force: bool) -> Self {
+        Self {
+            version: PLAN_VERSION,
+            created_at: Local::now().to_rfc3339(),
+            force,
+            repos: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read plan: {}", path.display()))?;
+        let plan: Plan = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse plan: {}", path.display()))?;
+
+        if plan.version != PLAN_VERSION {
+            bail!(
+                "Plan {} has version {}, this homie applies version {} plans",
+                path.display(),
+                plan.version,
+                PLAN_VERSION
+            );
+        }
+
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string_pretty(self).context("Failed to serialize plan")?;
+        fs::write(path, content).with_context(|| format!("Failed to write plan: {}", path.display()))
+    }
+
+    /// Number of operations that change something
+    pub fn change_count(&self) -> usize {
+        self.repos
+            .iter()
+            .flat_map(|r| &r.ops)
+            .filter(|op| op.action != Action::Skip)
+            .count()
+    }
+}
+
+impl PlannedOp {
+    /// Fail unless the target (and the item's source) still look as they
+    /// did when the plan was made
+    pub fn verify(
+        &self,
+        linker: &Linker,
+        item: Option<&RepoItem>,
+        var_resolver: &VarResolver,
+    ) -> Result<()> {
+        if fingerprint(&self.target)? != self.before {
+            bail!("target changed since planning: {}", self.target.display());
+        }
+
+        let source = item
+            .map(|item| source_fingerprint(linker, item, var_resolver))
+            .transpose()?;
+        if source != self.source {
+            bail!("source changed since planning");
+        }
+
+        Ok(())
+    }
+}
+
+/// Work out what linking `repo` would do, without touching the filesystem.
+/// Items shadowed by a higher-priority repo are left out.
+pub fn plan_repo(
+    linker: &Linker,
+    repo: &Repo,
+    resolution: &Resolution,
+    var_resolver: &VarResolver,
+    force: bool,
+    prune: bool,
+) -> Result<RepoPlan> {
+    let items = repo.items()?;
+    let manifest = Manifest::load(&repo.path)?;
+    let mut ops = Vec::new();
+
+    for item in &items {
+        if resolution.shadowed_by(&repo.name, &item.relative_path).is_some() {
+            continue;
+        }
+        if let Some(op) = plan_item(linker, repo, item, &manifest, var_resolver, force)? {
+            ops.push(op);
+        }
+    }
+    if prune {
+        ops.extend(plan_orphans(repo, &items, &manifest)?);
+    }
+
+    Ok(RepoPlan {
+        name: repo.name.clone(),
+        path: repo.path.clone(),
+        ops,
+    })
+}
+
+/// The operation linking `item` would perform, or `None` if it is already
+/// in place. Decisions come from a dry run of the linker itself.
+pub fn plan_item(
+    linker: &Linker,
+    repo: &Repo,
+    item: &RepoItem,
+    manifest: &Manifest,
+    var_resolver: &VarResolver,
+    force: bool,
+) -> Result<Option<PlannedOp>> {
+    let before = fingerprint(&item.target)?;
+    let source = Some(source_fingerprint(linker, item, var_resolver)?);
+    let op = |action: Action, reason: String| {
+        Some(PlannedOp {
+            path: item.relative_path.clone(),
+            action,
+            reason,
+            target: item.target.clone(),
+            before: before.clone(),
+            source: source.clone(),
+        })
+    };
+
+    if item.sync == SyncPolicy::Pull {
+        let outcome = sync::plan_pull(
+            item,
+            manifest,
+            &repo.path,
+            linker.template_engine(),
+            var_resolver,
+            false,
+        )?;
+        if !matches!(outcome, PullOutcome::Untracked | PullOutcome::Unchanged) {
+            return Ok(op(Action::Skip, "target was edited (run 'homie pull-back')".to_string()));
+        }
+    }
+
+    let options = LinkOptions {
+        dry_run: true,
+        force,
+        ..Default::default()
+    };
+    // Dry runs never record anything
+    let mut journal = Journal::new(&repo.path);
+    let result = linker.link_item(item, var_resolver, &repo.path, options, &mut journal)?;
+
+    let action = match result {
+        LinkResult::AlreadyCorrect { .. } | LinkResult::Unlinked => return Ok(None),
+        LinkResult::Skipped { reason } => return Ok(op(Action::Skip, reason)),
+        LinkResult::BackedUp { .. } => Action::BackupReplace,
+        LinkResult::Created { entry } => match entry {
+            ManifestEntry::Block => Action::MergeBlock,
+            ManifestEntry::Symlink if before == Fingerprint::Missing => Action::CreateSymlink,
+            ManifestEntry::Symlink => Action::ReplaceSymlink,
+            _ if item.is_template => Action::Render,
+            ManifestEntry::Secret => Action::Decrypt,
+            ManifestEntry::Copy | ManifestEntry::Rendered => Action::Copy,
+        },
+    };
+
+    Ok(op(action, describe(action, &before)))
+}
+
+/// Manifest entries whose items are gone but whose targets remain
+fn plan_orphans(repo: &Repo, items: &[RepoItem], manifest: &Manifest) -> Result<Vec<PlannedOp>> {
+    let current: HashSet<&str> = items.iter().map(|i| i.relative_path.as_str()).collect();
+    let mut ops = Vec::new();
+
+    for (path, record) in manifest.iter() {
+        if current.contains(path.as_str()) {
+            continue;
+        }
+
+        let target = repo.manifest_target(path, record);
+        let before = fingerprint(&target)?;
+        if before == Fingerprint::Missing {
+            continue;
+        }
+
+        let (action, reason) = match orphan_blocker(&target, record, &repo.path) {
+            Some(reason) => (Action::Skip, reason),
+            None => (Action::RemoveOrphan, "no longer in repo".to_string()),
+        };
+        ops.push(PlannedOp {
+            path: path.clone(),
+            action,
+            reason,
+            target,
+            before,
+            source: None,
+        });
+    }
+
+    Ok(ops)
+}
+
+/// The source as `link` would see it. Templates are fingerprinted by their
+/// rendered output, except those using `{{secret}}`, and secrets by their
+/// ciphertext, so no plaintext ends up in a plan.
+fn source_fingerprint(
+    linker: &Linker,
+    item: &RepoItem,
+    var_resolver: &VarResolver,
+) -> Result<Fingerprint> {
+    if !item.is_template || !item.source.is_file() {
+        return fingerprint(&item.source);
+    }
+
+    let content = fs::read_to_string(&item.source)
+        .with_context(|| format!("Failed to read template: {}", item.source.display()))?;
+    if template::uses_secrets(&content) {
+        return fingerprint(&item.source);
+    }
+
+    let vars = var_resolver.to_template_data();
+    let rendered = linker
+        .template_engine()
+        .render(&content, &vars)
+        .with_context(|| format!("Failed to render {}", item.relative_path))?;
+    if rendered.has_secrets {
+        return fingerprint(&item.source);
+    }
+    Ok(Fingerprint::File {
+        sha256: sync::hash_bytes(rendered.content.as_bytes()),
+    })
+}
+
+fn describe(action: Action, before: &Fingerprint) -> String {
+    match before {
+        Fingerprint::Missing => "target missing".to_string(),
+        Fingerprint::Symlink { points_to } => format!("replaces symlink to {}", points_to.display()),
+        Fingerprint::File { .. } if action == Action::BackupReplace => "file exists".to_string(),
+        Fingerprint::Dir { .. } if action == Action::BackupReplace => "directory exists".to_string(),
+        Fingerprint::File { .. } if action == Action::MergeBlock => "block differs".to_string(),
+        Fingerprint::File { .. } | Fingerprint::Dir { .. } => "content differs".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_plan_round_trip() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("plan.toml");
+
+        let mut plan = Plan::new(true);
+        plan.repos.push(RepoPlan {
+            name: "dotfiles".to_string(),
+            path: PathBuf::from("/repos/dotfiles"),
+            ops: vec![
+                PlannedOp {
+                    path: ".zshrc".to_string(),
+                    action: Action::BackupReplace,
+                    reason: "file exists".to_string(),
+                    target: PathBuf::from("/home/user/.zshrc"),
+                    before: Fingerprint::File { sha256: "abc".to_string() },
+                    source: Some(Fingerprint::File { sha256: "def".to_string() }),
+                },
+                PlannedOp {
+                    path: ".old".to_string(),
+                    action: Action::RemoveOrphan,
+                    reason: "no longer in repo".to_string(),
+                    target: PathBuf::from("/home/user/.old"),
+                    before: Fingerprint::Symlink { points_to: PathBuf::from("/repos/dotfiles/.old") },
+                    source: None,
+                },
+            ],
+        });
+        plan.save(&path).unwrap();
+
+        let loaded = Plan::load(&path).unwrap();
+        assert!(loaded.force);
+        assert_eq!(loaded.repos[0].ops, plan.repos[0].ops);
+        assert_eq!(loaded.change_count(), 2);
+
+        let content = fs::read_to_string(&path).unwrap().replace("version = 1", "version = 99");
+        fs::write(&path, content).unwrap();
+        assert!(Plan::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_verify_detects_changes() {
+        use crate::config::GlobalConfig;
+        use crate::strategy::Strategy;
+
+        let temp = TempDir::new().unwrap();
+        let config = GlobalConfig::default();
+        let linker = Linker::new(config.clone());
+        let var_resolver = VarResolver::new(&config, None);
+        let item = RepoItem {
+            source: temp.path().join("repo/.zshrc"),
+            target: temp.path().join("home/.zshrc"),
+            relative_path: ".zshrc".to_string(),
+            is_template: false,
+            is_secret: false,
+            strategy: Strategy::Copy,
+            sync: SyncPolicy::Overwrite,
+        };
+        fs::create_dir_all(item.source.parent().unwrap()).unwrap();
+        fs::write(&item.source, "a").unwrap();
+
+        let op = PlannedOp {
+            path: ".zshrc".to_string(),
+            action: Action::Copy,
+            reason: "target missing".to_string(),
+            target: item.target.clone(),
+            before: Fingerprint::Missing,
+            source: Some(fingerprint(&item.source).unwrap()),
+        };
+        assert!(op.verify(&linker, Some(&item), &var_resolver).is_ok());
+
+        fs::write(&item.source, "b").unwrap();
+        assert!(op.verify(&linker, Some(&item), &var_resolver).is_err());
+        fs::write(&item.source, "a").unwrap();
+
+        fs::create_dir_all(item.target.parent().unwrap()).unwrap();
+        fs::write(&item.target, "local").unwrap();
+        let err = op.verify(&linker, Some(&item), &var_resolver).unwrap_err();
+        assert!(err.to_string().contains("target changed"));
+    }
+}
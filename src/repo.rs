@@ -1,12 +1,18 @@
 use anyhow::{bail, Context, Result};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
-use crate::config::{repos_dir, RepoConfig};
+use crate::block;
+use crate::config::{repos_dir, GlobalConfig, RepoConfig};
 use crate::import::{resolve_import, Import};
-use crate::strategy::Strategy;
+use crate::manifest::{ManifestEntry, ManifestRecord};
+use crate::secret::{is_secret_path, SECRET_EXTENSION};
+use crate::strategy::{Strategy, SyncPolicy};
+use crate::sync::hash_bytes;
+use crate::template::{PartialDir, PARTIALS_DIR};
+use crate::vars::{VarResolver, Vars};
 
 #[derive(Debug)]
 pub struct Repo {
@@ -23,11 +29,28 @@ pub struct RepoItem {
     pub target: PathBuf,
     pub relative_path: String,
     pub is_template: bool,
+    /// An age-encrypted `.age` source, decrypted into place
+    pub is_secret: bool,
     pub strategy: Strategy,
+    /// What `link` does with target-side edits of copied or rendered files
+    pub sync: SyncPolicy,
 }
 
-/// Discover all repos in ~/.homie/repos/
-pub fn discover_repos() -> Result<Vec<Repo>> {
+impl RepoItem {
+    /// `path` is a repo-relative path (with or without `.tmpl`) or a target path
+    pub fn matches_path(&self, path: &str) -> bool {
+        let path = path.trim_end_matches('/');
+        if self.relative_path == path || self.relative_path.strip_suffix(".tmpl") == Some(path) {
+            return true;
+        }
+
+        let expanded = PathBuf::from(shellexpand::tilde(path).as_ref());
+        expanded.is_absolute() && self.target == expanded
+    }
+}
+
+/// Discover all repos in ~/.homie/repos/ that are active under the current profile
+pub fn discover_repos(global_config: &GlobalConfig) -> Result<Vec<Repo>> {
     let repos_path = repos_dir()?;
 
     if !repos_path.exists() {
@@ -43,7 +66,12 @@ pub fn discover_repos() -> Result<Vec<Repo>> {
         let path = entry.path();
 
         if path.is_dir() && path.join("homie.toml").exists() {
-            match Repo::from_path(&path) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !global_config.is_repo_active(&name) {
+                continue;
+            }
+
+            match Repo::from_path(&path, global_config) {
                 Ok(repo) => repos.push(repo),
                 Err(e) => {
                     eprintln!(
@@ -62,25 +90,30 @@ pub fn discover_repos() -> Result<Vec<Repo>> {
     Ok(repos)
 }
 
-/// Find a specific repo by name
-pub fn find_repo(name: &str) -> Result<Option<Repo>> {
+/// Find a specific repo by name (regardless of the active profile)
+pub fn find_repo(global_config: &GlobalConfig, name: &str) -> Result<Option<Repo>> {
     let repos_path = repos_dir()?;
     let repo_path = repos_path.join(name);
 
     if repo_path.is_dir() && repo_path.join("homie.toml").exists() {
-        Ok(Some(Repo::from_path(&repo_path)?))
+        Ok(Some(Repo::from_path(&repo_path, global_config)?))
     } else {
         Ok(None)
     }
 }
 
 impl Repo {
-    pub fn from_path(path: &Path) -> Result<Self> {
+    pub fn from_path(path: &Path, global_config: &GlobalConfig) -> Result<Self> {
         if !path.exists() {
             bail!("Repo path does not exist: {}", path.display());
         }
 
-        let config = RepoConfig::load(path)?;
+        let mut config = RepoConfig::load(path)?;
+
+        // Conditions see the same vars templates do, before any [[when]] overrides
+        let vars = VarResolver::with_repo_vars(global_config, Some(&config.vars)).to_template_data().vars;
+        config.apply_conditions(&vars);
+
         let target = config.expanded_target();
 
         let name = path
@@ -111,23 +144,85 @@ impl Repo {
         })
     }
 
-    pub fn vars(&self) -> &HashMap<String, String> {
+    pub fn vars(&self) -> &Vars {
         &self.config.vars
     }
 
+    /// The `_partials/` directories of the repo's imports, then of the repo
+    pub fn partial_dirs(&self) -> Vec<PartialDir> {
+        let mut dirs: Vec<PartialDir> = self
+            .imports
+            .iter()
+            .map(|import| PartialDir {
+                path: import.source_path().join(PARTIALS_DIR),
+                namespace: Some(import.name.clone()),
+            })
+            .collect();
+        dirs.push(PartialDir {
+            path: self.path.join(PARTIALS_DIR),
+            namespace: None,
+        });
+        dirs
+    }
+
     /// Fetch all git imports (clone if missing, pull if exists)
-    pub fn fetch_imports(&self, dry_run: bool) -> Result<()> {
+    pub fn fetch_imports(&self, dry_run: bool, quiet: bool) -> Result<()> {
         for import in &self.imports {
-            import.ensure_available(&self.path, dry_run)?;
+            import.ensure_available(&self.path, dry_run, quiet)?;
         }
         Ok(())
     }
 
+    /// Target for a manifest path (a repo-relative source path)
+    pub fn target_for(&self, relative_path: &str) -> PathBuf {
+        let relative = Path::new(relative_path);
+        let strip_extension = relative
+            .extension()
+            .is_some_and(|e| e == "tmpl" || e == SECRET_EXTENSION);
+        compute_target(&self.target, relative, strip_extension)
+    }
+
+    /// Where a manifest entry was placed: the recorded target if known
+    pub fn manifest_target(&self, relative_path: &str, record: &ManifestRecord) -> PathBuf {
+        record
+            .target
+            .clone()
+            .unwrap_or_else(|| self.target_for(relative_path))
+    }
+
+    /// The import an item's source comes from, if not the repo itself
+    pub fn import_for(&self, item: &RepoItem) -> Option<&str> {
+        self.imports
+            .iter()
+            .find(|import| item.source.starts_with(import.source_path()))
+            .map(|import| import.name.as_str())
+    }
+
+    /// The manifest record for an item just linked as `kind`
+    pub fn record_for(&self, item: &RepoItem, kind: ManifestEntry) -> Result<ManifestRecord> {
+        let mut record = ManifestRecord::for_item(item, kind, self.import_for(item))?;
+        if kind == ManifestEntry::Block {
+            // Only the block is homie's, so only the block is hashed
+            let content = fs::read_to_string(&item.target)
+                .with_context(|| format!("Failed to read: {}", item.target.display()))?;
+            record.sha256 = block::extract(&content, &self.name)?.map(|body| hash_bytes(body.as_bytes()));
+        }
+        Ok(record)
+    }
+
     pub fn items(&self) -> Result<Vec<RepoItem>> {
         let mut items = Vec::new();
         let mut seen_paths: HashSet<String> = HashSet::new();
 
         for item in self.collect_items_from(&self.path)? {
+            // Edits cannot be pulled back file by file out of a directory unit
+            if item.sync == SyncPolicy::Pull && item.source.is_dir() {
+                bail!(
+                    "{}: sync = \"pull\" needs single files, not a directory copied as a unit (use \"{}/*\" to copy its files one by one)",
+                    item.relative_path,
+                    item.relative_path
+                );
+            }
             seen_paths.insert(item.relative_path.clone());
             items.push(item);
         }
@@ -154,8 +249,10 @@ impl Repo {
                     continue;
                 }
 
-                item.target = compute_target(&self.target, &remapped, item.is_template);
+                item.target =
+                    compute_target(&self.target, &remapped, item.is_template || item.is_secret);
                 item.strategy = self.config.strategy_for_path(&remapped_str);
+                item.sync = self.config.sync_for_path(&remapped_str);
                 item.relative_path = remapped_str.clone();
                 seen_paths.insert(remapped_str);
                 items.push(item);
@@ -195,8 +292,10 @@ impl Repo {
             }
 
             let is_template = source.extension().is_some_and(|e| e == "tmpl");
+            let is_secret = entry.file_type().is_file() && is_secret_path(&source);
             let strategy = self.config.strategy_for_path(&relative_str);
-            let target = compute_target(&self.target, relative, is_template);
+            let sync = self.config.sync_for_path(&relative_str);
+            let target = compute_target(&self.target, relative, is_template || is_secret);
 
             if entry.file_type().is_dir() {
                 if strategy.is_directory_unit() {
@@ -206,7 +305,9 @@ impl Repo {
                         target,
                         relative_path: relative_str,
                         is_template,
+                        is_secret,
                         strategy,
+                        sync,
                     });
                 }
                 continue;
@@ -217,7 +318,9 @@ impl Repo {
                 target,
                 relative_path: relative_str,
                 is_template,
+                is_secret,
                 strategy,
+                sync,
             });
         }
 
@@ -225,11 +328,11 @@ impl Repo {
     }
 }
 
-fn compute_target(base: &Path, relative: &Path, is_template: bool) -> PathBuf {
+fn compute_target(base: &Path, relative: &Path, strip_extension: bool) -> PathBuf {
     let mut target = base.join(relative);
 
-    // Strip .tmpl extension for templates
-    if is_template {
+    // Strip .tmpl / .age extension for templates and secrets
+    if strip_extension {
         if let Some(stem) = target.file_stem() {
             let parent = target.parent().unwrap_or(Path::new(""));
             target = parent.join(stem);
@@ -260,4 +363,31 @@ mod tests {
         let target = compute_target(&base, &relative, true);
         assert_eq!(target, PathBuf::from("/home/user/.config/app/config.toml"));
     }
+
+    #[test]
+    fn test_compute_target_secret() {
+        let base = PathBuf::from("/home/user");
+        let relative = PathBuf::from(".ssh/config.age");
+        assert!(is_secret_path(&relative));
+
+        let target = compute_target(&base, &relative, true);
+        assert_eq!(target, PathBuf::from("/home/user/.ssh/config"));
+    }
+
+    #[test]
+    fn test_pull_sync_rejects_copied_directory() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let repo_path = temp.path().join("dotfiles");
+        fs::create_dir_all(repo_path.join(".config/app")).unwrap();
+        fs::write(repo_path.join(".config/app/settings.json"), "{}").unwrap();
+        fs::write(
+            repo_path.join("homie.toml"),
+            "target = \"~\"\n[strategies]\n\".config/app\" = { strategy = \"copy\", sync = \"pull\" }\n",
+        )
+        .unwrap();
+
+        let repo = Repo::from_path(&repo_path, &GlobalConfig::default()).unwrap();
+        let error = repo.items().unwrap_err().to_string();
+        assert!(error.contains(".config/app/*"), "{}", error);
+    }
 }
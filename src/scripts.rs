@@ -0,0 +1,257 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::hooks::HookRunner;
+use crate::repo::Repo;
+use crate::secret::ScratchDir;
+use crate::template::TemplateEngine;
+use crate::vars::VarResolver;
+
+/// A `[[scripts]]` entry in homie.toml
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScriptConfig {
+    /// Script path relative to the repo root (`.tmpl` scripts are rendered first)
+    pub path: String,
+    #[serde(default)]
+    pub run: ScriptRun,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptRun {
+    /// Once per machine
+    #[default]
+    Once,
+    /// Whenever the (rendered) content changes
+    OnChange,
+    /// On every link
+    Always,
+}
+
+/// Which scripts ran on this machine, at `<repo>/.homie/scripts.toml`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScriptState {
+    #[serde(default)]
+    pub scripts: BTreeMap<String, ScriptRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptRecord {
+    /// sha256 of the content that last ran
+    pub hash: String,
+    pub ran_at: String,
+}
+
+impl ScriptState {
+    pub fn load(repo_path: &Path) -> Result<Self> {
+        let path = Self::path_for_repo(repo_path);
+
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read script state: {}", path.display()))?;
+
+        toml::from_str(&content)
+            .with_context(|| format!("Failed to parse script state: {}", path.display()))
+    }
+
+    pub fn save(&self, repo_path: &Path) -> Result<()> {
+        let path = Self::path_for_repo(repo_path);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create .homie dir: {}", parent.display()))?;
+        }
+
+        let content = format!(
+            "# Auto-generated by homie. Do not edit manually.\n{}",
+            toml::to_string_pretty(self).context("Failed to serialize script state")?
+        );
+
+        let temp_path = path.with_extension("toml.tmp");
+        fs::write(&temp_path, &content)
+            .with_context(|| format!("Failed to write temp script state: {}", temp_path.display()))?;
+
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Failed to rename script state: {}", path.display()))?;
+
+        Ok(())
+    }
+
+    pub fn path_for_repo(repo_path: &Path) -> PathBuf {
+        repo_path.join(".homie/scripts.toml")
+    }
+
+    /// Whether a script with content `hash` should run now
+    pub fn is_due(&self, script: &ScriptConfig, hash: &str) -> bool {
+        match (script.run, self.scripts.get(&script.path)) {
+            (ScriptRun::Always, _) | (_, None) => true,
+            (ScriptRun::Once, Some(_)) => false,
+            (ScriptRun::OnChange, Some(record)) => record.hash != hash,
+        }
+    }
+
+    pub fn record(&mut self, script: &ScriptConfig, hash: String) {
+        let record = ScriptRecord {
+            hash,
+            ran_at: Local::now().to_rfc3339(),
+        };
+        self.scripts.insert(script.path.clone(), record);
+    }
+}
+
+/// Run the repo's due scripts in order, stopping at the first failure.
+/// State is saved after each script so finished ones are not rerun.
+pub fn run_scripts(
+    repo: &Repo,
+    engine: &TemplateEngine,
+    var_resolver: &VarResolver,
+    runner: &HookRunner,
+    changed: &[String],
+    dry_run: bool,
+) -> Result<()> {
+    if repo.config.scripts.is_empty() {
+        return Ok(());
+    }
+
+    let mut state = ScriptState::load(&repo.path)?;
+    let scratch = ScratchDir::create()?;
+
+    for script in &repo.config.scripts {
+        let source = repo.path.join(&script.path);
+        let content = if source.extension().is_some_and(|e| e == "tmpl") {
+            let vars = var_resolver.to_template_data();
+            engine.render_file(&source, &vars)?.content.into_bytes()
+        } else {
+            fs::read(&source)
+                .with_context(|| format!("Failed to read script: {}", source.display()))?
+        };
+
+        let hash = format!("{:x}", Sha256::digest(&content));
+        if !state.is_due(script, &hash) {
+            continue;
+        }
+
+        // Run a private copy so templates and missing exec bits both work
+        let file_name = source.file_name().context("Script has no filename")?;
+        let script_path = scratch.path().join(file_name);
+        fs::write(&script_path, &content)
+            .with_context(|| format!("Failed to write script: {}", script_path.display()))?;
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o700))
+            .with_context(|| format!("Failed to set permissions: {}", script_path.display()))?;
+
+        runner.run_script(&script.path, &script_path, changed)?;
+
+        if !dry_run {
+            state.record(script, hash);
+            state.save(&repo.path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn script(run: ScriptRun) -> ScriptConfig {
+        ScriptConfig {
+            path: "scripts/setup.sh".to_string(),
+            run,
+        }
+    }
+
+    #[test]
+    fn test_is_due() {
+        let mut state = ScriptState::default();
+        for run in [ScriptRun::Once, ScriptRun::OnChange, ScriptRun::Always] {
+            assert!(state.is_due(&script(run), "abc"));
+        }
+
+        state.record(&script(ScriptRun::Once), "abc".to_string());
+        assert!(!state.is_due(&script(ScriptRun::Once), "def"));
+        assert!(!state.is_due(&script(ScriptRun::OnChange), "abc"));
+        assert!(state.is_due(&script(ScriptRun::OnChange), "def"));
+        assert!(state.is_due(&script(ScriptRun::Always), "abc"));
+    }
+
+    #[test]
+    fn test_state_save_and_load() {
+        let temp = TempDir::new().unwrap();
+        let mut state = ScriptState::default();
+        state.record(&script(ScriptRun::OnChange), "abc".to_string());
+        state.save(temp.path()).unwrap();
+
+        let loaded = ScriptState::load(temp.path()).unwrap();
+        assert_eq!(loaded.scripts["scripts/setup.sh"].hash, "abc");
+    }
+
+    #[test]
+    fn test_parse_scripts() {
+        let toml = r#"
+target = "~"
+
+[[scripts]]
+path = "scripts/brew.sh"
+
+[[scripts]]
+path = "scripts/defaults.sh.tmpl"
+run = "onchange"
+"#;
+
+        let config: crate::config::RepoConfig = toml::from_str(toml).unwrap();
+        assert_eq!(config.scripts.len(), 2);
+        assert_eq!(config.scripts[0].run, ScriptRun::Once);
+        assert_eq!(config.scripts[1].run, ScriptRun::OnChange);
+    }
+
+    #[test]
+    fn test_run_scripts_once_and_onchange() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("dot");
+        fs::create_dir_all(repo_path.join("scripts")).unwrap();
+        fs::write(
+            repo_path.join("homie.toml"),
+            r#"
+target = "~"
+
+[[scripts]]
+path = "scripts/once.sh"
+
+[[scripts]]
+path = "scripts/change.sh"
+run = "onchange"
+"#,
+        )
+        .unwrap();
+        fs::write(repo_path.join("scripts/once.sh"), "echo once >> log").unwrap();
+        fs::write(repo_path.join("scripts/change.sh"), "echo change >> log").unwrap();
+
+        let config = crate::config::GlobalConfig::default();
+        let repo = Repo::from_path(&repo_path, &config).unwrap();
+        assert!(repo.items().unwrap().is_empty());
+
+        let engine = TemplateEngine::new();
+        let var_resolver = VarResolver::new(&config, Some(&repo));
+        let runner = HookRunner::new(&repo, false, true);
+        let run = || run_scripts(&repo, &engine, &var_resolver, &runner, &[], false).unwrap();
+
+        run();
+        run();
+        fs::write(repo_path.join("scripts/change.sh"), "echo changed >> log").unwrap();
+        run();
+
+        let log = fs::read_to_string(repo_path.join("log")).unwrap();
+        assert_eq!(log, "once\nchange\nchanged\n");
+    }
+}
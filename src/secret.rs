@@ -0,0 +1,347 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use age::secrecy::ExposeSecret;
+
+use crate::config::SecretsConfig;
+
+/// Extension marking an encrypted source file
+pub const SECRET_EXTENSION: &str = "age";
+
+pub fn is_secret_path(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == SECRET_EXTENSION)
+}
+
+/// Identities from an age identity file, loaded once and reused
+pub struct Identities {
+    path: PathBuf,
+    identities: Vec<Box<dyn age::Identity>>,
+}
+
+impl Identities {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            bail!(
+                "No age identity found at {}. Run 'homie secret keygen' to create one.",
+                path.display()
+            );
+        }
+
+        let identities = age::IdentityFile::from_file(path.to_string_lossy().to_string())
+            .with_context(|| format!("Failed to read identity file: {}", path.display()))?
+            .into_identities()
+            .with_context(|| format!("Failed to parse identity file: {}", path.display()))?;
+
+        if identities.is_empty() {
+            bail!("Identity file contains no identities: {}", path.display());
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            identities,
+        })
+    }
+
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let decryptor = age::Decryptor::new_buffered(ciphertext).context("Not an age file")?;
+        let mut reader = decryptor
+            .decrypt(self.identities.iter().map(|i| i.as_ref()))
+            .with_context(|| format!("Failed to decrypt with identity {}", self.path.display()))?;
+
+        let mut plaintext = Vec::new();
+        reader
+            .read_to_end(&mut plaintext)
+            .context("Failed to read decrypted content")?;
+        Ok(plaintext)
+    }
+
+    pub fn decrypt_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let ciphertext =
+            fs::read(path).with_context(|| format!("Failed to read secret: {}", path.display()))?;
+        self.decrypt(&ciphertext)
+            .with_context(|| format!("Failed to decrypt {}", path.display()))
+    }
+}
+
+/// Recipients for the identity file plus any configured in `[secrets]`
+pub fn recipients(config: &SecretsConfig) -> Result<Vec<Box<dyn age::Recipient + Send>>> {
+    let identity_path = config.identity_path()?;
+    let mut recipients = Vec::new();
+
+    if identity_path.exists() {
+        let file = age::IdentityFile::from_file(identity_path.to_string_lossy().to_string())
+            .with_context(|| format!("Failed to read identity file: {}", identity_path.display()))?;
+        recipients.extend(file.to_recipients().with_context(|| {
+            format!("Failed to derive recipients from {}", identity_path.display())
+        })?);
+    }
+
+    for key in &config.recipients {
+        let recipient: age::x25519::Recipient = key
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid recipient {}: {}", key, e))?;
+        recipients.push(Box::new(recipient));
+    }
+
+    if recipients.is_empty() {
+        bail!(
+            "No recipients to encrypt to. Run 'homie secret keygen' or add [secrets] recipients."
+        );
+    }
+
+    Ok(recipients)
+}
+
+pub fn encrypt(plaintext: &[u8], recipients: &[Box<dyn age::Recipient + Send>]) -> Result<Vec<u8>> {
+    let encryptor =
+        age::Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient))
+            .context("Failed to set up encryption")?;
+
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor
+        .wrap_output(&mut ciphertext)
+        .context("Failed to write age header")?;
+    writer.write_all(plaintext).context("Failed to encrypt")?;
+    writer.finish().context("Failed to finish encryption")?;
+
+    Ok(ciphertext)
+}
+
+/// Write a file readable only by the current user (0600)
+pub fn write_private(path: &Path, content: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to create: {}", path.display()))?;
+
+    // `mode` only applies to newly created files
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to set permissions: {}", path.display()))?;
+
+    file.write_all(content)
+        .with_context(|| format!("Failed to write: {}", path.display()))
+}
+
+/// Generate a new x25519 identity at `path`, returning its public key
+pub fn generate_identity(path: &Path) -> Result<String> {
+    if path.exists() {
+        bail!("Identity already exists: {}", path.display());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+
+    let identity = age::x25519::Identity::generate();
+    let public = identity.to_public().to_string();
+    let content = format!(
+        "# created: {}\n# public key: {}\n{}\n",
+        chrono::Local::now().to_rfc3339(),
+        public,
+        identity.to_string().expose_secret()
+    );
+    write_private(path, content.as_bytes())?;
+
+    Ok(public)
+}
+
+/// Fetches secrets by running the configured provider command, once per key
+pub struct SecretProvider {
+    command: String,
+    cache: Mutex<HashMap<String, String>>,
+}
+
+impl SecretProvider {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<String> {
+        if let Some(value) = self.cache.lock().unwrap().get(key) {
+            return Ok(value.clone());
+        }
+
+        let value = self.fetch(key)?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.clone());
+        Ok(value)
+    }
+
+    fn fetch(&self, key: &str) -> Result<String> {
+        // The key is passed as $1 rather than spliced into the command line
+        let script = if self.command.contains("{key}") {
+            self.command.replace("{key}", "\"$1\"")
+        } else {
+            format!("{} \"$1\"", self.command)
+        };
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&script)
+            .arg("sh")
+            .arg(key)
+            .output()
+            .with_context(|| format!("Failed to run secret provider: {}", self.command))?;
+
+        if !output.status.success() {
+            bail!(
+                "Secret provider failed for {}: {}",
+                key,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let value = String::from_utf8(output.stdout)
+            .with_context(|| format!("Secret {} is not valid UTF-8", key))?;
+
+        // Providers like `pass show` end with a newline
+        Ok(value.strip_suffix('\n').unwrap_or(&value).to_string())
+    }
+}
+
+/// Private (0700) temp directory holding decrypted content, removed on drop
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    pub fn create() -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "homie-{}-{}-{}",
+            std::process::id(),
+            chrono::Local::now().timestamp_nanos_opt().unwrap_or_default(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .with_context(|| format!("Failed to create temp directory: {}", path.display()))?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn config_with_identity(temp: &TempDir) -> SecretsConfig {
+        let path = temp.path().join("identity.txt");
+        generate_identity(&path).unwrap();
+        SecretsConfig {
+            identity: Some(path.to_string_lossy().to_string()),
+            recipients: Vec::new(),
+            provider: None,
+        }
+    }
+
+    #[test]
+    fn test_is_secret_path() {
+        assert!(is_secret_path(Path::new(".ssh/config.age")));
+        assert!(!is_secret_path(Path::new(".ssh/config")));
+        assert!(!is_secret_path(Path::new(".gitconfig.tmpl")));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_roundtrip() {
+        let temp = TempDir::new().unwrap();
+        let config = config_with_identity(&temp);
+
+        let ciphertext = encrypt(b"token = hunter2", &recipients(&config).unwrap()).unwrap();
+        assert!(!ciphertext.windows(7).any(|w| w == b"hunter2"));
+
+        let identities = Identities::load(&config.identity_path().unwrap()).unwrap();
+        assert_eq!(identities.decrypt(&ciphertext).unwrap(), b"token = hunter2");
+    }
+
+    #[test]
+    fn test_decrypt_with_wrong_identity_fails() {
+        let temp = TempDir::new().unwrap();
+        let config = config_with_identity(&temp);
+        let ciphertext = encrypt(b"secret", &recipients(&config).unwrap()).unwrap();
+
+        let other = TempDir::new().unwrap();
+        let other_config = config_with_identity(&other);
+        let identities = Identities::load(&other_config.identity_path().unwrap()).unwrap();
+        assert!(identities.decrypt(&ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_generated_identity_is_private() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("keys/identity.txt");
+        let public = generate_identity(&path).unwrap();
+
+        assert!(public.starts_with("age1"));
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(generate_identity(&path).is_err());
+    }
+
+    #[test]
+    fn test_extra_recipients() {
+        let temp = TempDir::new().unwrap();
+        let other = age::x25519::Identity::generate();
+        let config = SecretsConfig {
+            identity: Some(temp.path().join("missing.txt").to_string_lossy().to_string()),
+            recipients: vec![other.to_public().to_string()],
+            provider: None,
+        };
+
+        let ciphertext = encrypt(b"shared", &recipients(&config).unwrap()).unwrap();
+        assert_eq!(age::decrypt(&other, &ciphertext).unwrap(), b"shared");
+    }
+
+    #[test]
+    fn test_secret_provider_caches_per_key() {
+        let temp = TempDir::new().unwrap();
+        let log = temp.path().join("calls");
+        let command = format!("echo >> {}; printf 'value-%s\\n'", log.display());
+        let provider = SecretProvider::new(&command);
+
+        assert_eq!(provider.get("github/token").unwrap(), "value-github/token");
+        assert_eq!(provider.get("github/token").unwrap(), "value-github/token");
+        assert_eq!(provider.get("other").unwrap(), "value-other");
+
+        let calls = fs::read_to_string(&log).unwrap();
+        assert_eq!(calls.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_secret_provider_key_placeholder() {
+        let provider = SecretProvider::new("echo op://vault/{key}/password");
+        assert_eq!(provider.get("db").unwrap(), "op://vault/db/password");
+    }
+
+    #[test]
+    fn test_secret_provider_failure() {
+        let provider = SecretProvider::new("sh -c 'echo nope >&2; exit 1'");
+        let err = provider.get("missing").unwrap_err();
+        assert!(format!("{:#}", err).contains("nope"));
+    }
+}
@@ -1,7 +1,15 @@
+use serde::Serialize;
 use std::fs;
 use std::path::Path;
+use walkdir::WalkDir;
 
+use crate::block;
+use crate::manifest::ManifestRecord;
 use crate::repo::RepoItem;
+use crate::strategy::Strategy;
+use crate::sync::{hash_bytes, hash_dir};
+use crate::template::{self, TemplateEngine};
+use crate::vars::VarResolver;
 
 #[derive(Debug, Default)]
 pub struct RepoStatus {
@@ -11,28 +19,98 @@ pub struct RepoStatus {
     pub missing: usize,
     pub conflict: usize,
     pub rendered: usize,
+    pub decrypted: usize,
+    pub merged: usize,
+    pub stale: usize,
+    pub modified: usize,
+    pub shadowed: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
 pub enum ItemStatus {
     Linked,
     Copied,
-    External(String),
+    External { points_to: String },
     Missing,
     Conflict,
     Rendered,
+    Decrypted,
+    /// The repo's block in the target is up to date
+    Merged,
+    /// The source, template or vars changed since the target was written
+    Stale,
+    /// The target was edited since homie wrote it
+    Modified,
+    /// The target belongs to a higher-priority repo
+    Shadowed { by: String },
 }
 
 impl RepoStatus {
     pub fn total(&self) -> usize {
-        self.linked + self.copied + self.external + self.missing + self.conflict + self.rendered
+        self.linked
+            + self.copied
+            + self.external
+            + self.missing
+            + self.conflict
+            + self.rendered
+            + self.decrypted
+            + self.merged
+            + self.stale
+            + self.modified
+            + self.shadowed
     }
 }
 
-pub fn check_item_status(item: &RepoItem, repo_path: &Path) -> ItemStatus {
+/// Everything needed to work out what `link` would write
+pub struct StatusContext<'a> {
+    pub repo_path: &'a Path,
+    pub engine: &'a TemplateEngine,
+    pub var_resolver: &'a VarResolver,
+}
+
+/// Status of `item`'s target. Copies and rendered templates are compared
+/// against what `link` would write now and against `record`'s hash of what
+/// it wrote last time.
+pub fn check_item_status(
+    item: &RepoItem,
+    record: Option<&ManifestRecord>,
+    ctx: &StatusContext,
+) -> ItemStatus {
+    let repo_path = ctx.repo_path;
+    let recorded = record.and_then(|r| r.sha256.as_deref());
+
+    if item.strategy == Strategy::Block && !item.is_secret {
+        return block_status(item, recorded, ctx);
+    }
+
     if item.is_template {
-        return if item.target.exists() {
-            ItemStatus::Rendered
+        if !item.target.exists() {
+            return ItemStatus::Missing;
+        }
+
+        let Ok(source) = fs::read_to_string(&item.source) else {
+            return ItemStatus::Stale;
+        };
+        // Rendering would run the secret provider, so these are not compared
+        if template::uses_secrets(&source) {
+            return ItemStatus::Rendered;
+        }
+
+        let vars = ctx.var_resolver.to_template_data();
+        let expected = match ctx.engine.render(&source, &vars) {
+            Ok(rendered) if rendered.has_secrets => return ItemStatus::Rendered,
+            Ok(rendered) => Some(rendered.content.into_bytes()),
+            Err(_) => None,
+        };
+        return compare_content(&item.target, recorded, expected, ItemStatus::Rendered);
+    }
+
+    if item.is_secret {
+        return if item.target.is_symlink() {
+            ItemStatus::Conflict
+        } else if item.target.exists() {
+            ItemStatus::Decrypted
         } else {
             ItemStatus::Missing
         };
@@ -45,10 +123,18 @@ pub fn check_item_status(item: &RepoItem, repo_path: &Path) -> ItemStatus {
     }
 
     if is_copy {
-        if item.target.exists() && !item.target.is_symlink() {
-            return ItemStatus::Copied;
+        if !item.target.exists() || item.target.is_symlink() {
+            return ItemStatus::Conflict;
         }
-        return ItemStatus::Conflict;
+        if item.source.is_dir() {
+            return compare_dir(&item.source, &item.target, recorded);
+        }
+        return compare_content(
+            &item.target,
+            recorded,
+            fs::read(&item.source).ok(),
+            ItemStatus::Copied,
+        );
     }
 
     if !item.target.is_symlink() {
@@ -68,6 +154,265 @@ pub fn check_item_status(item: &RepoItem, repo_path: &Path) -> ItemStatus {
     if resolved == item.source || resolved.starts_with(repo_path) {
         ItemStatus::Linked
     } else {
-        ItemStatus::External(resolved.to_string_lossy().to_string())
+        ItemStatus::External {
+            points_to: resolved.to_string_lossy().to_string(),
+        }
+    }
+}
+
+/// Status of the repo's block inside the target; only the block itself is
+/// compared, the rest of the file belongs to someone else
+fn block_status(item: &RepoItem, recorded: Option<&str>, ctx: &StatusContext) -> ItemStatus {
+    if !item.target.exists() && !item.target.is_symlink() {
+        return ItemStatus::Missing;
+    }
+    let Ok(content) = fs::read_to_string(&item.target) else {
+        return ItemStatus::Conflict;
+    };
+    let body = match block::extract(&content, &block::owner(ctx.repo_path)) {
+        Ok(Some(body)) => body,
+        Ok(None) => return ItemStatus::Missing,
+        Err(_) => return ItemStatus::Conflict,
+    };
+
+    let Ok(source) = fs::read_to_string(&item.source) else {
+        return ItemStatus::Stale;
+    };
+    let expected = if item.is_template {
+        if template::uses_secrets(&source) {
+            return ItemStatus::Merged;
+        }
+        let vars = ctx.var_resolver.to_template_data();
+        match ctx.engine.render(&source, &vars) {
+            Ok(rendered) if rendered.has_secrets => return ItemStatus::Merged,
+            Ok(rendered) => Some(rendered.content),
+            Err(_) => None,
+        }
+    } else {
+        Some(source)
+    };
+
+    // Compare the way `link` writes the block: always newline-terminated
+    let expected = expected.map(|mut body| {
+        if !body.is_empty() && !body.ends_with('\n') {
+            body.push('\n');
+        }
+        body.into_bytes()
+    });
+    compare_bytes(body.as_bytes(), recorded, expected, ItemStatus::Merged)
+}
+
+/// `Modified` if the target no longer matches the hash recorded at link
+/// time, otherwise `Stale` if it differs from `expected`
+fn compare_content(
+    target: &Path,
+    recorded: Option<&str>,
+    expected: Option<Vec<u8>>,
+    current: ItemStatus,
+) -> ItemStatus {
+    let Ok(content) = fs::read(target) else {
+        return ItemStatus::Conflict;
+    };
+    compare_bytes(&content, recorded, expected, current)
+}
+
+fn compare_bytes(
+    content: &[u8],
+    recorded: Option<&str>,
+    expected: Option<Vec<u8>>,
+    current: ItemStatus,
+) -> ItemStatus {
+    if recorded.is_some_and(|hash| hash != hash_bytes(content)) {
+        return ItemStatus::Modified;
+    }
+
+    match expected {
+        Some(expected) if expected == content => current,
+        _ => ItemStatus::Stale,
+    }
+}
+
+/// `compare_content` for a directory copied as a unit: `Modified` if the
+/// target's tree no longer hashes to `recorded`, otherwise `Stale` unless
+/// every source file is in place
+fn compare_dir(source: &Path, target: &Path, recorded: Option<&str>) -> ItemStatus {
+    if let Some(recorded) = recorded {
+        match hash_dir(target) {
+            Ok(hash) if hash == recorded => {}
+            Ok(_) => return ItemStatus::Modified,
+            Err(_) => return ItemStatus::Conflict,
+        }
+    }
+
+    if dirs_match(source, target) {
+        ItemStatus::Copied
+    } else {
+        ItemStatus::Stale
+    }
+}
+
+/// Whether every file under `source` exists under `target` with the same content
+fn dirs_match(source: &Path, target: &Path) -> bool {
+    WalkDir::new(source)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .all(|entry| {
+            let Ok(relative) = entry.path().strip_prefix(source) else {
+                return false;
+            };
+            match (fs::read(entry.path()), fs::read(target.join(relative))) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GlobalConfig;
+    use crate::manifest::ManifestEntry;
+    use crate::repo::Repo;
+    use crate::strategy::{Strategy, SyncPolicy};
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn item(temp: &TempDir, relative: &str, strategy: Strategy) -> RepoItem {
+        RepoItem {
+            source: temp.path().join("repo").join(relative),
+            target: temp.path().join("home").join(relative.trim_end_matches(".tmpl")),
+            relative_path: relative.to_string(),
+            is_template: relative.ends_with(".tmpl"),
+            is_secret: false,
+            strategy,
+            sync: SyncPolicy::Overwrite,
+        }
+    }
+
+    fn write(path: &PathBuf, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn status(item: &RepoItem, record: Option<&ManifestRecord>) -> ItemStatus {
+        let config = GlobalConfig::default();
+        let var_resolver = VarResolver::new(&config, None);
+        let ctx = StatusContext {
+            repo_path: item.source.parent().unwrap(),
+            engine: &TemplateEngine::new(),
+            var_resolver: &var_resolver,
+        };
+        check_item_status(item, record, &ctx)
+    }
+
+    #[test]
+    fn test_copy_stale_and_modified() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, "settings.json", Strategy::Copy);
+        write(&item.source, "{}");
+        write(&item.target, "{}");
+        let record = ManifestRecord::for_item(&item, ManifestEntry::Copy, None).unwrap();
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Copied));
+
+        write(&item.source, "{\"a\": 1}");
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Stale));
+
+        write(&item.target, "{\"b\": 2}");
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Modified));
+
+        // Without a recorded hash an edit is indistinguishable from a repo change
+        assert!(matches!(status(&item, None), ItemStatus::Stale));
+    }
+
+    #[test]
+    fn test_template_stale_and_modified() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".gitconfig.tmpl", Strategy::File);
+        write(&item.source, "editor = {{#if true}}vim{{/if}}\n");
+        write(&item.target, "editor = vim\n");
+        let record = ManifestRecord::for_item(&item, ManifestEntry::Rendered, None).unwrap();
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Rendered));
+
+        write(&item.source, "editor = {{#if false}}vim{{else}}nano{{/if}}\n");
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Stale));
+
+        write(&item.target, "editor = emacs\n");
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Modified));
+    }
+
+    #[test]
+    fn test_template_with_secret_partial_is_not_compared() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".netrc.tmpl", Strategy::File);
+        write(&item.source, "{{> netrc}}");
+        write(&temp.path().join("repo/_partials/netrc"), "password {{secret \"netrc\"}}\n");
+        write(&temp.path().join("repo/homie.toml"), "target = \"~\"\n");
+        write(&item.target, "password old\n");
+
+        let mut config = GlobalConfig::default();
+        config.secrets.provider = Some("echo hunter2 #".to_string());
+        let repo = Repo::from_path(&temp.path().join("repo"), &config).unwrap();
+        let var_resolver = VarResolver::new(&config, Some(&repo));
+        let ctx = StatusContext {
+            repo_path: &repo.path,
+            engine: &TemplateEngine::for_config(&config),
+            var_resolver: &var_resolver,
+        };
+        assert!(matches!(check_item_status(&item, None, &ctx), ItemStatus::Rendered));
+    }
+
+    #[test]
+    fn test_copied_directory_stale() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".config/app", Strategy::Copy);
+        write(&item.source.join("a.toml"), "a");
+        write(&item.target.join("a.toml"), "a");
+        assert!(matches!(status(&item, None), ItemStatus::Copied));
+
+        write(&item.source.join("b.toml"), "b");
+        assert!(matches!(status(&item, None), ItemStatus::Stale));
+    }
+
+    #[test]
+    fn test_copied_directory_modified() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".config/app", Strategy::Copy);
+        write(&item.source.join("a.toml"), "a");
+        write(&item.target.join("a.toml"), "a");
+        let record = ManifestRecord::for_item(&item, ManifestEntry::Copy, None).unwrap();
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Copied));
+
+        write(&item.source.join("a.toml"), "repo");
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Stale));
+
+        // A file the app added counts as an edit too
+        write(&item.target.join("cache.db"), "x");
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Modified));
+    }
+
+    #[test]
+    fn test_block_status() {
+        let temp = TempDir::new().unwrap();
+        let item = item(&temp, ".bashrc", Strategy::Block);
+        write(&item.source, "alias ll='ls -l'");
+        assert!(matches!(status(&item, None), ItemStatus::Missing));
+
+        write(&item.target, "# from an installer\n");
+        assert!(matches!(status(&item, None), ItemStatus::Missing));
+
+        let content = block::upsert("# from an installer\n", "repo", "alias ll='ls -l'").unwrap();
+        write(&item.target, &format!("{}export EDITOR=vi\n", content));
+        let mut record = ManifestRecord::new(ManifestEntry::Block);
+        record.sha256 = Some(hash_bytes(b"alias ll='ls -l'\n"));
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Merged));
+
+        write(&item.source, "alias ll='ls -la'\n");
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Stale));
+
+        let edited = block::upsert(&content, "repo", "alias ll='exa'\n").unwrap();
+        write(&item.target, &edited);
+        assert!(matches!(status(&item, Some(&record)), ItemStatus::Modified));
     }
 }
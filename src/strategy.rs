@@ -1,6 +1,6 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum Strategy {
     #[default]
@@ -8,6 +8,8 @@ pub enum Strategy {
     Directory,
     Contents,
     Copy,
+    /// A delimited block merged into a file that other tools also edit
+    Block,
 }
 
 impl Strategy {
@@ -17,6 +19,7 @@ impl Strategy {
             Strategy::Directory => false,
             Strategy::Contents => false,
             Strategy::Copy => true,
+            Strategy::Block => false,
         }
     }
 
@@ -26,6 +29,47 @@ impl Strategy {
             Strategy::Directory => true,
             Strategy::Contents => false,
             Strategy::Copy => true,
+            Strategy::Block => false,
+        }
+    }
+}
+
+/// What `link` does when a copied or rendered target was edited in place
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    /// Replace the target with the repo version (one-way)
+    #[default]
+    Overwrite,
+    /// Pull target-side edits back into the repo first; skip if both changed
+    Pull,
+}
+
+/// A `[strategies]` value: a bare strategy, or a table with a sync policy
+/// (`{ strategy = "copy", sync = "pull" }`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum StrategyEntry {
+    Simple(Strategy),
+    Detailed {
+        strategy: Strategy,
+        #[serde(default)]
+        sync: SyncPolicy,
+    },
+}
+
+impl StrategyEntry {
+    pub fn strategy(&self) -> Strategy {
+        match self {
+            StrategyEntry::Simple(strategy) => *strategy,
+            StrategyEntry::Detailed { strategy, .. } => *strategy,
+        }
+    }
+
+    pub fn sync(&self) -> SyncPolicy {
+        match self {
+            StrategyEntry::Simple(_) => SyncPolicy::default(),
+            StrategyEntry::Detailed { sync, .. } => *sync,
         }
     }
 }
@@ -37,6 +81,7 @@ impl std::fmt::Display for Strategy {
             Strategy::Directory => "directory",
             Strategy::Contents => "contents",
             Strategy::Copy => "copy",
+            Strategy::Block => "block",
         };
         write!(f, "{}", s)
     }
@@ -0,0 +1,416 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walkdir::WalkDir;
+
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::repo::{Repo, RepoItem};
+use crate::secret::ScratchDir;
+use crate::template::TemplateEngine;
+use crate::vars::VarResolver;
+
+/// What pulling a target back into the repo would do
+#[derive(Debug, PartialEq, Eq)]
+pub enum PullOutcome {
+    /// Not a copied file or rendered template with a recorded hash
+    Untracked,
+    /// The target still matches what homie last wrote
+    Unchanged,
+    /// Only the target changed: the new source content
+    Pull(Vec<u8>),
+    /// Both sides changed and `merge` was not requested
+    Conflict,
+    /// Both sides changed and were merged; `conflicts` hunks carry markers
+    Merged { content: Vec<u8>, conflicts: usize },
+}
+
+/// Marker lines `git merge-file` leaves around a conflicting hunk, with the
+/// labels `merge_file` gives the two sides
+const CONFLICT_START: &str = "<<<<<<< repo";
+const CONFLICT_END: &str = ">>>>>>> target";
+
+pub fn hash_bytes(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Hash of every path, file content and symlink target below `dir`
+pub fn hash_dir(dir: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+
+    for entry in WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+        let relative = entry
+            .path()
+            .strip_prefix(dir)
+            .context("Failed to strip prefix")?;
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update([0]);
+
+        let file_type = entry.file_type();
+        if file_type.is_symlink() {
+            hasher.update(b"l");
+            hasher.update(fs::read_link(entry.path())?.to_string_lossy().as_bytes());
+        } else if file_type.is_file() {
+            hasher.update(b"f");
+            let content = fs::read(entry.path())
+                .with_context(|| format!("Failed to read: {}", entry.path().display()))?;
+            hasher.update(Sha256::digest(&content));
+        } else {
+            hasher.update(b"d");
+        }
+        hasher.update([0]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// What is at a path, compact enough to compare later
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Fingerprint {
+    Missing,
+    Symlink { points_to: PathBuf },
+    File { sha256: String },
+    Dir { sha256: String },
+}
+
+pub fn fingerprint(path: &Path) -> Result<Fingerprint> {
+    if path.is_symlink() {
+        let points_to = fs::read_link(path)
+            .with_context(|| format!("Failed to read symlink: {}", path.display()))?;
+        return Ok(Fingerprint::Symlink { points_to });
+    }
+
+    if path.is_file() {
+        let content =
+            fs::read(path).with_context(|| format!("Failed to read: {}", path.display()))?;
+        return Ok(Fingerprint::File {
+            sha256: hash_bytes(&content),
+        });
+    }
+
+    if path.is_dir() {
+        return Ok(Fingerprint::Dir {
+            sha256: hash_dir(path)?,
+        });
+    }
+
+    Ok(Fingerprint::Missing)
+}
+
+/// Snapshot of what homie last wrote, used as the merge base
+pub fn base_path(repo_path: &Path, relative_path: &str) -> PathBuf {
+    repo_path.join(".homie/base").join(relative_path)
+}
+
+/// Snapshot the content of copied and rendered targets as merge bases.
+/// Targets that no longer match their recorded hash are left alone.
+pub fn record_bases(repo: &Repo, manifest: &Manifest) -> Result<()> {
+    for (path, record) in manifest.iter() {
+        if !matches!(record.kind, ManifestEntry::Copy | ManifestEntry::Rendered) {
+            continue;
+        }
+        let Some(hash) = &record.sha256 else {
+            continue;
+        };
+
+        let target = repo.manifest_target(path, record);
+        let Ok(content) = fs::read(&target) else {
+            continue;
+        };
+        if hash_bytes(&content) == *hash {
+            write_base(&repo.path, path, &content)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_base(repo_path: &Path, relative_path: &str, content: &[u8]) -> Result<()> {
+    let path = base_path(repo_path, relative_path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    fs::write(&path, content).with_context(|| format!("Failed to write: {}", path.display()))
+}
+
+/// Work out how target-side edits to `item` would land in the repo.
+/// Copied files with an unchanged source take the target as-is; templates
+/// always go through a three-way merge so variables are not baked in.
+pub fn plan_pull(
+    item: &RepoItem,
+    manifest: &Manifest,
+    repo_path: &Path,
+    engine: &TemplateEngine,
+    var_resolver: &VarResolver,
+    merge: bool,
+) -> Result<PullOutcome> {
+    // Secrets and imported sources never get plaintext written back
+    if item.is_secret || !item.source.starts_with(repo_path) || !item.source.is_file() {
+        return Ok(PullOutcome::Untracked);
+    }
+    if !matches!(
+        manifest.kind(&item.relative_path),
+        Some(ManifestEntry::Copy | ManifestEntry::Rendered)
+    ) {
+        return Ok(PullOutcome::Untracked);
+    }
+    let Some(recorded) = manifest.hash(&item.relative_path) else {
+        return Ok(PullOutcome::Untracked);
+    };
+    if item.target.is_symlink() || !item.target.is_file() {
+        return Ok(PullOutcome::Untracked);
+    }
+
+    let target = fs::read(&item.target)
+        .with_context(|| format!("Failed to read: {}", item.target.display()))?;
+    if hash_bytes(&target) == recorded {
+        return Ok(PullOutcome::Unchanged);
+    }
+
+    let source = fs::read(&item.source)
+        .with_context(|| format!("Failed to read: {}", item.source.display()))?;
+    let repo_changed = if item.is_template {
+        let vars = var_resolver.to_template_data();
+        let rendered = engine.render_file(&item.source, &vars)?;
+        hash_bytes(rendered.content.as_bytes()) != recorded
+    } else {
+        hash_bytes(&source) != recorded
+    };
+
+    if !repo_changed && !item.is_template {
+        return Ok(PullOutcome::Pull(target));
+    }
+
+    let base_file = base_path(repo_path, &item.relative_path);
+    if (repo_changed && !merge) || !base_file.exists() {
+        return Ok(PullOutcome::Conflict);
+    }
+    let base = fs::read(&base_file)
+        .with_context(|| format!("Failed to read: {}", base_file.display()))?;
+
+    let (content, conflicts) = merge_file(&source, &base, &target)?;
+    match (conflicts, repo_changed) {
+        (0, false) => Ok(PullOutcome::Pull(content)),
+        (_, false) if !merge => Ok(PullOutcome::Conflict),
+        _ => Ok(PullOutcome::Merged { content, conflicts }),
+    }
+}
+
+/// Whether `path` still holds conflict markers from `pull-back --merge`
+pub fn has_conflict_markers(path: &Path) -> bool {
+    let Ok(content) = fs::read_to_string(path) else {
+        return false;
+    };
+    content.lines().any(|line| line.starts_with(CONFLICT_START))
+        && content.lines().any(|line| line.starts_with(CONFLICT_END))
+}
+
+/// Three-way merge of `base -> target` into `source` with `git merge-file`,
+/// returning the result and the number of conflicting hunks
+fn merge_file(source: &[u8], base: &[u8], target: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let scratch = ScratchDir::create()?;
+    let paths = [("repo", source), ("base", base), ("target", target)]
+        .into_iter()
+        .map(|(name, content)| {
+            let path = scratch.path().join(name);
+            fs::write(&path, content)
+                .with_context(|| format!("Failed to write: {}", path.display()))?;
+            Ok(path)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let output = Command::new("git")
+        .args(["merge-file", "-p", "-L", "repo", "-L", "base", "-L", "target"])
+        .args(&paths)
+        .output()
+        .context("Failed to execute git merge-file")?;
+
+    // The exit code is the number of conflicts; negative means an error
+    match output.status.code() {
+        Some(code) if code >= 0 => Ok((output.stdout, code as usize)),
+        _ => bail!(
+            "git merge-file failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GlobalConfig;
+    use crate::manifest::ManifestRecord;
+    use crate::strategy::{Strategy, SyncPolicy};
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    struct Fixture {
+        _temp: TempDir,
+        repo_path: PathBuf,
+        item: RepoItem,
+        manifest: Manifest,
+    }
+
+    /// A copied file linked with `content` on both sides
+    fn linked_copy(relative: &str, content: &str) -> Fixture {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let target_dir = temp.path().join("home");
+        fs::create_dir_all(&repo_path).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+
+        let item = RepoItem {
+            source: repo_path.join(relative),
+            target: target_dir.join(relative.trim_end_matches(".tmpl")),
+            relative_path: relative.to_string(),
+            is_template: relative.ends_with(".tmpl"),
+            is_secret: false,
+            strategy: Strategy::Copy,
+            sync: SyncPolicy::Pull,
+        };
+        fs::write(&item.source, content).unwrap();
+        fs::write(&item.target, content).unwrap();
+
+        let mut manifest = Manifest::default();
+        let kind = if item.is_template { ManifestEntry::Rendered } else { ManifestEntry::Copy };
+        manifest.insert(relative.to_string(), ManifestRecord::for_item(&item, kind, None).unwrap());
+        write_base(&repo_path, relative, content.as_bytes()).unwrap();
+
+        Fixture {
+            _temp: temp,
+            repo_path,
+            item,
+            manifest,
+        }
+    }
+
+    fn plan(fixture: &Fixture, merge: bool) -> PullOutcome {
+        let config = GlobalConfig::default();
+        let var_resolver = VarResolver::new(&config, None);
+        plan_pull(
+            &fixture.item,
+            &fixture.manifest,
+            &fixture.repo_path,
+            &TemplateEngine::new(),
+            &var_resolver,
+            merge,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_plan_pull_unchanged() {
+        let fixture = linked_copy("settings.json", "{}\n");
+        assert_eq!(plan(&fixture, false), PullOutcome::Unchanged);
+    }
+
+    #[test]
+    fn test_plan_pull_target_changed() {
+        let fixture = linked_copy("settings.json", "{}\n");
+        fs::write(&fixture.item.target, "{\"theme\": 1}\n").unwrap();
+        assert_eq!(
+            plan(&fixture, false),
+            PullOutcome::Pull(b"{\"theme\": 1}\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_plan_pull_both_changed() {
+        let fixture = linked_copy("settings", "a\nb\nc\n");
+        fs::write(&fixture.item.source, "A\nb\nc\n").unwrap();
+        fs::write(&fixture.item.target, "a\nb\nC\n").unwrap();
+
+        assert_eq!(plan(&fixture, false), PullOutcome::Conflict);
+        assert_eq!(
+            plan(&fixture, true),
+            PullOutcome::Merged {
+                content: b"A\nb\nC\n".to_vec(),
+                conflicts: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_plan_pull_merge_conflict() {
+        let fixture = linked_copy("settings", "a\n");
+        fs::write(&fixture.item.source, "repo\n").unwrap();
+        fs::write(&fixture.item.target, "target\n").unwrap();
+
+        let PullOutcome::Merged { content, conflicts } = plan(&fixture, true) else {
+            panic!("expected a merge");
+        };
+        assert_eq!(conflicts, 1);
+
+        assert!(!has_conflict_markers(&fixture.item.source));
+        fs::write(&fixture.item.source, content).unwrap();
+        assert!(has_conflict_markers(&fixture.item.source));
+    }
+
+    #[test]
+    fn test_plan_pull_template_keeps_expressions() {
+        let mut fixture = linked_copy("gitconfig.tmpl", "[user]\nname = {{#if true}}Ada{{/if}}\n");
+        // As rendered at link time
+        let rendered = "[user]\nname = Ada\n";
+        let relative = fixture.item.relative_path.clone();
+        fixture.manifest.set_hash(&relative, hash_bytes(rendered.as_bytes()));
+        write_base(&fixture.repo_path, &relative, rendered.as_bytes()).unwrap();
+
+        fs::write(&fixture.item.target, "[core]\npager = less\n[user]\nname = Ada\n").unwrap();
+        assert_eq!(
+            plan(&fixture, false),
+            PullOutcome::Pull(b"[core]\npager = less\n[user]\nname = {{#if true}}Ada{{/if}}\n".to_vec())
+        );
+
+        // An edit to a rendered line cannot be mapped back onto the template
+        fs::write(&fixture.item.target, "[user]\nname = Bob\n").unwrap();
+        assert_eq!(plan(&fixture, false), PullOutcome::Conflict);
+    }
+
+    #[test]
+    fn test_plan_pull_ignores_secrets_and_untracked() {
+        let mut fixture = linked_copy("token", "x\n");
+        fs::write(&fixture.item.target, "y\n").unwrap();
+
+        fixture.item.is_secret = true;
+        assert_eq!(plan(&fixture, false), PullOutcome::Untracked);
+
+        fixture.item.is_secret = false;
+        fixture.manifest.files.get_mut("token").unwrap().sha256 = None;
+        assert_eq!(plan(&fixture, false), PullOutcome::Untracked);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("file");
+        let dir = temp.path().join("dir");
+        let link = temp.path().join("link");
+
+        assert_eq!(fingerprint(&file).unwrap(), Fingerprint::Missing);
+
+        fs::write(&file, "a").unwrap();
+        let Fingerprint::File { sha256 } = fingerprint(&file).unwrap() else {
+            panic!("expected a file");
+        };
+        assert_eq!(sha256, hash_bytes(b"a"));
+
+        symlink(&file, &link).unwrap();
+        assert_eq!(
+            fingerprint(&link).unwrap(),
+            Fingerprint::Symlink { points_to: file.clone() }
+        );
+
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/config"), "a").unwrap();
+        let before = fingerprint(&dir).unwrap();
+        assert_eq!(fingerprint(&dir).unwrap(), before);
+
+        fs::write(dir.join("sub/config"), "b").unwrap();
+        assert_ne!(fingerprint(&dir).unwrap(), before);
+    }
+}
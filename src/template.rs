@@ -1,47 +1,204 @@
 use anyhow::{Context, Result};
-use handlebars::Handlebars;
+use handlebars::{
+    Context as HbsContext, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext,
+    RenderErrorReason,
+};
+use serde_json::Value;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use walkdir::WalkDir;
+
+use crate::config::GlobalConfig;
+use crate::helpers::{self, IncludeHelper};
+use crate::secret::SecretProvider;
+use crate::vars::lookup;
 
 pub struct TemplateEngine {
     handlebars: Handlebars<'static>,
+    /// Names of every registered helper
+    helpers: Vec<&'static str>,
+    /// Per-repo registries, built on a repo's first render
+    registries: Mutex<HashMap<RegistryKey, RepoRegistry>>,
+}
+
+/// A repo's `include` root and partial directories
+type RegistryKey = (Option<PathBuf>, Vec<PartialDir>);
+
+/// A registry with a repo's `include` and partials, and the vars its
+/// partials were preprocessed with
+struct RepoRegistry {
+    vars: Value,
+    handlebars: Arc<Handlebars<'static>>,
+}
+
+/// Directory (in a repo or import) whose files are registered as partials
+pub const PARTIALS_DIR: &str = "_partials";
+
+/// What a template is rendered with: the resolved vars, the repo its
+/// `include` paths are relative to, and where its partials come from
+#[derive(Debug, Clone)]
+pub struct TemplateData {
+    pub vars: Value,
+    pub root: Option<PathBuf>,
+    pub partials: Vec<PartialDir>,
+}
+
+impl From<Value> for TemplateData {
+    fn from(vars: Value) -> Self {
+        Self {
+            vars,
+            root: None,
+            partials: Vec::new(),
+        }
+    }
+}
+
+/// A `_partials/` directory. Partials from an import are named
+/// `<import>/<name>`, so they can't clash with the repo's own.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PartialDir {
+    pub path: PathBuf,
+    pub namespace: Option<String>,
+}
+
+/// A rendered template
+pub struct Rendered {
+    pub content: String,
+    /// Whether rendering pulled values through `{{secret ...}}`
+    pub has_secrets: bool,
 }
 
 impl TemplateEngine {
     pub fn new() -> Self {
         let mut handlebars = Handlebars::new();
-        handlebars.set_strict_mode(false); // Allow missing vars with default syntax
+        handlebars.set_strict_mode(true); // Missing vars need {{var?}} or {{var:default}}
         handlebars.register_escape_fn(handlebars::no_escape); // Don't escape output
+        handlebars.register_helper("secret", Box::new(SecretHelper { provider: None }));
+        let mut names = helpers::BUILT_IN.to_vec();
+        names.push("secret");
+        names.extend(helpers::register(&mut handlebars));
+
+        Self {
+            handlebars,
+            helpers: names,
+            registries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// An engine set up the way `config` asks: strict mode and the secret
+    /// provider
+    pub fn for_config(config: &GlobalConfig) -> Self {
+        let mut engine = Self::new();
+        engine.set_strict_mode(config.settings.strict_templates);
+        if let Some(command) = &config.secrets.provider {
+            engine.set_secret_provider(command);
+        }
+        engine
+    }
+
+    /// With strict mode off, undefined vars render as empty strings
+    pub fn set_strict_mode(&mut self, strict: bool) {
+        self.handlebars.set_strict_mode(strict);
+        self.forget_partials();
+    }
+
+    /// Resolve `{{secret "key"}}` through `command` (see `SecretsConfig::provider`)
+    pub fn set_secret_provider(&mut self, command: &str) {
+        let helper = SecretHelper {
+            provider: Some(SecretProvider::new(command)),
+        };
+        self.handlebars.register_helper("secret", Box::new(helper));
+        self.forget_partials();
+    }
 
-        Self { handlebars }
+    /// Names a template calls helpers by rather than reading vars
+    pub fn helpers(&self) -> &[&'static str] {
+        &self.helpers
+    }
+
+    /// Read partials from disk again on the next render, after they changed
+    pub fn forget_partials(&self) {
+        self.registries.lock().unwrap().clear();
     }
 
     pub fn render_file(
         &self,
         source: &Path,
-        vars: &HashMap<String, String>,
-    ) -> Result<String> {
+        data: &TemplateData,
+    ) -> Result<Rendered> {
         let content = fs::read_to_string(source)
             .with_context(|| format!("Failed to read template: {}", source.display()))?;
+        self.render(&content, data)
+    }
+
+    /// Render `template`, noting whether `{{secret}}` was called, directly or
+    /// through an `include` or partial
+    pub fn render(&self, template: &str, data: &TemplateData) -> Result<Rendered> {
+        SECRET_USED.set(false);
+        let content = self.render_string(template, data)?;
 
-        self.render_string(&content, vars)
+        Ok(Rendered {
+            content,
+            has_secrets: SECRET_USED.replace(false),
+        })
     }
 
     pub fn render_string(
         &self,
         template: &str,
-        vars: &HashMap<String, String>,
+        data: &TemplateData,
     ) -> Result<String> {
-        // Pre-process template for our custom syntax:
+        // Pre-process template for our custom syntax (also for dotted paths):
         // {{var?}} -> optional (empty if missing)
         // {{var:default}} -> default value if missing
-        let processed = preprocess_template(template, vars);
+        let processed = preprocess_template(template, &data.vars);
 
-        self.handlebars
-            .render_template(&processed, vars)
+        let registry = self.registry_for(data)?;
+        registry
+            .as_deref()
+            .unwrap_or(&self.handlebars)
+            .render_template(&processed, &data.vars)
             .context("Failed to render template")
     }
+
+    /// The registry with `include` bound to the template's repo and its
+    /// partials registered, or `None` for the plain one. It is built once
+    /// per repo and rebuilt only if the vars change.
+    fn registry_for(&self, data: &TemplateData) -> Result<Option<Arc<Handlebars<'static>>>> {
+        if data.root.is_none() && data.partials.is_empty() {
+            return Ok(None);
+        }
+
+        let key = (data.root.clone(), data.partials.clone());
+        if let Some(registry) = self.registries.lock().unwrap().get(&key) {
+            if registry.vars == data.vars {
+                return Ok(Some(Arc::clone(&registry.handlebars)));
+            }
+        }
+
+        let mut handlebars = self.handlebars.clone();
+        if let Some(root) = &data.root {
+            let include = IncludeHelper {
+                root: Some(root.clone()),
+            };
+            handlebars.register_helper("include", Box::new(include));
+        }
+
+        for dir in &data.partials {
+            register_partials(&mut handlebars, dir, &data.vars)?;
+        }
+
+        let handlebars = Arc::new(handlebars);
+        let registry = RepoRegistry {
+            vars: data.vars.clone(),
+            handlebars: Arc::clone(&handlebars),
+        };
+        self.registries.lock().unwrap().insert(key, registry);
+        Ok(Some(handlebars))
+    }
 }
 
 impl Default for TemplateEngine {
@@ -50,58 +207,153 @@ impl Default for TemplateEngine {
     }
 }
 
-fn preprocess_template(template: &str, vars: &HashMap<String, String>) -> String {
-    let mut result = template.to_string();
+thread_local! {
+    /// Set by `secret` during a render on this thread. Includes render
+    /// through a context of their own, so the flag lives outside of it.
+    static SECRET_USED: Cell<bool> = const { Cell::new(false) };
+}
 
-    // Handle env vars first ({{env.VAR_NAME}}) - replace directly since Handlebars
-    // doesn't support dots in variable names
-    let env_re = regex_lite::Regex::new(r"\{\{(env\.\w+)\}\}").unwrap();
-    result = env_re
-        .replace_all(&result, |caps: &regex_lite::Captures| {
-            let var_name = &caps[1];
-            vars.get(var_name).cloned().unwrap_or_default()
-        })
-        .to_string();
+/// Whether a template calls the `secret` helper itself. Secrets reached
+/// through an `include` or partial only show up in [`Rendered::has_secrets`].
+pub fn uses_secrets(template: &str) -> bool {
+    static SECRET_RE: OnceLock<regex_lite::Regex> = OnceLock::new();
+    SECRET_RE
+        .get_or_init(|| regex_lite::Regex::new(r"\{\{~?\s*secret\s").unwrap())
+        .is_match(template)
+}
+
+/// `{{secret "key"}}`: the provider's value for `key`, fetched once per run
+struct SecretHelper {
+    provider: Option<SecretProvider>,
+}
+
+impl HelperDef for SecretHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc HbsContext,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let key = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("secret", 0))?;
+        SECRET_USED.set(true);
+
+        let Some(provider) = &self.provider else {
+            return Err(RenderErrorReason::Other(format!(
+                "secret \"{}\": no provider configured ([secrets] provider)",
+                key
+            ))
+            .into());
+        };
+
+        let value = provider
+            .get(key)
+            .map_err(|e| RenderErrorReason::Other(format!("{:#}", e)))?;
+        out.write(&value)?;
+        Ok(())
+    }
+}
+
+/// Register every file under `dir` as a partial named by its path there,
+/// without extensions: `_partials/git/aliases.tmpl` is `{{> git/aliases}}`
+fn register_partials(handlebars: &mut Handlebars, dir: &PartialDir, vars: &Value) -> Result<()> {
+    if !dir.path.is_dir() {
+        return Ok(());
+    }
+
+    for entry in WalkDir::new(&dir.path).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("Failed to read {}", dir.path.display()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let Some(name) = partial_name(entry.path().strip_prefix(&dir.path)?) else {
+            continue;
+        };
+        let name = match &dir.namespace {
+            Some(namespace) => format!("{}/{}", namespace, name),
+            None => name,
+        };
+
+        let template = fs::read_to_string(entry.path())
+            .with_context(|| format!("Failed to read partial: {}", entry.path().display()))?;
+        handlebars
+            .register_partial(&name, preprocess_template(&template, vars))
+            .with_context(|| format!("Invalid partial: {}", entry.path().display()))?;
+    }
+    Ok(())
+}
+
+/// `git/aliases.sh.tmpl` -> `git/aliases`
+fn partial_name(relative: &Path) -> Option<String> {
+    let file_name = relative.file_name()?.to_string_lossy();
+    let stem = match file_name.char_indices().skip(1).find(|&(_, c)| c == '.') {
+        Some((i, _)) => &file_name[..i],
+        None => &file_name,
+    };
+
+    let mut parts: Vec<String> = relative
+        .parent()
+        .into_iter()
+        .flat_map(|parent| parent.components())
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    parts.push(stem.to_string());
+    Some(parts.join("/"))
+}
+
+/// `{{var:default}}`: the var's path and the default
+pub(crate) fn default_syntax() -> &'static regex_lite::Regex {
+    static DEFAULT_RE: OnceLock<regex_lite::Regex> = OnceLock::new();
+    DEFAULT_RE.get_or_init(|| regex_lite::Regex::new(r"\{\{(\w+(?:\.\w+)*):([^}]*)\}\}").unwrap())
+}
+
+/// `{{var?}}`: the var's path
+pub(crate) fn optional_syntax() -> &'static regex_lite::Regex {
+    static OPTIONAL_RE: OnceLock<regex_lite::Regex> = OnceLock::new();
+    OPTIONAL_RE.get_or_init(|| regex_lite::Regex::new(r"\{\{(\w+(?:\.\w+)*)\?\}\}").unwrap())
+}
+
+pub(crate) fn preprocess_template(template: &str, vars: &Value) -> String {
+    let is_set = |path: &str| lookup(vars, path).is_some_and(|v| !v.is_null());
 
     // Handle {{var:default}} syntax
-    let default_re = regex_lite::Regex::new(r"\{\{(\w+):([^}]*)\}\}").unwrap();
-    result = default_re
-        .replace_all(&result, |caps: &regex_lite::Captures| {
-            let var_name = &caps[1];
-            let default = &caps[2];
-            if vars.contains_key(var_name) {
-                format!("{{{{{}}}}}", var_name)
-            } else {
-                default.to_string()
-            }
-        })
-        .to_string();
+    let result = default_syntax().replace_all(template, |caps: &regex_lite::Captures| {
+        let var_name = &caps[1];
+        let default = &caps[2];
+        if is_set(var_name) {
+            format!("{{{{{}}}}}", var_name)
+        } else {
+            default.to_string()
+        }
+    });
 
     // Handle {{var?}} syntax (optional, empty if missing)
-    let optional_re = regex_lite::Regex::new(r"\{\{(\w+)\?\}\}").unwrap();
-    result = optional_re
+    optional_syntax()
         .replace_all(&result, |caps: &regex_lite::Captures| {
             let var_name = &caps[1];
-            if vars.contains_key(var_name) {
+            if is_set(var_name) {
                 format!("{{{{{}}}}}", var_name)
             } else {
                 String::new()
             }
         })
-        .to_string();
-
-    result
+        .to_string()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
 
     #[test]
     fn test_simple_render() {
         let engine = TemplateEngine::new();
-        let mut vars = HashMap::new();
-        vars.insert("name".to_string(), "World".to_string());
+        let vars = TemplateData::from(json!({ "name": "World" }));
 
         let result = engine.render_string("Hello, {{name}}!", &vars).unwrap();
         assert_eq!(result, "Hello, World!");
@@ -110,7 +362,7 @@ mod tests {
     #[test]
     fn test_default_value() {
         let engine = TemplateEngine::new();
-        let vars = HashMap::new();
+        let vars = TemplateData::from(json!({}));
 
         let result = engine
             .render_string("Hello, {{name:Guest}}!", &vars)
@@ -121,8 +373,7 @@ mod tests {
     #[test]
     fn test_default_value_with_var_present() {
         let engine = TemplateEngine::new();
-        let mut vars = HashMap::new();
-        vars.insert("name".to_string(), "World".to_string());
+        let vars = TemplateData::from(json!({ "name": "World" }));
 
         let result = engine
             .render_string("Hello, {{name:Guest}}!", &vars)
@@ -133,7 +384,7 @@ mod tests {
     #[test]
     fn test_optional_missing() {
         let engine = TemplateEngine::new();
-        let vars = HashMap::new();
+        let vars = TemplateData::from(json!({}));
 
         let result = engine.render_string("Hello{{name?}}!", &vars).unwrap();
         assert_eq!(result, "Hello!");
@@ -142,8 +393,7 @@ mod tests {
     #[test]
     fn test_optional_present() {
         let engine = TemplateEngine::new();
-        let mut vars = HashMap::new();
-        vars.insert("name".to_string(), ", World".to_string());
+        let vars = TemplateData::from(json!({ "name": ", World" }));
 
         let result = engine.render_string("Hello{{name?}}!", &vars).unwrap();
         assert_eq!(result, "Hello, World!");
@@ -152,8 +402,7 @@ mod tests {
     #[test]
     fn test_env_var_syntax() {
         let engine = TemplateEngine::new();
-        let mut vars = HashMap::new();
-        vars.insert("env.API_KEY".to_string(), "secret123".to_string());
+        let vars = TemplateData::from(json!({ "env": { "API_KEY": "secret123" } }));
 
         let result = engine
             .render_string("Key: {{env.API_KEY}}", &vars)
@@ -164,9 +413,7 @@ mod tests {
     #[test]
     fn test_multiline_template() {
         let engine = TemplateEngine::new();
-        let mut vars = HashMap::new();
-        vars.insert("user".to_string(), "alice".to_string());
-        vars.insert("email".to_string(), "alice@example.com".to_string());
+        let vars = TemplateData::from(json!({ "user": "alice", "email": "alice@example.com" }));
 
         let template = r#"[user]
     name = {{user}}
@@ -181,4 +428,124 @@ mod tests {
         let result = engine.render_string(template, &vars).unwrap();
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_secret_helper() {
+        let mut engine = TemplateEngine::new();
+        engine.set_secret_provider("echo token-for");
+        let vars = TemplateData::from(json!({}));
+
+        let result = engine
+            .render_string("token = {{secret \"github/token\"}}", &vars)
+            .unwrap();
+        assert_eq!(result, "token = token-for github/token");
+    }
+
+    #[test]
+    fn test_secret_helper_without_provider() {
+        let engine = TemplateEngine::new();
+        let vars = TemplateData::from(json!({}));
+
+        let err = engine
+            .render_string("{{secret \"github/token\"}}", &vars)
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("no provider configured"));
+    }
+
+    #[test]
+    fn test_uses_secrets() {
+        assert!(uses_secrets("token = {{secret \"github/token\"}}"));
+        assert!(uses_secrets("{{~ secret \"a\" ~}}"));
+        assert!(!uses_secrets("{{secretary}} {{user}}"));
+    }
+
+    #[test]
+    fn test_dotted_default_and_optional() {
+        let engine = TemplateEngine::new();
+        let vars = TemplateData::from(json!({ "git": { "email": "me@example.com", "name": null } }));
+
+        let result = engine
+            .render_string("{{git.email:nobody}} {{git.name:anon}} {{git.key?}}|{{work.host?}}", &vars)
+            .unwrap();
+        assert_eq!(result, "me@example.com anon |");
+    }
+
+    #[test]
+    fn test_structured_vars() {
+        let engine = TemplateEngine::new();
+        let vars = TemplateData::from(json!({
+            "work": true,
+            "ssh": { "hosts": [
+                { "name": "build", "port": 2222 },
+                { "name": "db", "port": 22 },
+            ] },
+        }));
+
+        let template = "{{#if work}}work{{else}}home{{/if}}\n\
+                        {{#each ssh.hosts}}Host {{name}} {{port}}\n{{/each}}";
+        let result = engine.render_string(template, &vars).unwrap();
+        assert_eq!(result, "work\nHost build 2222\nHost db 22\n");
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let mut engine = TemplateEngine::new();
+        let vars = TemplateData::from(json!({ "name": "World" }));
+
+        let err = engine.render_string("Hello, {{nmae}}!", &vars).unwrap_err();
+        assert!(format!("{:#}", err).contains("nmae"));
+        assert_eq!(
+            engine.render_string("{{#if work}}w{{/if}}{{default editor \"vi\"}}", &vars).unwrap(),
+            "vi"
+        );
+
+        engine.set_strict_mode(false);
+        assert_eq!(engine.render_string("Hello, {{nmae}}!", &vars).unwrap(), "Hello, !");
+    }
+
+    #[test]
+    fn test_partials() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let repo = temp.path().join("repo/_partials");
+        let import = temp.path().join("shared/_partials");
+        fs::create_dir_all(repo.join("git")).unwrap();
+        fs::create_dir_all(&import).unwrap();
+        fs::write(repo.join("aliases.sh"), "alias e={{editor:vi}}\n").unwrap();
+        fs::write(repo.join("git/aliases.tmpl"), "co = checkout\n").unwrap();
+        fs::write(import.join("aliases.sh"), "alias g=git\n").unwrap();
+
+        let engine = TemplateEngine::new();
+        let mut data = TemplateData::from(json!({}));
+        data.partials = vec![
+            PartialDir { path: import, namespace: Some("shared-dots".to_string()) },
+            PartialDir { path: repo, namespace: None },
+            PartialDir { path: temp.path().join("missing"), namespace: None },
+        ];
+
+        let result = engine
+            .render_string("{{> aliases}}{{> shared-dots/aliases}}[alias]\n{{> git/aliases}}", &data)
+            .unwrap();
+        assert_eq!(result, "alias e=vi\nalias g=git\n[alias]\nco = checkout\n");
+
+        assert!(engine.render_string("{{> nope}}", &data).is_err());
+    }
+
+    #[test]
+    fn test_partials_are_read_once_per_repo() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let partials = temp.path().join("_partials");
+        fs::create_dir_all(&partials).unwrap();
+        fs::write(partials.join("name"), "old").unwrap();
+
+        let engine = TemplateEngine::new();
+        let mut data = TemplateData::from(json!({}));
+        data.partials = vec![PartialDir { path: partials.clone(), namespace: None }];
+        assert_eq!(engine.render_string("{{> name}}", &data).unwrap(), "old");
+
+        fs::write(partials.join("name"), "new").unwrap();
+        assert_eq!(engine.render_string("{{> name}}", &data).unwrap(), "old");
+
+        engine.forget_partials();
+        assert_eq!(engine.render_string("{{> name}}", &data).unwrap(), "new");
+    }
 }
@@ -1,57 +1,168 @@
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
 
 use crate::config::GlobalConfig;
 use crate::repo::Repo;
+use crate::template::{PartialDir, TemplateData};
+
+/// `[vars]` tables: any TOML value, handed to templates as JSON
+pub type Vars = HashMap<String, Value>;
 
 pub struct VarResolver {
-    vars: HashMap<String, String>,
+    vars: Vars,
     env_passthrough: Vec<String>,
+    /// The repo templates are rendered for
+    root: Option<PathBuf>,
+    partials: Vec<PartialDir>,
 }
 
 impl VarResolver {
     pub fn new(global_config: &GlobalConfig, repo: Option<&Repo>) -> Self {
-        let mut vars = HashMap::new();
+        let mut resolver = Self::with_repo_vars(global_config, repo.map(|r| r.vars()));
+        if let Some(repo) = repo {
+            resolver.root = Some(repo.path.clone());
+            resolver.partials = repo.partial_dirs();
+        }
+        resolver
+    }
+
+    pub fn with_repo_vars(global_config: &GlobalConfig, repo_vars: Option<&Vars>) -> Self {
+        let mut vars = Vars::new();
 
         // Built-in vars
-        vars.insert("hostname".to_string(), get_hostname());
-        vars.insert("user".to_string(), get_username());
-        vars.insert("home".to_string(), get_home_dir());
-        vars.insert("os".to_string(), get_os());
+        vars.insert("hostname".to_string(), Value::String(get_hostname()));
+        vars.insert("user".to_string(), Value::String(get_username()));
+        vars.insert("home".to_string(), Value::String(get_home_dir()));
+        vars.insert("os".to_string(), Value::String(get_os()));
 
         // Global vars
-        for (k, v) in &global_config.vars {
-            vars.insert(k.clone(), v.clone());
-        }
+        merge_vars(&mut vars, &global_config.vars);
 
         // Repo-specific vars (override globals)
-        if let Some(r) = repo {
-            for (k, v) in r.vars() {
-                vars.insert(k.clone(), v.clone());
-            }
+        if let Some(repo_vars) = repo_vars {
+            merge_vars(&mut vars, repo_vars);
         }
 
+        // Command-line overrides
+        merge_vars(&mut vars, &global_config.overrides);
+
         Self {
             vars,
             env_passthrough: global_config.env.pass_through.clone(),
+            root: None,
+            partials: Vec::new(),
         }
     }
 
-    pub fn to_template_data(&self) -> HashMap<String, String> {
-        let mut data = self.vars.clone();
+    pub fn to_template_data(&self) -> TemplateData {
+        let mut data: Map<String, Value> = self
+            .vars
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
 
-        // Add passthrough env vars
+        // Add passthrough env vars as {{env.VAR_NAME}}
+        let mut env_vars = Map::new();
         for env_var in &self.env_passthrough {
             if let Ok(value) = env::var(env_var) {
-                data.insert(format!("env.{}", env_var), value);
+                env_vars.insert(env_var.clone(), Value::String(value));
             }
         }
+        if !env_vars.is_empty() {
+            merge_value(
+                data.entry("env").or_insert_with(|| Value::Object(Map::new())),
+                Value::Object(env_vars),
+            );
+        }
 
-        data
+        TemplateData {
+            vars: Value::Object(data),
+            root: self.root.clone(),
+            partials: self.partials.clone(),
+        }
     }
 }
 
-fn get_hostname() -> String {
+/// A `KEY=VALUE` override from the command line as vars to merge. `KEY` may
+/// be a dotted path; `VALUE` is read as TOML when it is one (`true`, `22`,
+/// `["a", "b"]`) and as a plain string otherwise.
+pub fn parse_override(arg: &str) -> Result<Vars> {
+    let Some((key, raw)) = arg.split_once('=') else {
+        bail!("Invalid var `{}`: expected KEY=VALUE", arg);
+    };
+    let key = key.trim();
+    if key.is_empty() || key.split('.').any(str::is_empty) {
+        bail!("Invalid var `{}`: empty key", arg);
+    }
+
+    let value = toml::from_str::<Vars>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()));
+
+    let mut segments = key.rsplit('.');
+    let mut value = value;
+    let mut top = segments.next().unwrap_or(key);
+    for parent in segments {
+        value = Value::Object(Map::from_iter([(top.to_string(), value)]));
+        top = parent;
+    }
+
+    Ok(Vars::from([(top.to_string(), value)]))
+}
+
+/// Merge `overrides` into `vars`. Tables are merged key by key, anything
+/// else replaces the earlier value.
+pub fn merge_vars(vars: &mut Vars, overrides: &Vars) {
+    for (key, value) in overrides {
+        match vars.get_mut(key) {
+            Some(existing) => merge_value(existing, value.clone()),
+            None => {
+                vars.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn merge_value(existing: &mut Value, value: Value) {
+    match (existing, value) {
+        (Value::Object(existing), Value::Object(value)) => {
+            for (key, value) in value {
+                match existing.get_mut(&key) {
+                    Some(slot) => merge_value(slot, value),
+                    None => {
+                        existing.insert(key, value);
+                    }
+                }
+            }
+        }
+        (existing, value) => *existing = value,
+    }
+}
+
+/// The value at a dotted path like `git.email` or `hosts.0.name`
+pub fn lookup<'a>(data: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(data, |value, segment| match value {
+        Value::Object(map) => map.get(segment),
+        Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
+
+/// A scalar var as text (strings, numbers and booleans)
+pub fn as_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+pub fn get_hostname() -> String {
     hostname::get()
         .ok()
         .and_then(|h| h.into_string().ok())
@@ -84,6 +195,7 @@ fn get_os() -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
 
     #[test]
     fn test_builtin_vars() {
@@ -91,19 +203,62 @@ mod tests {
         let resolver = VarResolver::new(&config, None);
 
         let data = resolver.to_template_data();
-        assert!(data.contains_key("hostname"));
-        assert!(data.contains_key("user"));
-        assert!(data.contains_key("home"));
-        assert!(data.contains_key("os"));
+        assert!(data.vars.get("hostname").is_some());
+        assert!(data.vars.get("user").is_some());
+        assert!(data.vars.get("home").is_some());
+        assert!(data.vars.get("os").is_some());
     }
 
     #[test]
     fn test_global_vars() {
         let mut config = GlobalConfig::default();
-        config.vars.insert("email".to_string(), "test@example.com".to_string());
+        config.vars.insert("email".to_string(), json!("test@example.com"));
 
         let resolver = VarResolver::new(&config, None);
         let data = resolver.to_template_data();
-        assert_eq!(data.get("email"), Some(&"test@example.com".to_string()));
+        assert_eq!(data.vars.get("email"), Some(&json!("test@example.com")));
+    }
+
+    #[test]
+    fn test_structured_vars_merge_tables() {
+        let mut config = GlobalConfig::default();
+        config.vars.insert("git".to_string(), json!({ "email": "me@home", "sign": false }));
+        let repo_vars: Vars = [("git".to_string(), json!({ "sign": true, "keys": ["a", "b"] }))].into();
+
+        let data = VarResolver::with_repo_vars(&config, Some(&repo_vars)).to_template_data();
+        assert_eq!(
+            data.vars["git"],
+            json!({ "email": "me@home", "sign": true, "keys": ["a", "b"] })
+        );
+        assert_eq!(lookup(&data.vars, "git.keys.1"), Some(&json!("b")));
+        assert_eq!(lookup(&data.vars, "git.missing"), None);
+        assert_eq!(as_text(&json!(true)).as_deref(), Some("true"));
+        assert_eq!(as_text(&json!(["a"])), None);
+    }
+
+    #[test]
+    fn test_parse_override() {
+        assert_eq!(parse_override("editor=nvim").unwrap(), Vars::from([("editor".to_string(), json!("nvim"))]));
+        assert_eq!(parse_override("work=true").unwrap()["work"], json!(true));
+        assert_eq!(parse_override("port=22").unwrap()["port"], json!(22));
+        assert_eq!(parse_override("hosts=[\"a\", \"b\"]").unwrap()["hosts"], json!(["a", "b"]));
+        assert_eq!(parse_override("name=Ada Lovelace").unwrap()["name"], json!("Ada Lovelace"));
+        assert_eq!(parse_override("url=a=b").unwrap()["url"], json!("a=b"));
+        assert_eq!(parse_override("git.user.email=me@work").unwrap()["git"], json!({ "user": { "email": "me@work" } }));
+        assert!(parse_override("editor").is_err());
+        assert!(parse_override("git..email=x").is_err());
+    }
+
+    #[test]
+    fn test_overrides_win() {
+        let mut config = GlobalConfig::default();
+        config.vars.insert("git".to_string(), json!({ "email": "me@home", "name": "me" }));
+        config.overrides = parse_override("git.email=me@work").unwrap();
+        config.overrides.extend(parse_override("os=windows").unwrap());
+
+        let repo_vars = Vars::from([("os".to_string(), json!("plan9"))]);
+        let data = VarResolver::with_repo_vars(&config, Some(&repo_vars)).to_template_data();
+        assert_eq!(data.vars["git"], json!({ "email": "me@work", "name": "me" }));
+        assert_eq!(data.vars["os"], json!("windows"));
     }
 }
@@ -0,0 +1,311 @@
+use assert_cmd::Command;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// A throwaway home directory holding one `dotfiles` repo that links into it
+struct Home {
+    dir: TempDir,
+}
+
+impl Home {
+    fn new() -> Self {
+        let home = Self {
+            dir: TempDir::new().unwrap(),
+        };
+        home.write_repo("homie.toml", "target = \"~\"\n");
+        home
+    }
+
+    fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    fn repo(&self) -> PathBuf {
+        self.path().join(".homie/repos/dotfiles")
+    }
+
+    fn write_repo(&self, path: &str, content: &str) {
+        self.write_to("dotfiles", path, content);
+    }
+
+    /// Write into the repo `name`, created next to `dotfiles` if new
+    fn write_to(&self, name: &str, path: &str, content: &str) {
+        let repo = self.path().join(".homie/repos").join(name);
+        if !repo.join("homie.toml").exists() {
+            fs::create_dir_all(&repo).unwrap();
+            fs::write(repo.join("homie.toml"), "target = \"~\"\n").unwrap();
+        }
+        let path = repo.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn homie(&self, args: &[&str]) -> Command {
+        let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("homie");
+        cmd.args(args)
+            .env("HOME", self.path())
+            .env("XDG_CONFIG_HOME", self.path().join(".config"))
+            .env_remove("HOMIE_PROFILE")
+            .env("NO_COLOR", "1");
+        cmd
+    }
+
+    fn stdout(&self, args: &[&str]) -> String {
+        let output = self.homie(args).output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
+
+/// Every line of NDJSON output, parsed
+fn ndjson(stdout: &str) -> Vec<Value> {
+    stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {}", e, line)))
+        .collect()
+}
+
+/// JSON output, which must be one array and nothing else
+fn json(stdout: &str) -> Vec<Value> {
+    match serde_json::from_str(stdout).unwrap() {
+        Value::Array(records) => records,
+        other => panic!("not an array: {}", other),
+    }
+}
+
+fn keys(record: &Value) -> Vec<&str> {
+    record.as_object().unwrap().keys().map(String::as_str).collect()
+}
+
+#[test]
+fn test_link_ndjson_records() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    home.write_repo(".gitconfig.tmpl", "[user]\n");
+
+    let records = ndjson(&home.stdout(&["link", "--format", "ndjson"]));
+    assert_eq!(records.len(), 2);
+
+    let zshrc = records.iter().find(|r| r["path"] == ".zshrc").unwrap();
+    assert_eq!(
+        keys(zshrc),
+        vec!["repo", "path", "source", "target", "strategy", "result", "entry"]
+    );
+    assert_eq!(zshrc["repo"], "dotfiles");
+    assert_eq!(zshrc["target"], home.path().join(".zshrc").to_str().unwrap());
+    assert_eq!(zshrc["result"], "created");
+    assert_eq!(zshrc["entry"], "symlink");
+
+    let gitconfig = records.iter().find(|r| r["path"] == ".gitconfig.tmpl").unwrap();
+    assert_eq!(gitconfig["entry"], "rendered");
+}
+
+#[test]
+fn test_status_json_records() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    home.write_repo(".bashrc", "set -o vi\n");
+    home.homie(&["link", "--format", "json"]).assert().success();
+
+    // One item goes away in the repo and at the target: an orphan
+    fs::remove_file(home.repo().join(".bashrc")).unwrap();
+    fs::remove_file(home.path().join(".bashrc")).unwrap();
+
+    let records = json(&home.stdout(&["status", "--format", "json"]));
+    assert_eq!(records.len(), 2);
+
+    assert_eq!(
+        keys(&records[0]),
+        vec!["repo", "path", "source", "target", "strategy", "status"]
+    );
+    assert_eq!(records[0]["path"], ".zshrc");
+    assert_eq!(records[0]["status"], "linked");
+
+    assert_eq!(keys(&records[1]), vec!["repo", "path", "target", "status", "reason"]);
+    assert_eq!(records[1]["path"], ".bashrc");
+    assert_eq!(records[1]["status"], "orphaned");
+    assert_eq!(records[1]["reason"], "not on disk");
+}
+
+#[test]
+fn test_status_reports_orphans_kept_on_disk() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    home.homie(&["link"]).assert().success();
+
+    fs::remove_file(home.repo().join(".zshrc")).unwrap();
+    home.homie(&["link", "--no-prune"]).assert().success();
+    assert!(home.path().join(".zshrc").is_symlink());
+
+    let records = ndjson(&home.stdout(&["status", "--format", "ndjson"]));
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["status"], "orphaned");
+    assert_eq!(records[0]["reason"], "removed from repo");
+    home.homie(&["status", "--check"]).assert().code(2);
+}
+
+#[test]
+fn test_plan_ndjson_records() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    fs::write(home.path().join(".zshrc"), "mine\n").unwrap();
+
+    let records = ndjson(&home.stdout(&["plan", "--format", "ndjson"]));
+    assert_eq!(records.len(), 1);
+    assert_eq!(
+        keys(&records[0]),
+        vec!["repo", "path", "target", "action", "reason"]
+    );
+    assert_eq!(records[0]["action"], "skip");
+}
+
+#[test]
+fn test_list_records() {
+    let home = Home::new();
+    home.write_repo("homie.toml", "target = \"~\"\n\n[vars]\nshell = \"zsh\"\n");
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+
+    let lines = ndjson(&home.stdout(&["list", "--format", "ndjson"]));
+    let array = json(&home.stdout(&["list", "--format", "json"]));
+    assert_eq!(lines, array);
+
+    assert_eq!(array.len(), 1);
+    assert_eq!(keys(&array[0]), vec!["repo", "path", "target", "items", "vars"]);
+    assert_eq!(array[0]["items"], 1);
+    assert_eq!(array[0]["vars"], serde_json::json!(["shell"]));
+}
+
+#[test]
+fn test_json_without_records_is_an_empty_array() {
+    let home = Home::new();
+    assert!(json(&home.stdout(&["status", "--format", "json"])).is_empty());
+    assert_eq!(home.stdout(&["status", "--format", "ndjson"]), "");
+}
+
+#[test]
+fn test_status_exit_codes() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+
+    // Not linked yet: missing is drift
+    home.homie(&["status"]).assert().code(2);
+
+    home.homie(&["link"]).assert().code(0);
+    home.homie(&["status"]).assert().code(0);
+}
+
+#[test]
+fn test_status_check_prints_nothing() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+
+    home.homie(&["status", "--check"]).assert().code(2).stdout("");
+    home.homie(&["status", "--check", "-v"]).assert().code(2).stdout("");
+
+    home.homie(&["link"]).assert().code(0);
+    home.homie(&["status", "--check"]).assert().code(0).stdout("");
+}
+
+#[test]
+fn test_link_exit_codes() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    fs::write(home.path().join(".zshrc"), "mine\n").unwrap();
+
+    // The user's file is left alone: skipped
+    home.homie(&["link"]).assert().code(3);
+
+    home.homie(&["link", "--force"]).assert().code(0);
+    home.homie(&["link"]).assert().code(0);
+}
+
+#[test]
+fn test_plan_and_apply_exit_codes() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    let plan = home.path().join("plan.json");
+    let plan = plan.to_str().unwrap();
+
+    // Changes planned are drift
+    home.homie(&["plan", "--out", plan]).assert().code(2);
+    home.homie(&["apply", plan]).assert().code(0);
+    home.homie(&["plan"]).assert().code(0);
+
+    // A skip is not a change, but apply reports it
+    home.write_repo(".bashrc", "set -o vi\n");
+    fs::write(home.path().join(".bashrc"), "mine\n").unwrap();
+    home.homie(&["plan", "--out", plan]).assert().code(0);
+    home.homie(&["apply", plan]).assert().code(3);
+}
+
+#[test]
+fn test_apply_journals_orphan_removal() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    home.homie(&["link"]).assert().success();
+    let plan = home.path().join("plan.json");
+    let plan = plan.to_str().unwrap();
+
+    fs::remove_file(home.repo().join(".zshrc")).unwrap();
+    home.homie(&["plan", "--out", plan]).assert().code(2);
+    home.homie(&["apply", plan]).assert().code(0);
+    assert!(!home.path().join(".zshrc").is_symlink());
+
+    // The removal is part of the generation apply recorded
+    home.homie(&["rollback"]).assert().success();
+    assert!(home.path().join(".zshrc").is_symlink());
+}
+
+#[test]
+fn test_rollback_undoes_only_the_last_run() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    home.write_to("work", ".gitconfig", "[user]\n");
+    home.homie(&["link"]).assert().success();
+
+    // A later run that only touches one repo
+    home.write_to("work", ".npmrc", "save-exact=true\n");
+    home.homie(&["link", "work"]).assert().success();
+
+    home.homie(&["rollback"]).assert().success();
+    assert!(!home.path().join(".npmrc").exists());
+    assert!(home.path().join(".gitconfig").is_symlink());
+    assert!(home.path().join(".zshrc").is_symlink());
+
+    // Then the first run, in both repos
+    home.homie(&["rollback"]).assert().success();
+    assert!(!home.path().join(".gitconfig").exists());
+    assert!(!home.path().join(".zshrc").exists());
+}
+
+#[test]
+fn test_rollback_to_missing_generation_undoes_nothing() {
+    let home = Home::new();
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    home.homie(&["link"]).assert().success();
+    home.write_repo(".bashrc", "set -o vi\n");
+    home.homie(&["link"]).assert().success();
+
+    home.homie(&["rollback", "dotfiles", "--to", "7"]).assert().failure();
+    assert!(home.path().join(".bashrc").is_symlink());
+    assert!(home.path().join(".zshrc").is_symlink());
+}
+
+#[test]
+fn test_apply_runs_hooks_like_link() {
+    let home = Home::new();
+    home.write_repo(
+        "homie.toml",
+        "target = \"~\"\n\n[hooks]\npre_link = \"echo pre >> $HOME/hooks.log\"\npost_link = \"echo post $HOMIE_CHANGED >> $HOME/hooks.log\"\n",
+    );
+    home.write_repo(".zshrc", "export EDITOR=vi\n");
+    let plan = home.path().join("plan.json");
+    let plan = plan.to_str().unwrap();
+
+    home.homie(&["plan", "--out", plan]).assert().code(2);
+    home.homie(&["apply", plan]).assert().code(0);
+
+    let log = fs::read_to_string(home.path().join("hooks.log")).unwrap();
+    assert_eq!(log, "pre\npost .zshrc\n");
+}